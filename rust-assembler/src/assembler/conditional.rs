@@ -0,0 +1,192 @@
+use anyhow::{anyhow, Result};
+
+use crate::diagnostics::{Diagnostic, Span};
+
+/// Conditional assembly directives, in both `#if` and `.if` spelling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConditionalKind {
+    If,
+    IfDef,
+    IfNDef,
+    ElseIf,
+    Else,
+    EndIf,
+}
+
+impl ConditionalKind {
+    pub fn from_word(word: &str) -> Option<Self> {
+        match word {
+            "#if" | ".if" => Some(ConditionalKind::If),
+            "#ifdef" | ".ifdef" => Some(ConditionalKind::IfDef),
+            "#ifndef" | ".ifndef" => Some(ConditionalKind::IfNDef),
+            "#elif" | ".elseif" | ".elif" => Some(ConditionalKind::ElseIf),
+            "#else" | ".else" => Some(ConditionalKind::Else),
+            "#endif" | ".endif" => Some(ConditionalKind::EndIf),
+            _ => None,
+        }
+    }
+}
+
+struct Block {
+    /// The opening directive and its line, for unterminated block errors
+    directive: String,
+    opened_at: Span,
+    /// Whether the code around the block is assembled
+    parent_active: bool,
+    /// Whether the current branch is assembled
+    active: bool,
+    /// Whether an earlier branch was already assembled
+    taken: bool,
+    seen_else: bool,
+}
+
+/// Nested conditional blocks and whether the current line is assembled.
+#[derive(Default)]
+pub struct ConditionalStack {
+    blocks: Vec<Block>,
+}
+
+impl ConditionalStack {
+    pub fn is_active(&self) -> bool {
+        self.blocks.last().is_none_or(|block| block.active)
+    }
+
+    pub fn depth(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Open a block. The condition is only evaluated inside assembled code,
+    /// and a failed condition still opens the block so its `#endif` matches.
+    pub fn open(
+        &mut self,
+        directive: &str,
+        span: &Span,
+        condition: impl FnOnce() -> Result<bool>,
+    ) -> Result<()> {
+        let parent_active = self.is_active();
+        let result = if parent_active {
+            condition()
+        } else {
+            Ok(false)
+        };
+        let taken = matches!(result, Ok(true));
+        self.blocks.push(Block {
+            directive: directive.to_string(),
+            opened_at: span.clone(),
+            parent_active,
+            active: taken,
+            taken,
+            seen_else: false,
+        });
+        result.map(|_| ())
+    }
+
+    /// Start an `#elif`/`.elseif` branch, taken if no earlier branch was.
+    pub fn else_if(
+        &mut self,
+        directive: &str,
+        condition: impl FnOnce() -> Result<bool>,
+    ) -> Result<()> {
+        let block = self.open_block(directive)?;
+        if !block.parent_active || block.taken {
+            block.active = false;
+            return Ok(());
+        }
+        let result = condition();
+        block.active = matches!(result, Ok(true));
+        block.taken = block.active;
+        result.map(|_| ())
+    }
+
+    pub fn otherwise(&mut self, directive: &str) -> Result<()> {
+        let block = self.open_block(directive)?;
+        block.active = block.parent_active && !block.taken;
+        block.taken = true;
+        block.seen_else = true;
+        Ok(())
+    }
+
+    pub fn close(&mut self, directive: &str) -> Result<()> {
+        self.blocks
+            .pop()
+            .map(|_| ())
+            .ok_or_else(|| anyhow!("{} without a matching #if", directive))
+    }
+
+    /// Close the blocks opened past `depth`, reporting each as unterminated.
+    pub fn close_from(&mut self, depth: usize) -> Vec<Diagnostic> {
+        let unclosed = self.blocks.split_off(depth.min(self.blocks.len()));
+        unclosed
+            .into_iter()
+            .map(|block| {
+                Diagnostic::error(format!("Missing #endif for this {}", block.directive))
+                    .with_span(block.opened_at)
+            })
+            .collect()
+    }
+
+    fn open_block(&mut self, directive: &str) -> Result<&mut Block> {
+        match self.blocks.last_mut() {
+            Some(block) if block.seen_else => Err(anyhow!("{} after #else", directive)),
+            Some(block) => Ok(block),
+            None => Err(anyhow!("{} without a matching #if", directive)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span() -> Span {
+        Span {
+            file: "test.asm".to_string(),
+            line: 1,
+            columns: 0..3,
+            source_line: "#if".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_only_first_true_branch_is_active() {
+        let mut stack = ConditionalStack::default();
+        stack.open("#if", &span(), || Ok(false)).unwrap();
+        assert!(!stack.is_active());
+        stack.else_if("#elif", || Ok(true)).unwrap();
+        assert!(stack.is_active());
+        stack.else_if("#elif", || panic!("not evaluated")).unwrap();
+        assert!(!stack.is_active());
+        stack.otherwise("#else").unwrap();
+        assert!(!stack.is_active());
+        stack.close("#endif").unwrap();
+        assert!(stack.is_active());
+    }
+
+    #[test]
+    fn test_nested_blocks_in_skipped_code_stay_inactive() {
+        let mut stack = ConditionalStack::default();
+        stack.open("#if", &span(), || Ok(false)).unwrap();
+        stack
+            .open("#if", &span(), || panic!("not evaluated"))
+            .unwrap();
+        stack.otherwise("#else").unwrap();
+        assert!(!stack.is_active());
+        stack.close("#endif").unwrap();
+        stack.otherwise("#else").unwrap();
+        assert!(stack.is_active());
+    }
+
+    #[test]
+    fn test_mismatched_directives() {
+        let mut stack = ConditionalStack::default();
+        assert!(stack.close("#endif").is_err());
+        assert!(stack.otherwise("#else").is_err());
+
+        stack.open("#ifdef", &span(), || Ok(true)).unwrap();
+        stack.otherwise("#else").unwrap();
+        assert!(stack.otherwise("#else").is_err());
+        let unclosed = stack.close_from(0);
+        assert_eq!(unclosed[0].message, "Missing #endif for this #ifdef");
+        assert_eq!(stack.depth(), 0);
+    }
+}
@@ -0,0 +1,358 @@
+use anyhow::{anyhow, Result};
+use std::ops::RangeInclusive;
+
+use crate::assembler::expr::{Expr, SymbolResolver};
+use crate::assembler::symbols::{is_local, SymbolKind, SymbolTable};
+use crate::assembler::target::Target;
+use crate::constants::{
+    MAX_INDEX_DISPLACEMENT, MAX_RELATIVE_JUMP, MIN_INDEX_DISPLACEMENT, MIN_RELATIVE_JUMP,
+};
+use crate::diagnostics::Diagnostic;
+use crate::ti83plus::Charset;
+
+/// Which assembly pass is currently running.
+///
+/// Pass one runs the real encoders to learn the exact size of every line,
+/// so symbols that are not defined yet resolve to a placeholder value.
+/// Pass two emits the final bytes and requires every symbol to resolve.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pass {
+    Sizing,
+    Emit,
+}
+
+/// An anonymous label, and the direction of the references that can reach it.
+#[derive(Debug, Clone, Copy)]
+pub struct AnonymousLabel {
+    pub address: u32,
+    /// `-:` and `@@:` are found by `-` references
+    pub backward: bool,
+    /// `+:` and `@@:` are found by `+` references
+    pub forward: bool,
+}
+
+impl AnonymousLabel {
+    /// The label defined by `@@`, `+` or `-` at `address`.
+    pub fn new(name: &str, address: u32) -> Self {
+        AnonymousLabel {
+            address,
+            backward: name != "+",
+            forward: name != "-",
+        }
+    }
+}
+
+/// Symbol lookup state handed to the instruction and directive handlers.
+pub struct Context<'a> {
+    pub symbols: &'a SymbolTable,
+    /// The global label that local labels currently belong to
+    pub scope: Option<&'a str>,
+    /// All anonymous labels, in source order
+    pub anonymous: &'a [AnonymousLabel],
+    /// Number of anonymous labels up to and including the current line
+    pub anonymous_seen: usize,
+    pub current_address: u32,
+    pub pass: Pass,
+    /// Encoding of string literals, set with `.charset`
+    pub charset: Charset,
+    pub target: Target,
+    /// eZ80 ADL mode, where addresses and 16-bit immediates take three bytes
+    pub adl: bool,
+}
+
+impl SymbolResolver for Context<'_> {
+    /// Characters are encoded with the charset selected by `.charset`.
+    fn character(&self, ch: char) -> Result<i64> {
+        self.charset.encode_char(ch).map(i64::from)
+    }
+
+    /// During the sizing pass a symbol that is not defined yet evaluates to
+    /// the current address, which keeps relative jumps in range and never
+    /// changes the size of the encoding.
+    fn resolve(&self, name: &str) -> Result<i64> {
+        let name = self.symbols.scoped_name(name, self.scope)?;
+        if let Some(value) = self.symbols.value(&name) {
+            Ok(i64::from(value))
+        } else if self.pass == Pass::Sizing {
+            Ok(i64::from(self.current_address))
+        } else if is_local(&name) {
+            let message = match self.scope {
+                Some(scope) => format!("Undefined local label {} in scope {}", name, scope),
+                None => format!("Undefined local label {} outside any global label", name),
+            };
+            Err(Diagnostic::error(message)
+                .with_help("local labels belong to the global label before them")
+                .into())
+        } else {
+            Err(Diagnostic::error(format!("Undefined symbol: {}", name))
+                .with_help("define it as a label or with .equ")
+                .into())
+        }
+    }
+
+    /// `-` is the nearest `-:` or `@@:` label at or before this line, `+`
+    /// the nearest `+:` or `@@:` label after it.
+    fn resolve_anonymous(&self, offset: i64) -> Result<i64> {
+        let seen = self.anonymous_seen.min(self.anonymous.len());
+        let (before, after) = self.anonymous.split_at(seen);
+        let skip = (offset.unsigned_abs() as usize).saturating_sub(1);
+        let label = if offset < 0 {
+            before.iter().rev().filter(|label| label.backward).nth(skip)
+        } else {
+            after.iter().filter(|label| label.forward).nth(skip)
+        };
+        match label {
+            Some(label) => Ok(i64::from(label.address)),
+            None if self.pass == Pass::Sizing => Ok(i64::from(self.current_address)),
+            None => Err(anyhow!(
+                "No anonymous label for {}",
+                Expr::Anonymous(offset)
+            )),
+        }
+    }
+
+    fn current_address(&self) -> Result<i64> {
+        Ok(i64::from(self.current_address))
+    }
+
+    fn is_final(&self) -> bool {
+        self.pass == Pass::Emit
+    }
+}
+
+impl Context<'_> {
+    /// Evaluate an expression against the symbol table.
+    pub fn eval(&self, expr: &Expr) -> Result<i64> {
+        expr.evaluate(self)
+    }
+
+    /// Evaluate an 8-bit operand, written signed or unsigned.
+    pub fn byte(&self, expr: &Expr) -> Result<u8> {
+        Ok(self.sized(expr, 8)? as u8)
+    }
+
+    /// Evaluate a 16-bit operand, written signed or unsigned.
+    pub fn value(&self, expr: &Expr) -> Result<u16> {
+        Ok(self.sized(expr, 16)? as u16)
+    }
+
+    /// Evaluate a value of `bits` bits, checking its range once values are final.
+    fn sized(&self, expr: &Expr, bits: u32) -> Result<i64> {
+        let range = -(1 << (bits - 1))..=(1 << bits) - 1;
+        let value = self.ranged(expr, &range)?;
+        if self.pass == Pass::Emit && !range.contains(&value) {
+            return Err(
+                Diagnostic::error(format!("{}-bit value out of range: {}", bits, value))
+                    .with_help(format!(
+                        "use a value from {} to {}",
+                        range.start(),
+                        range.end()
+                    ))
+                    .into(),
+            );
+        }
+        Ok(value)
+    }
+
+    /// Evaluate an address, which wraps at the target's address width.
+    pub fn address(&self, expr: &Expr) -> Result<u32> {
+        Ok(self.eval(expr)? as u32 & self.target.address_mask())
+    }
+
+    /// Encode an address or 16-bit immediate in little-endian order.
+    ///
+    /// In ADL mode the value takes three bytes instead of two.
+    pub fn word(&self, expr: &Expr) -> Result<Vec<u8>> {
+        let size = if self.adl { 3 } else { 2 };
+        // Z80-mode code on the eZ80 still reaches 24-bit addresses through MBASE
+        let bits = self.target.address_mask().count_ones();
+        Ok(self.sized(expr, bits)?.to_le_bytes()[..size].to_vec())
+    }
+
+    /// Evaluate an expression that decides the size or address of code.
+    ///
+    /// Sizes and addresses must be exact in pass one, which runs once, so
+    /// placeholders are not allowed. Labels must be defined above the line;
+    /// constants that do not depend on labels are defined before pass one.
+    pub fn known_value(&self, expr: &Expr) -> Result<i64> {
+        let strict = Context {
+            pass: Pass::Emit,
+            ..*self
+        };
+        strict.eval(expr).map_err(|error| match error.downcast::<Diagnostic>() {
+            Ok(diagnostic) => diagnostic
+                .with_note("this value sets the size or address of code, so it cannot depend on labels defined further down")
+                .into(),
+            Err(error) => error,
+        })
+    }
+
+    /// Compute the displacement byte for a relative jump of `length` bytes.
+    ///
+    /// The range is only checked once addresses are final.
+    pub fn relative_offset(&self, target: u32, length: u32, mnemonic: &str) -> Result<u8> {
+        let offset = i64::from(target) - (i64::from(self.current_address) + i64::from(length));
+        let range = i64::from(MIN_RELATIVE_JUMP)..=i64::from(MAX_RELATIVE_JUMP);
+        if self.pass == Pass::Emit && !range.contains(&offset) {
+            return Err(Diagnostic::error(format!(
+                "{} target out of range: offset {}",
+                mnemonic.to_uppercase(),
+                offset
+            ))
+            .with_help("use jp, which can reach any address")
+            .into());
+        }
+        Ok(offset as u8)
+    }
+
+    /// Evaluate an expression whose value should be within `range`.
+    ///
+    /// Symbols hold values wrapped to the address width, so `d .equ -5`
+    /// reads back as $FFFB. A value outside the range is evaluated again with
+    /// equates read as signed numbers; literals always keep the value written.
+    pub fn ranged(&self, expr: &Expr, range: &RangeInclusive<i64>) -> Result<i64> {
+        let value = self.eval(expr)?;
+        if range.contains(&value) {
+            return Ok(value);
+        }
+        expr.evaluate(&SignedEquates(self))
+    }
+
+    /// Evaluate the `d` of `(ix+d)`, checking its range once values are final.
+    pub fn displacement(&self, disp: &Expr) -> Result<u8> {
+        let range = i64::from(MIN_INDEX_DISPLACEMENT)..=i64::from(MAX_INDEX_DISPLACEMENT);
+        let value = self.ranged(disp, &range)?;
+        if self.pass == Pass::Emit && !range.contains(&value) {
+            return Err(
+                Diagnostic::error(format!("Index displacement out of range: {}", value))
+                    .with_note(format!(
+                        "displacements must be between {} and {}",
+                        MIN_INDEX_DISPLACEMENT, MAX_INDEX_DISPLACEMENT
+                    ))
+                    .with_help("point the index register closer to the field")
+                    .into(),
+            );
+        }
+        Ok(value as u8)
+    }
+}
+
+/// Resolver that reads `.equ` and `.set` symbols as signed numbers.
+struct SignedEquates<'c, 'a>(&'c Context<'a>);
+
+impl SymbolResolver for SignedEquates<'_, '_> {
+    fn resolve(&self, name: &str) -> Result<i64> {
+        let ctx = self.0;
+        let value = ctx.resolve(name)?;
+        let name = ctx.symbols.scoped_name(name, ctx.scope)?;
+        match ctx.symbols.get(&name) {
+            Some(symbol) if matches!(symbol.kind, SymbolKind::Equate | SymbolKind::Set) => {
+                let bits = 64 - ctx.target.address_mask().count_ones();
+                Ok((value << bits) >> bits)
+            },
+            _ => Ok(value),
+        }
+    }
+
+    fn current_address(&self) -> Result<i64> {
+        self.0.current_address()
+    }
+
+    fn resolve_anonymous(&self, offset: i64) -> Result<i64> {
+        self.0.resolve_anonymous(offset)
+    }
+
+    fn is_final(&self) -> bool {
+        self.0.is_final()
+    }
+
+    fn character(&self, ch: char) -> Result<i64> {
+        self.0.character(ch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sizing_pass_uses_placeholder() {
+        let symbols = SymbolTable::new();
+        let ctx = Context {
+            symbols: &symbols,
+            scope: None,
+            anonymous: &[],
+            anonymous_seen: 0,
+            current_address: 0x9D95,
+            pass: Pass::Sizing,
+            charset: Charset::Ascii,
+            target: Target::TI83Plus,
+            adl: false,
+        };
+        let later = Expr::Symbol("later".to_string());
+        assert_eq!(ctx.value(&later).unwrap(), 0x9D95);
+    }
+
+    #[test]
+    fn test_emit_pass_requires_symbols() {
+        let symbols = SymbolTable::new();
+        let ctx = Context {
+            symbols: &symbols,
+            scope: None,
+            anonymous: &[],
+            anonymous_seen: 0,
+            current_address: 0x9D95,
+            pass: Pass::Emit,
+            charset: Charset::Ascii,
+            target: Target::TI83Plus,
+            adl: false,
+        };
+        assert!(ctx.value(&Expr::Symbol("later".to_string())).is_err());
+        assert!(ctx.relative_offset(0x9E95, 2, "jr").is_err());
+        assert_eq!(ctx.displacement(&Expr::Number(-128)).unwrap(), 0x80);
+        assert!(ctx.displacement(&Expr::Number(128)).is_err());
+    }
+
+    #[test]
+    fn test_system_variables_resolve_everywhere() {
+        let symbols = SymbolTable::new();
+        let ctx = Context {
+            symbols: &symbols,
+            scope: None,
+            anonymous: &[],
+            anonymous_seen: 0,
+            current_address: 0x9D95,
+            pass: Pass::Emit,
+            charset: Charset::Ascii,
+            target: Target::TI83Plus,
+            adl: false,
+        };
+        let pen_col = Expr::Symbol("penCol".to_string());
+        assert_eq!(ctx.value(&pen_col).unwrap(), 0x86D7);
+    }
+
+    #[test]
+    fn test_adl_mode_uses_24_bit_words() {
+        let symbols = SymbolTable::for_target(Target::TI84PlusCE);
+        let mut ctx = Context {
+            symbols: &symbols,
+            scope: None,
+            anonymous: &[],
+            anonymous_seen: 0,
+            current_address: 0xD1A881,
+            pass: Pass::Emit,
+            charset: Charset::Ascii,
+            target: Target::TI84PlusCE,
+            adl: true,
+        };
+        assert_eq!(
+            ctx.word(&Expr::CurrentAddress).unwrap(),
+            vec![0x81, 0xA8, 0xD1]
+        );
+        assert_eq!(ctx.address(&Expr::Number(-1)).unwrap(), 0xFF_FFFF);
+        assert_eq!(ctx.relative_offset(0xD1A8A0, 2, "jr").unwrap(), 0x1D);
+
+        ctx.adl = false;
+        assert_eq!(ctx.word(&Expr::CurrentAddress).unwrap(), vec![0x81, 0xA8]);
+    }
+}
@@ -1,24 +1,102 @@
 use anyhow::{anyhow, Result};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
 
-use crate::assembler::parser::Parser;
-use crate::constants::{RST_28H, TI83_PLUS_ORIGIN};
-use crate::directives::handle_data_directive;
-use crate::instructions::opcodes::{OPCODES, REG_LOAD_IMMEDIATE};
+use crate::assembler::context::{AnonymousLabel, Context, Pass};
+use crate::assembler::dialect::Dialect;
+use crate::assembler::expr::Expr;
+use crate::assembler::operand::Operand;
+use crate::assembler::parser::{ParsedLine, Parser};
+use crate::assembler::preprocessor::{ExpandedLine, Preprocessor};
+use crate::assembler::result::{AssemblyResult, LineRecord};
+use crate::assembler::symbols::{is_anonymous, is_local, qualify, SymbolKind, SymbolTable};
+use crate::assembler::target::Target;
+use crate::constants::{BJUMP_ADDRESS, CALL_NN, JP_NN, RST_28H};
+use crate::diagnostics::{AssembleError, Diagnostic, Span};
+use crate::directives::{check_assertion, format_message, handle_data_directive, handle_incbin};
+use crate::instructions::cycles::{instruction_cycles, Cycles};
+use crate::instructions::ez80::suffix_prefix;
+use crate::instructions::opcodes::OPCODES;
 use crate::instructions::{
     handle_arithmetic_instruction, handle_bit_instruction, handle_call_instruction,
-    handle_index_instruction, handle_io_instruction, handle_jump_instruction,
-    handle_load_instruction,
+    handle_ez80_instruction, handle_index_instruction, handle_io_instruction,
+    handle_jump_instruction, handle_load_instruction, handle_undocumented_instruction,
 };
-use crate::ti83plus::rom_calls::ROM_CALLS;
-use crate::utils::immediate::parse_immediate;
+use crate::ti83plus::Charset;
 
+/// Assembler configuration.
+///
+/// Each call to [`Z80Assembler::assemble`] starts a fresh session, so symbols
+/// from one source never leak into the next.
 pub struct Z80Assembler {
     parser: Parser,
-    labels: HashMap<String, u16>,
-    constants: HashMap<String, u16>,
-    org_address: u16,
-    current_address: u16,
+    file_name: String,
+    target: Target,
+    origin: Option<u32>,
+    predefined: BTreeMap<String, u32>,
+    defines: BTreeMap<String, String>,
+    include_paths: Vec<PathBuf>,
+    undocumented: bool,
+}
+
+/// A parsed source line together with its location for diagnostics.
+struct SourceLine {
+    /// Index among the expanded lines, used to order diagnostics
+    position: usize,
+    source: ExpandedLine,
+    parsed: ParsedLine,
+    /// Set once the line has produced an error, so later passes skip it
+    failed: bool,
+}
+
+/// State of a single assembly run.
+struct Session {
+    symbols: SymbolTable,
+    rom_calls: BTreeMap<String, u32>,
+    org_address: u32,
+    current_address: u32,
+    /// The most recent global label, which owns the local labels after it
+    scope: Option<String>,
+    anonymous: Vec<AnonymousLabel>,
+    anonymous_seen: usize,
+    include_paths: Vec<PathBuf>,
+    /// Warnings and messages from the line being assembled
+    reports: Vec<Diagnostic>,
+    charset: Charset,
+    /// Equates whose value used symbols defined further down in pass one
+    unresolved: Vec<Unresolved>,
+    /// Whether undocumented instructions are allowed without a warning
+    undocumented: bool,
+    target: Target,
+    /// eZ80 ADL mode, set with `.assume adl=1`
+    adl: bool,
+    /// `.cycles begin` regions not closed yet, innermost last
+    cycle_regions: Vec<CycleRegion>,
+    /// Index of the line being assembled among the expanded lines
+    position: usize,
+    /// Lines whose `.equ` was defined before pass two, which only checks them
+    ///
+    /// Every expansion of a macro or loop body shares the body line's span,
+    /// so the line index is what tells a second definition from a repeat.
+    defined_equates: HashSet<usize>,
+}
+
+/// A `.cycles` region and the T-states of the instructions in it so far.
+struct CycleRegion {
+    span: Span,
+    cycles: Cycles,
+}
+
+/// An equate to evaluate again once more symbols are defined.
+struct Unresolved {
+    mnemonic: String,
+    operands: Vec<Operand>,
+    span: Span,
+    position: usize,
+    /// The line's address, scope and anonymous label count, as seen by `$`, locals and `-`/`+`
+    address: u32,
+    scope: Option<String>,
+    anonymous_seen: usize,
 }
 
 impl Default for Z80Assembler {
@@ -31,158 +109,715 @@ impl Z80Assembler {
     pub fn new() -> Self {
         Z80Assembler {
             parser: Parser::new(),
-            labels: HashMap::new(),
-            constants: HashMap::new(),
-            org_address: TI83_PLUS_ORIGIN,
-            current_address: TI83_PLUS_ORIGIN,
+            file_name: "<source>".to_string(),
+            target: Target::default(),
+            origin: None,
+            predefined: BTreeMap::new(),
+            defines: BTreeMap::new(),
+            include_paths: Vec::new(),
+            undocumented: false,
         }
     }
 
-    pub fn assemble(&mut self, source: &str) -> Result<Vec<u8>> {
-        let lines: Vec<&str> = source.lines().collect();
+    /// Also accept the conventions of SPASM-ng, TASM or Brass sources.
+    pub fn with_dialect(mut self, dialect: Dialect) -> Self {
+        self.parser = Parser::new().with_dialect(dialect);
+        self
+    }
+
+    /// Name of the source file shown in diagnostics.
+    pub fn with_file_name(mut self, file_name: impl Into<String>) -> Self {
+        self.file_name = file_name.into();
+        self
+    }
+
+    /// Calculator model to assemble for.
+    pub fn with_target(mut self, target: Target) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// Address the code is assembled at until the first `.org`.
+    ///
+    /// Defaults to the load address of the target.
+    pub fn with_origin(mut self, origin: u32) -> Self {
+        self.origin = Some(origin);
+        self
+    }
+
+    /// Define a constant before the source is read, like an `.equ` at the top.
+    pub fn with_symbol(mut self, name: impl Into<String>, value: u32) -> Self {
+        self.predefined.insert(name.into(), value);
+        self
+    }
+
+    /// Define a name before the source is read, like a `#define` at the top.
+    pub fn with_define(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.defines.insert(name.into(), value.into());
+        self
+    }
+
+    /// Allow undocumented instructions such as `sll` and `ld a,ixh` without a warning.
+    ///
+    /// A `.undoc` line allows them from that line on.
+    pub fn with_undocumented(mut self, undocumented: bool) -> Self {
+        self.undocumented = undocumented;
+        self
+    }
 
-        self.current_address = self.org_address;
-        for line in &lines {
-            if let Some(parsed) = self.parser.parse_line(line) {
-                if let Some(label) = parsed.label {
-                    self.labels.insert(label, self.current_address);
+    /// Directory searched for `.include` files not found next to the including file.
+    pub fn with_include_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.include_paths.push(path.into());
+        self
+    }
+
+    /// Assemble a complete source file.
+    ///
+    /// Included files are looked up relative to the file name given with
+    /// [`Z80Assembler::with_file_name`], then in the include paths.
+    ///
+    /// Errors do not stop assembly: every line is still processed so that
+    /// all problems are reported together in the returned [`AssembleError`].
+    pub fn assemble(&self, source: &str) -> Result<AssemblyResult, AssembleError> {
+        // Diagnostics are kept with the index of their line to report them in source order
+        let mut preprocessor =
+            Preprocessor::new(&self.parser, &self.file_name, &self.include_paths);
+        for (name, value) in &self.defines {
+            preprocessor.add_define(name, value);
+        }
+        for (name, &value) in &self.predefined {
+            preprocessor.add_constant(name, value);
+        }
+        let (expanded, mut diagnostics) = preprocessor.run(source);
+
+        // Each line is parsed once; both passes work on the typed operands
+        let mut lines = Vec::new();
+        for (position, source) in expanded.into_iter().enumerate() {
+            match self.parser.parse_line(&source.text) {
+                Ok(Some(parsed)) => lines.push(SourceLine {
+                    position,
+                    source,
+                    parsed,
+                    failed: false,
+                }),
+                Ok(None) => {},
+                Err(e) => {
+                    diagnostics.push((position, source.diagnostic(e)));
+                    // Keep the label so references to it do not cascade into more errors
+                    let parsed = ParsedLine {
+                        label: self.parser.parse_label(&source.text),
+                        mnemonic: None,
+                        operands: Vec::new(),
+                    };
+                    lines.push(SourceLine {
+                        position,
+                        source,
+                        parsed,
+                        failed: true,
+                    });
+                },
+            }
+        }
+
+        let origin = self.origin.unwrap_or(self.target.origin());
+        let mut symbols = SymbolTable::for_target(self.target);
+        for (name, &value) in &self.predefined {
+            symbols
+                .define(name, SymbolKind::Equate, value, None)
+                .expect("predefined symbols have unique names");
+        }
+        let mut session = Session {
+            symbols,
+            rom_calls: BTreeMap::new(),
+            org_address: origin,
+            current_address: origin,
+            scope: None,
+            anonymous: Vec::new(),
+            anonymous_seen: 0,
+            include_paths: self.include_paths.clone(),
+            reports: Vec::new(),
+            charset: Charset::default(),
+            unresolved: Vec::new(),
+            undocumented: self.undocumented,
+            target: self.target,
+            adl: self.target.is_ez80(),
+            cycle_regions: Vec::new(),
+            position: 0,
+            defined_equates: HashSet::new(),
+        };
+
+        session.define_constants(&lines);
+
+        // Pass one runs the real encoders with placeholder values for symbols
+        // that are not defined yet, so every label gets its exact address.
+        let mut sizes = Vec::with_capacity(lines.len());
+        for line in &mut lines {
+            session.position = line.position;
+            if let Some(label) = &line.parsed.label {
+                if let Err(e) = session.enter_label(label, &line.source.span, Pass::Sizing) {
+                    diagnostics.push((line.position, line.source.diagnostic(e)));
                 }
+            }
 
-                if let Some(mnemonic) = &parsed.mnemonic {
-                    if mnemonic == ".org" {
-                        if let Some(operands) = &parsed.operands {
-                            self.org_address = parse_immediate(operands, &self.constants)?;
-                            self.current_address = self.org_address;
-                        }
-                    } else if mnemonic != ".equ" {
-                        let size =
-                            self.estimate_instruction_size(mnemonic, parsed.operands.as_deref());
-                        self.current_address = self.current_address.wrapping_add(size as u16);
-                    }
+            let mut size = 0;
+            if let Some(mnemonic) = &line.parsed.mnemonic {
+                match session.assemble_instruction(
+                    mnemonic,
+                    &line.parsed.operands,
+                    &line.source.span,
+                    Pass::Sizing,
+                ) {
+                    Ok(code) => size = code.len(),
+                    Err(e) => {
+                        diagnostics.push((line.position, line.source.diagnostic(e)));
+                        line.failed = true;
+                    },
                 }
             }
+            sizes.push(size);
+            session.advance(size);
         }
 
+        session.resolve_equates();
+
         let mut output = Vec::new();
-        self.current_address = self.org_address;
-
-        for line in &lines {
-            if let Some(parsed) = self.parser.parse_line(line) {
-                if let Some(mnemonic) = parsed.mnemonic {
-                    let code = self.assemble_instruction(&mnemonic, parsed.operands.as_deref())?;
-                    output.extend_from_slice(&code);
-                    self.current_address = self.current_address.wrapping_add(code.len() as u16);
+        let mut records = Vec::with_capacity(lines.len());
+        session.org_address = origin;
+        session.current_address = origin;
+        session.rom_calls.clear();
+        session.scope = None;
+        session.anonymous_seen = 0;
+        session.charset = Charset::default();
+        session.undocumented = self.undocumented;
+        session.adl = self.target.is_ez80();
+
+        for (line, &size) in lines.into_iter().zip(&sizes) {
+            session.position = line.position;
+            if let Some(label) = &line.parsed.label {
+                // Definition errors were already reported in pass one
+                let _ = session.enter_label(label, &line.source.span, Pass::Emit);
+            }
+
+            let mut bytes = Vec::new();
+            let mut cycles = None;
+            if let (Some(mnemonic), false) = (&line.parsed.mnemonic, line.failed) {
+                session.record_references(mnemonic, &line.parsed.operands, &line.source.span);
+                match session.assemble_instruction(
+                    mnemonic,
+                    &line.parsed.operands,
+                    &line.source.span,
+                    Pass::Emit,
+                ) {
+                    Ok(code) if code.len() != size => {
+                        let error = Diagnostic::error(format!(
+                            "Instruction size changed between passes ({} bytes in pass one, {} in pass two)",
+                            size,
+                            code.len()
+                        ))
+                        .with_note("labels after this line would point to the wrong address");
+                        diagnostics.push((line.position, line.source.diagnostic(error.into())));
+                    },
+                    Ok(code) => bytes = code,
+                    Err(e) => diagnostics.push((line.position, line.source.diagnostic(e))),
                 }
+                cycles = session.count_cycles(mnemonic, &bytes);
+                for report in session.reports.drain(..) {
+                    diagnostics.push((line.position, line.source.diagnostic(report.into())));
+                }
+            }
+            output.extend_from_slice(&bytes);
+            // Read after the instruction so an `.org` line reports its new address
+            records.push(LineRecord {
+                span: line.source.span,
+                address: session.current_address,
+                bytes,
+                cycles,
+            });
+            session.advance(size);
+        }
+        for region in session.cycle_regions.drain(..) {
+            let error =
+                Diagnostic::error(".cycles begin without .cycles end").with_span(region.span);
+            diagnostics.push((usize::MAX, error));
+        }
+
+        diagnostics.sort_by_key(|(position, _)| *position);
+        let diagnostics: Vec<Diagnostic> = diagnostics
+            .into_iter()
+            .map(|(_, diagnostic)| diagnostic)
+            .collect();
+        if diagnostics.iter().any(Diagnostic::is_error) {
+            return Err(AssembleError { diagnostics });
+        }
+
+        Ok(AssemblyResult {
+            bytes: output,
+            symbols: session.symbols,
+            lines: records,
+            warnings: diagnostics,
+            rom_calls: session.rom_calls,
+        })
+    }
+}
+
+impl Session {
+    /// Define a label in pass one and track the scope and anonymous labels in both passes.
+    fn enter_label(&mut self, label: &str, span: &Span, pass: Pass) -> Result<()> {
+        let address = self.current_address;
+        if is_anonymous(label) {
+            if pass == Pass::Sizing {
+                self.anonymous.push(AnonymousLabel::new(label, address));
+            }
+            self.anonymous_seen += 1;
+            return Ok(());
+        }
+
+        let name = if is_local(label) {
+            let Some(scope) = &self.scope else {
+                return Err(anyhow!(
+                    "Local label {} has no enclosing global label",
+                    label
+                ));
+            };
+            qualify(scope, label)
+        } else if label.contains('.') {
+            // Qualified names like `draw.loop` and macro-local labels keep the current scope
+            label.to_string()
+        } else {
+            self.scope = Some(label.to_string());
+            label.to_string()
+        };
+
+        if pass == Pass::Sizing {
+            self.symbols
+                .define(&name, SymbolKind::Label, address, Some(span))?;
+        }
+        Ok(())
+    }
+
+    /// Record the symbols used by a line's operands in the symbol table, and
+    /// the ROM calls among them, however they are called.
+    fn record_references(&mut self, mnemonic: &str, operands: &[Operand], span: &Span) {
+        // The first argument of a definition is the name being defined
+        let used = match (mnemonic, operands) {
+            (".equ" | ".set", [_, rest @ ..]) => rest,
+            _ => operands,
+        };
+        for name in used.iter().flat_map(Operand::symbols) {
+            let name = self
+                .symbols
+                .scoped_name(name, self.scope.as_deref())
+                .unwrap_or_else(|_| name.to_string());
+            self.symbols.add_reference(&name, span);
+            if let Some(symbol) = self.symbols.get(&name) {
+                if symbol.kind == SymbolKind::RomCall {
+                    self.rom_calls.insert(name, symbol.value);
+                }
+            }
+        }
+    }
+
+    /// Define the symbol of an `.equ` or `.set` line.
+    ///
+    /// In pass one the value may use symbols that are only defined further
+    /// down, so an equate that cannot be evaluated yet is left undefined and
+    /// evaluated again after the pass.
+    fn define_equate(
+        &mut self,
+        mnemonic: &str,
+        operands: &[Operand],
+        span: &Span,
+        pass: Pass,
+    ) -> Result<()> {
+        let [name, value] = operands else {
+            return Err(anyhow!("{} requires name and value", mnemonic));
+        };
+        let Expr::Symbol(name) = name.expression()? else {
+            return Err(anyhow!("{} requires name and value", mnemonic));
+        };
+        let kind = if mnemonic == ".set" {
+            SymbolKind::Set
+        } else {
+            SymbolKind::Equate
+        };
+        if kind == SymbolKind::Equate && self.defined_equates.contains(&self.position) {
+            return Ok(());
+        }
+        // A placeholder value could set the size of a later line, so pass one is strict
+        let value = match self.context(Pass::Emit).address(value.expression()?) {
+            Ok(value) => value,
+            Err(_) if pass == Pass::Sizing => {
+                self.unresolved.push(Unresolved {
+                    mnemonic: mnemonic.to_string(),
+                    operands: operands.to_vec(),
+                    span: span.clone(),
+                    position: self.position,
+                    address: self.current_address,
+                    scope: self.scope.clone(),
+                    anonymous_seen: self.anonymous_seen,
+                });
+                return Ok(());
+            },
+            Err(e) => return Err(e),
+        };
+        self.symbols.define(name, kind, value, Some(span))?;
+        if kind == SymbolKind::Equate {
+            self.defined_equates.insert(self.position);
+        }
+        Ok(())
+    }
+
+    /// Define the equates whose values do not depend on where code is placed,
+    /// before pass one.
+    ///
+    /// Pass one fixes the size of `.ds SIZE` and the address of `.org START`,
+    /// so this lets them use constants defined further down. Equates on
+    /// labels, `$` or character constants are left to pass one.
+    fn define_constants(&mut self, lines: &[SourceLine]) {
+        loop {
+            let count = self.defined_equates.len();
+            for line in lines {
+                self.position = line.position;
+                if let Some(label) = &line.parsed.label {
+                    // Only tracks the scope, as pass two does
+                    let _ = self.enter_label(label, &line.source.span, Pass::Emit);
+                }
+                let (Some(".equ"), [_, value]) = (
+                    line.parsed.mnemonic.as_deref(),
+                    line.parsed.operands.as_slice(),
+                ) else {
+                    continue;
+                };
+                if line.failed || !value.expression().is_ok_and(Expr::is_constant) {
+                    continue;
+                }
+                // Errors are reported when pass one defines the equate
+                let _ = self.define_equate(
+                    ".equ",
+                    &line.parsed.operands,
+                    &line.source.span,
+                    Pass::Emit,
+                );
+            }
+            self.scope = None;
+            self.anonymous_seen = 0;
+            if self.defined_equates.len() == count {
+                break;
+            }
+        }
+    }
+
+    /// Evaluate the equates left undefined by pass one until no more can be.
+    ///
+    /// Each round defines at least one more symbol, so chains like
+    /// `a .equ b`, `b .equ c`, `c .equ end` resolve in a few rounds. Equates
+    /// that still fail are reported by pass two.
+    fn resolve_equates(&mut self) {
+        loop {
+            let pending = std::mem::take(&mut self.unresolved);
+            let count = pending.len();
+            for equate in pending {
+                self.current_address = equate.address;
+                self.scope = equate.scope;
+                self.anonymous_seen = equate.anonymous_seen;
+                self.position = equate.position;
+                // Duplicate definitions are reported by pass two
+                let _ = self.define_equate(
+                    &equate.mnemonic,
+                    &equate.operands,
+                    &equate.span,
+                    Pass::Sizing,
+                );
+            }
+            if self.unresolved.is_empty() || self.unresolved.len() == count {
+                break;
             }
         }
+        self.unresolved.clear();
+    }
 
-        Ok(output)
+    fn context(&self, pass: Pass) -> Context<'_> {
+        Context {
+            symbols: &self.symbols,
+            scope: self.scope.as_deref(),
+            anonymous: &self.anonymous,
+            anonymous_seen: self.anonymous_seen,
+            current_address: self.current_address,
+            pass,
+            charset: self.charset,
+            target: self.target,
+            adl: self.adl,
+        }
     }
 
-    fn assemble_instruction(&mut self, mnemonic: &str, operands: Option<&str>) -> Result<Vec<u8>> {
-        let mut result = Vec::new();
+    /// T-states of an instruction line, also added to every open `.cycles` region.
+    ///
+    /// Directives and ROM calls have no count: the time an OS routine takes
+    /// depends on the OS version. eZ80 timings depend on the calculator's
+    /// memory wait states, so nothing is counted for the TI-84 Plus CE.
+    fn count_cycles(&mut self, mnemonic: &str, code: &[u8]) -> Option<Cycles> {
+        if self.target.is_ez80()
+            || mnemonic.starts_with('.')
+            || matches!(mnemonic, "bcall" | "b_call" | "bjump")
+        {
+            return None;
+        }
+        let cycles = instruction_cycles(code)?;
+        for region in &mut self.cycle_regions {
+            region.cycles += cycles;
+        }
+        Some(cycles)
+    }
+
+    /// Handle `.cycles begin`, or `.cycles end`, which reports the T-states
+    /// of the instructions since the matching `begin`.
+    fn cycle_region(&mut self, operands: &[Operand], span: &Span) -> Result<()> {
+        let [Operand::Immediate(Expr::Symbol(action))] = operands else {
+            return Err(anyhow!(".cycles requires begin or end"));
+        };
+        match action.to_lowercase().as_str() {
+            "begin" => self.cycle_regions.push(CycleRegion {
+                span: span.clone(),
+                cycles: Cycles::default(),
+            }),
+            "end" => {
+                let region = self
+                    .cycle_regions
+                    .pop()
+                    .ok_or_else(|| anyhow!(".cycles end without .cycles begin"))?;
+                let mut note = Diagnostic::note(format!(
+                    "{} T-states since the .cycles begin on line {}",
+                    region.cycles, region.span.line
+                ));
+                if region.cycles.is_conditional() {
+                    note = note.with_note(
+                        "counted with every branch taken and block instruction repeated, then with none",
+                    );
+                }
+                self.reports.push(note);
+            },
+            _ => {
+                return Err(anyhow!(
+                    "Unknown .cycles action {}, expected begin or end",
+                    action
+                ))
+            },
+        }
+        Ok(())
+    }
+
+    /// Move past a line of `size` bytes.
+    fn advance(&mut self, size: usize) {
+        let address = self.current_address.wrapping_add(size as u32);
+        self.current_address = address & self.target.address_mask();
+    }
+
+    /// Assemble an eZ80 instruction with a suffix such as `.lil`, which adds a
+    /// prefix byte and sets the width of its immediate values.
+    fn assemble_suffixed(
+        &mut self,
+        mnemonic: &str,
+        operands: &[Operand],
+        span: &Span,
+        pass: Pass,
+    ) -> Result<Vec<u8>> {
+        let (base, suffix) = mnemonic.split_once('.').unwrap_or((mnemonic, ""));
+        let Some((prefix, long)) = suffix_prefix(suffix) else {
+            return Err(anyhow!("Unknown instruction: {}", mnemonic));
+        };
+        if !self.target.is_ez80() {
+            return Err(ez80_only(format!(
+                "The .{} suffix is an eZ80 feature",
+                suffix
+            )));
+        }
+        let adl = std::mem::replace(&mut self.adl, long);
+        let code = self.assemble_instruction(base, operands, span, pass);
+        self.adl = adl;
+        Ok([vec![prefix], code?].concat())
+    }
+
+    /// Handle `.assume adl=1` or `adl=0`, which switches the eZ80 between
+    /// 24-bit ADL mode and Z80 mode.
+    fn assume(&mut self, operands: &[Operand]) -> Result<()> {
+        let [Operand::Immediate(Expr::Symbol(setting)), value] = operands else {
+            return Err(anyhow!(".assume requires a setting such as adl=1"));
+        };
+        if setting != "adl" {
+            return Err(anyhow!("Unknown .assume setting {}, expected adl", setting));
+        }
+        if !self.target.is_ez80() {
+            return Err(ez80_only(".assume adl is an eZ80 feature".to_string()));
+        }
+        self.adl = self.context(Pass::Emit).value(value.expression()?)? != 0;
+        Ok(())
+    }
+
+    fn assemble_instruction(
+        &mut self,
+        mnemonic: &str,
+        operands: &[Operand],
+        span: &Span,
+        pass: Pass,
+    ) -> Result<Vec<u8>> {
+        if mnemonic.contains('.') && !mnemonic.starts_with('.') {
+            return self.assemble_suffixed(mnemonic, operands, span, pass);
+        }
 
         match mnemonic {
             ".org" => {
-                if let Some(ops) = operands {
-                    self.org_address = parse_immediate(ops, &self.constants)?;
+                if let [address] = operands {
+                    // The origin must be known in pass one
+                    let address = self.context(pass).known_value(address.expression()?)?;
+                    self.org_address = address as u32 & self.target.address_mask();
                     self.current_address = self.org_address;
                 }
                 return Ok(vec![]);
             },
             ".end" => return Ok(vec![]),
-            ".equ" => {
-                if let Some(ops) = operands {
-                    let parts: Vec<&str> = ops.split(',').map(|s| s.trim()).collect();
-                    if parts.len() != 2 {
-                        return Err(anyhow!(".equ requires name and value"));
+            ".charset" => {
+                let name = match operands {
+                    [Operand::Immediate(Expr::Symbol(name))] => name.clone(),
+                    [Operand::Immediate(Expr::Str(name))] => name.to_plain(),
+                    _ => return Err(anyhow!(".charset requires a charset name: ti or ascii")),
+                };
+                self.charset = Charset::from_name(&name)
+                    .ok_or_else(|| anyhow!("Unknown charset {}, expected ti or ascii", name))?;
+                return Ok(vec![]);
+            },
+            // Checks and messages run once, when every address is final
+            ".assert" | ".error" | ".warning" | ".echo" => {
+                if pass == Pass::Emit {
+                    let ctx = self.context(pass);
+                    match mnemonic {
+                        ".assert" => check_assertion(operands, &ctx)?,
+                        ".error" => return Err(anyhow!("{}", format_message(operands, &ctx)?)),
+                        ".warning" => {
+                            let message = format_message(operands, &ctx)?;
+                            self.reports.push(Diagnostic::warning(message));
+                        },
+                        _ => {
+                            let message = format_message(operands, &ctx)?;
+                            self.reports.push(Diagnostic::note(message));
+                        },
                     }
-                    let value = parse_immediate(parts[1], &self.constants)?;
-                    self.constants.insert(parts[0].to_string(), value);
+                }
+                return Ok(vec![]);
+            },
+            ".incbin" => {
+                // The size of the included range must be known in pass one
+                let ctx = self.context(Pass::Emit);
+                return handle_incbin(operands, &span.file, &self.include_paths, &ctx);
+            },
+            ".assume" => {
+                self.assume(operands)?;
+                return Ok(vec![]);
+            },
+            // Regions are counted once, from the final encodings
+            ".cycles" => {
+                if pass == Pass::Emit {
+                    self.cycle_region(operands, span)?;
+                }
+                return Ok(vec![]);
+            },
+            ".undoc" => {
+                if !operands.is_empty() {
+                    return Err(anyhow!(".undoc takes no arguments"));
+                }
+                self.undocumented = true;
+                return Ok(vec![]);
+            },
+            ".equ" | ".set" => {
+                if !operands.is_empty() {
+                    self.define_equate(mnemonic, operands, span, pass)?;
                 }
                 return Ok(vec![]);
             },
             _ => {},
         }
 
-        if let Some(data) =
-            handle_data_directive(mnemonic, operands, &self.labels, &self.constants)?
-        {
+        let ctx = self.context(pass);
+
+        if let Some(data) = handle_data_directive(mnemonic, operands, &ctx)? {
             return Ok(data);
         }
 
-        if mnemonic == "bcall" {
-            if let Some(ops) = operands {
-                let call_name = ops.trim_start_matches('(').trim_end_matches(')');
-                if let Some(&address) = ROM_CALLS.get(call_name) {
-                    result.push(RST_28H);
-                    result.push((address & 0xff) as u8);
-                    result.push(((address >> 8) & 0xff) as u8);
-                    return Ok(result);
-                } else {
-                    return Err(anyhow!("Unknown ROM call: {}", call_name));
-                }
+        if matches!(mnemonic, "bcall" | "b_call" | "bjump") {
+            if let [Operand::Immediate(Expr::Symbol(call_name))
+            | Operand::Address(Expr::Symbol(call_name))] = operands
+            {
+                let Some(address) = self.target.rom_call(call_name) else {
+                    return Err(
+                        Diagnostic::error(format!("Unknown ROM call: {}", call_name))
+                            .with_help("ROM call names are case-sensitive, e.g. _PutS")
+                            .into(),
+                    );
+                };
+                return Ok(rom_call(self.target, mnemonic, address));
             }
         }
 
-        if let Some(code) =
-            handle_index_instruction(mnemonic, operands, &self.labels, &self.constants)?
-        {
+        if let Some(code) = handle_ez80_instruction(mnemonic, operands, &ctx)? {
+            if !self.target.is_ez80() {
+                return Err(ez80_only(format!("{} is an eZ80 instruction", mnemonic)));
+            }
             return Ok(code);
         }
 
-        if mnemonic == "ld" {
-            if let Some(ops) = operands {
-                if let Some(code) =
-                    handle_load_instruction(ops, &self.labels, &self.constants, &self.parser)?
-                {
-                    return Ok(code);
-                }
+        if let Some(code) = handle_undocumented_instruction(mnemonic, operands, &ctx)? {
+            if pass == Pass::Emit && !self.undocumented {
+                let operands: Vec<String> = operands.iter().map(|op| op.to_string()).collect();
+                let warning = Diagnostic::warning(format!(
+                    "Undocumented instruction: {} {}",
+                    mnemonic,
+                    operands.join(",")
+                ))
+                .with_help("allow undocumented instructions with --undocumented or .undoc");
+                self.reports.push(warning);
             }
+            return Ok(code);
         }
 
-        if let Some(code) = handle_jump_instruction(
-            mnemonic,
-            operands,
-            &self.labels,
-            &self.constants,
-            self.current_address,
-        )? {
+        if let Some(code) = handle_index_instruction(mnemonic, operands, &ctx)? {
             return Ok(code);
         }
 
-        if let Some(code) =
-            handle_call_instruction(mnemonic, operands, &self.labels, &self.constants)?
-        {
-            return Ok(code);
+        if mnemonic == "ld" {
+            if let Some(code) = handle_load_instruction(operands, &ctx)? {
+                return Ok(code);
+            }
         }
 
-        if let Some(code) = handle_arithmetic_instruction(mnemonic, operands, &self.constants)? {
+        if let Some(code) = handle_jump_instruction(mnemonic, operands, &ctx)? {
             return Ok(code);
         }
 
-        if let Some(code) = handle_bit_instruction(mnemonic, operands)? {
+        if let Some(code) = handle_call_instruction(mnemonic, operands, &ctx)? {
             return Ok(code);
         }
 
-        if let Some(code) = handle_io_instruction(mnemonic, operands, &self.constants)? {
+        if let Some(code) = handle_arithmetic_instruction(mnemonic, operands, &ctx)? {
             return Ok(code);
         }
 
-        if mnemonic == "reti" {
-            return Ok(vec![0xed, 0x4d]);
+        if let Some(code) = handle_bit_instruction(mnemonic, operands, &ctx)? {
+            return Ok(code);
         }
-        if mnemonic == "retn" {
-            return Ok(vec![0xed, 0x45]);
+
+        if let Some(code) = handle_io_instruction(mnemonic, operands, &ctx)? {
+            return Ok(code);
         }
 
-        let full_inst = if let Some(ops) = operands {
-            format!("{} {}", mnemonic, ops)
-        } else {
+        // Register-only forms are looked up by their canonical spelling
+        let full_inst = if operands.is_empty() {
             mnemonic.to_string()
+        } else {
+            let operands: Vec<String> = operands.iter().map(|op| op.to_string()).collect();
+            format!("{} {}", mnemonic, operands.join(","))
         };
 
         if let Some(&opcode) = OPCODES.get(full_inst.as_str()) {
+            let mut result = Vec::new();
             if opcode > 0xffff {
                 result.push(((opcode >> 16) & 0xff) as u8);
                 result.push(((opcode >> 8) & 0xff) as u8);
@@ -196,86 +831,34 @@ impl Z80Assembler {
             return Ok(result);
         }
 
-        if let Some(&opcode) = OPCODES.get(mnemonic) {
-            if opcode > 0xff {
-                result.push(((opcode >> 8) & 0xff) as u8);
-                result.push((opcode & 0xff) as u8);
-            } else {
-                result.push(opcode as u8);
-            }
-            return Ok(result);
-        }
-
-        Err(anyhow!(
-            "Unknown instruction: {} {}",
-            mnemonic,
-            operands.unwrap_or("")
-        ))
+        Err(anyhow!("Unknown instruction: {}", full_inst))
     }
+}
 
-    fn estimate_instruction_size(&self, mnemonic: &str, operands: Option<&str>) -> usize {
-        match mnemonic {
-            ".org" | ".end" | ".equ" => 0,
-            ".db" => {
-                if let Some(ops) = operands {
-                    crate::directives::estimate_data_size(mnemonic, ops)
-                } else {
-                    0
-                }
-            },
-            ".dw" => {
-                if let Some(ops) = operands {
-                    ops.split(',').count() * 2
-                } else {
-                    0
-                }
-            },
-            "bcall" | "jp" | "call" => 3,
-            "ld" => {
-                if let Some(ops) = operands {
-                    if ops.starts_with("hl,")
-                        || ops.starts_with("bc,")
-                        || ops.starts_with("de,")
-                        || ops.starts_with("sp,")
-                        || ops.contains("),hl")
-                        || ops.contains("),a")
-                    {
-                        3
-                    } else {
-                        let parts = self.parser.split_operands(ops);
-                        if parts.len() == 2 && !parts[1].contains('(') {
-                            if REG_LOAD_IMMEDIATE.contains_key(parts[0].as_str()) {
-                                2
-                            } else {
-                                1
-                            }
-                        } else {
-                            1
-                        }
-                    }
-                } else {
-                    1
-                }
-            },
-            "jr" | "djnz" => 2,
-            "reti" | "retn" => 2,
-            "add" | "adc" | "sub" | "sbc" | "and" | "xor" | "or" | "cp" => {
-                if let Some(ops) = operands {
-                    let parts = self.parser.split_operands(ops);
-                    if parts.len() == 1 || (parts.len() == 2 && parts[0] == "a") {
-                        if !parts.last().unwrap().starts_with('(') {
-                            2
-                        } else {
-                            1
-                        }
-                    } else {
-                        1
-                    }
-                } else {
-                    1
-                }
-            },
-            _ => 1,
-        }
+/// Encode `bcall` or `bjump` of the ROM routine at `address`.
+///
+/// The TI-83 Plus reaches its paged ROM through `rst 28h`, and `bjump`
+/// calls the ROM's jump routine, which never returns. The TI-84 Plus CE
+/// calls and jumps to its OS routines directly.
+fn rom_call(target: Target, mnemonic: &str, address: u32) -> Vec<u8> {
+    let [low, high, upper, _] = address.to_le_bytes();
+    match (target.is_ez80(), mnemonic) {
+        (true, "bjump") => vec![JP_NN, low, high, upper],
+        (true, _) => vec![CALL_NN, low, high, upper],
+        (false, "bjump") => vec![
+            CALL_NN,
+            BJUMP_ADDRESS as u8,
+            (BJUMP_ADDRESS >> 8) as u8,
+            low,
+            high,
+        ],
+        (false, _) => vec![RST_28H, low, high],
     }
 }
+
+/// Error for an eZ80 feature used while assembling for a Z80 calculator.
+fn ez80_only(message: String) -> anyhow::Error {
+    Diagnostic::error(message)
+        .with_help("assemble for the TI-84 Plus CE with --target ti84pce")
+        .into()
+}
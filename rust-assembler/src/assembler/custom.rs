@@ -0,0 +1,151 @@
+use anyhow::{anyhow, Result};
+
+use crate::assembler::lexer::is_ident_char;
+
+/// An instruction added with `.addinstr`, in the table format of TASM.
+///
+/// `.addinstr ld a,(hl+) 7E23 2` makes `ld a,(hl+)` assemble to `$7E,$23`.
+/// A `*` in the arguments stands for an expression whose value follows the
+/// opcode, taking the bytes of the size that the opcode does not.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CustomInstruction {
+    /// Arguments in lowercase without whitespace
+    args: String,
+    opcode: Vec<u8>,
+    /// Bytes of the `*` value, 0 to 2
+    value_size: usize,
+}
+
+impl CustomInstruction {
+    /// Parse `mnemonic args opcode size [rule [class]]`, returning the lowercase mnemonic.
+    ///
+    /// Only the `NOP` rule, which copies the value as it is, is supported.
+    pub fn parse(definition: &str) -> Result<(String, Self)> {
+        let fields: Vec<&str> = definition.split_whitespace().collect();
+        let [mnemonic, args, opcode, size, rest @ ..] = fields.as_slice() else {
+            return Err(anyhow!(
+                ".addinstr requires an instruction, its arguments, an opcode and a size"
+            ));
+        };
+        if !mnemonic.chars().all(is_ident_char) {
+            return Err(anyhow!("Invalid instruction name: {}", mnemonic));
+        }
+        if let Some(rule) = rest
+            .first()
+            .filter(|rule| !rule.eq_ignore_ascii_case("nop"))
+        {
+            return Err(anyhow!(
+                "Unsupported .addinstr rule {}, only NOP is supported",
+                rule
+            ));
+        }
+
+        let args = match *args {
+            "\"\"" => String::new(),
+            args => args.to_lowercase(),
+        };
+        if args.matches('*').count() > 1 {
+            return Err(anyhow!("An added instruction can take only one * value"));
+        }
+        let opcode = parse_opcode(opcode)?;
+        let size: usize = size
+            .parse()
+            .map_err(|_| anyhow!("Invalid instruction size: {}", size))?;
+        let value_size = size
+            .checked_sub(opcode.len())
+            .filter(|&value_size| value_size <= 2)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Instruction size {} does not fit the {}-byte opcode and a value of at most 2 bytes",
+                    size,
+                    opcode.len()
+                )
+            })?;
+        if (value_size > 0) != args.contains('*') {
+            return Err(anyhow!(
+                "The arguments of a {}-byte instruction with a {}-byte opcode must {}contain *",
+                size,
+                opcode.len(),
+                if value_size > 0 { "" } else { "not " }
+            ));
+        }
+
+        let instruction = CustomInstruction {
+            args,
+            opcode,
+            value_size,
+        };
+        Ok((mnemonic.to_lowercase(), instruction))
+    }
+
+    /// The `.db` line for `args` if they match this instruction.
+    pub fn expand(&self, args: &str) -> Option<String> {
+        let args: String = args.chars().filter(|c| !c.is_whitespace()).collect();
+        let mut bytes: Vec<String> = self
+            .opcode
+            .iter()
+            .map(|byte| format!("${:02X}", byte))
+            .collect();
+
+        match self.args.split_once('*') {
+            None if args.to_lowercase() != self.args => return None,
+            None => {},
+            Some((prefix, suffix)) => {
+                let lower = args.to_lowercase();
+                let fits = lower.len() > prefix.len() + suffix.len()
+                    && lower.starts_with(prefix)
+                    && lower.ends_with(suffix);
+                if !fits {
+                    return None;
+                }
+                let value = args.get(prefix.len()..args.len() - suffix.len())?;
+                bytes.push(format!("low({})", value));
+                if self.value_size == 2 {
+                    bytes.push(format!("high({})", value));
+                }
+            },
+        }
+        Some(format!(".db {}", bytes.join(",")))
+    }
+}
+
+/// Opcode bytes written as hex digits, most significant first.
+fn parse_opcode(text: &str) -> Result<Vec<u8>> {
+    let invalid = || anyhow!("Invalid opcode {}, expected pairs of hex digits", text);
+    if text.is_empty()
+        || !text.len().is_multiple_of(2)
+        || !text.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        return Err(invalid());
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|pos| u8::from_str_radix(&text[pos..pos + 2], 16).map_err(|_| invalid()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_custom_instructions() {
+        let (mnemonic, ldi) = CustomInstruction::parse("LDI A,(HL) 7E23 2 NOP 1").unwrap();
+        assert_eq!(mnemonic, "ldi");
+        assert_eq!(ldi.expand("a, (hl)"), Some(".db $7E,$23".to_string()));
+        assert_eq!(ldi.expand(""), None);
+
+        let (_, load) = CustomInstruction::parse("ldw hl,* 21 3").unwrap();
+        assert_eq!(
+            load.expand("HL, Table+2"),
+            Some(".db $21,low(Table+2),high(Table+2)".to_string())
+        );
+        assert_eq!(load.expand("hl,"), None);
+
+        assert!(CustomInstruction::parse("nop2 \"\" 0000 2").is_ok());
+        assert!(CustomInstruction::parse("bad a,* 7E 1").is_err());
+        assert!(CustomInstruction::parse("bad a 7E 2").is_err());
+        assert!(CustomInstruction::parse("bad a 7 1").is_err());
+        assert!(CustomInstruction::parse("bad a 7E 1 R1").is_err());
+    }
+}
@@ -0,0 +1,43 @@
+/// Source conventions of other TI-83 Plus assemblers.
+///
+/// Forms that cannot be misread, such as `b_call(_PutS)`, `0FFh` or
+/// `.addinstr`, are accepted in every dialect. A dialect switches on the
+/// rules that change how a native line would be read. SPASM-ng, TASM and
+/// Brass share those rules, so their dialects only record which assembler
+/// the source was written for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Dialect {
+    /// Labels end with a colon and every line holds one statement
+    #[default]
+    Native,
+    Spasm,
+    Tasm,
+    Brass,
+}
+
+impl Dialect {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "native" | "z80asm" => Some(Dialect::Native),
+            "spasm" | "spasm-ng" => Some(Dialect::Spasm),
+            "tasm" => Some(Dialect::Tasm),
+            "brass" => Some(Dialect::Brass),
+            _ => None,
+        }
+    }
+
+    /// Whether a name written in column one is a label without a colon.
+    pub fn column_one_labels(self) -> bool {
+        self != Dialect::Native
+    }
+
+    /// Whether `\` separates statements written on one line.
+    pub fn backslash_separators(self) -> bool {
+        self != Dialect::Native
+    }
+
+    /// Whether `#define name(args) text` defines a one-line macro.
+    pub fn parameterized_defines(self) -> bool {
+        self != Dialect::Native
+    }
+}
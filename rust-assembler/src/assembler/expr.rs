@@ -0,0 +1,502 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+
+use crate::assembler::lexer::{Text, TextChar, Token};
+use crate::ti83plus::Charset;
+
+/// An operand expression, kept unevaluated until symbols are known.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(i64),
+    Symbol(String),
+    Str(Text),
+    /// `'x'`, or `'AB'` with the first character in the high byte
+    Char(Text),
+    /// `$`, the address of the current instruction
+    CurrentAddress,
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Function(Function, Box<Expr>),
+    /// Anonymous label reference: `-1` for `-`, `-2` for `--`, `1` for `+`, ...
+    Anonymous(i64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    BitNot,
+    LogicalNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Mul,
+    Div,
+    Mod,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    BitAnd,
+    BitXor,
+    BitOr,
+    LogicalAnd,
+    LogicalOr,
+}
+
+/// Built-in functions callable from expressions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Function {
+    Low,
+    High,
+}
+
+impl BinaryOp {
+    fn from_token(token: &Token) -> Option<Self> {
+        let Token::Op(op) = token else {
+            return None;
+        };
+        Some(match *op {
+            "*" => BinaryOp::Mul,
+            "/" => BinaryOp::Div,
+            "%" => BinaryOp::Mod,
+            "+" => BinaryOp::Add,
+            "-" => BinaryOp::Sub,
+            "<<" => BinaryOp::Shl,
+            ">>" => BinaryOp::Shr,
+            "<" => BinaryOp::Lt,
+            "<=" => BinaryOp::Le,
+            ">" => BinaryOp::Gt,
+            ">=" => BinaryOp::Ge,
+            "==" => BinaryOp::Eq,
+            "!=" => BinaryOp::Ne,
+            "&" => BinaryOp::BitAnd,
+            "^" => BinaryOp::BitXor,
+            "|" => BinaryOp::BitOr,
+            "&&" => BinaryOp::LogicalAnd,
+            "||" => BinaryOp::LogicalOr,
+            _ => return None,
+        })
+    }
+
+    /// Binding strength; higher binds tighter (C precedence)
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod => 10,
+            BinaryOp::Add | BinaryOp::Sub => 9,
+            BinaryOp::Shl | BinaryOp::Shr => 8,
+            BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => 7,
+            BinaryOp::Eq | BinaryOp::Ne => 6,
+            BinaryOp::BitAnd => 5,
+            BinaryOp::BitXor => 4,
+            BinaryOp::BitOr => 3,
+            BinaryOp::LogicalAnd => 2,
+            BinaryOp::LogicalOr => 1,
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Mod => "%",
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Shl => "<<",
+            BinaryOp::Shr => ">>",
+            BinaryOp::Lt => "<",
+            BinaryOp::Le => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Ge => ">=",
+            BinaryOp::Eq => "==",
+            BinaryOp::Ne => "!=",
+            BinaryOp::BitAnd => "&",
+            BinaryOp::BitXor => "^",
+            BinaryOp::BitOr => "|",
+            BinaryOp::LogicalAnd => "&&",
+            BinaryOp::LogicalOr => "||",
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Number(value) => write!(f, "{}", value),
+            Expr::Symbol(name) => write!(f, "{}", name),
+            Expr::Str(text) => write!(f, "\"{}\"", text),
+            Expr::Char(text) => write!(f, "'{}'", text),
+            Expr::CurrentAddress => write!(f, "$"),
+            Expr::Unary(op, inner) => {
+                let symbol = match op {
+                    UnaryOp::Neg => "-",
+                    UnaryOp::BitNot => "~",
+                    UnaryOp::LogicalNot => "!",
+                };
+                write!(f, "{}{}", symbol, inner)
+            },
+            Expr::Binary(op, left, right) => {
+                write_operand(f, left)?;
+                write!(f, "{}", op.symbol())?;
+                write_operand(f, right)
+            },
+            Expr::Function(function, arg) => {
+                let name = match function {
+                    Function::Low => "low",
+                    Function::High => "high",
+                };
+                write!(f, "{}({})", name, arg)
+            },
+            Expr::Anonymous(offset) => {
+                let symbol = if *offset < 0 { "-" } else { "+" };
+                write!(f, "{}", symbol.repeat(offset.unsigned_abs() as usize))
+            },
+        }
+    }
+}
+
+fn write_operand(f: &mut fmt::Formatter<'_>, expr: &Expr) -> fmt::Result {
+    match expr {
+        Expr::Binary(..) => write!(f, "({})", expr),
+        _ => write!(f, "{}", expr),
+    }
+}
+
+/// Supplies symbol values to the expression evaluator.
+pub trait SymbolResolver {
+    /// Value of a named symbol
+    fn resolve(&self, name: &str) -> Result<i64>;
+
+    /// Value of `$`
+    fn current_address(&self) -> Result<i64>;
+
+    /// Address of the anonymous label `offset` labels away from the current line
+    fn resolve_anonymous(&self, offset: i64) -> Result<i64> {
+        Err(anyhow!(
+            "Anonymous label reference {} is not allowed here",
+            Expr::Anonymous(offset)
+        ))
+    }
+
+    /// Whether values are final, so errors that placeholders can cause are real
+    fn is_final(&self) -> bool {
+        true
+    }
+
+    /// Code of a character in a character constant or one-character string
+    fn character(&self, ch: char) -> Result<i64> {
+        Charset::Ascii.encode_char(ch).map(i64::from)
+    }
+}
+
+/// Code of one character of a literal; `\xNN` escapes are already bytes.
+fn character(ch: TextChar, resolver: &dyn SymbolResolver) -> Result<i64> {
+    match ch {
+        TextChar::Char(ch) => resolver.character(ch),
+        TextChar::Byte(byte) => Ok(i64::from(byte)),
+    }
+}
+
+impl Expr {
+    /// Evaluate the expression with 64-bit signed arithmetic.
+    pub fn evaluate(&self, resolver: &dyn SymbolResolver) -> Result<i64> {
+        match self {
+            Expr::Number(value) => Ok(*value),
+            Expr::Symbol(name) => resolver.resolve(name),
+            Expr::Str(text) => match text.0.as_slice() {
+                [ch] => character(*ch, resolver),
+                _ => Err(anyhow!(
+                    "String \"{}\" used where a number is expected",
+                    text
+                )),
+            },
+            Expr::Char(text) => text
+                .0
+                .iter()
+                .try_fold(0, |value, ch| Ok(value << 8 | character(*ch, resolver)?)),
+            Expr::CurrentAddress => resolver.current_address(),
+            Expr::Anonymous(offset) => resolver.resolve_anonymous(*offset),
+            Expr::Unary(op, inner) => {
+                let value = inner.evaluate(resolver)?;
+                Ok(match op {
+                    UnaryOp::Neg => value.wrapping_neg(),
+                    UnaryOp::BitNot => !value,
+                    UnaryOp::LogicalNot => i64::from(value == 0),
+                })
+            },
+            Expr::Binary(op, left, right) => {
+                let left = left.evaluate(resolver)?;
+                // Short-circuit so the untaken side may reference anything
+                match op {
+                    BinaryOp::LogicalAnd if left == 0 => return Ok(0),
+                    BinaryOp::LogicalOr if left != 0 => return Ok(1),
+                    _ => {},
+                }
+                let right = right.evaluate(resolver)?;
+                apply_binary(*op, left, right, resolver.is_final())
+            },
+            Expr::Function(function, arg) => {
+                let value = arg.evaluate(resolver)?;
+                Ok(match function {
+                    Function::Low => value & 0xff,
+                    Function::High => (value >> 8) & 0xff,
+                })
+            },
+        }
+    }
+
+    /// Names of all symbols the expression refers to, in order of appearance.
+    pub fn symbols(&self) -> Vec<&str> {
+        let mut names = Vec::new();
+        self.collect_symbols(&mut names);
+        names
+    }
+
+    /// Whether the value is the same wherever it appears: no `$`, anonymous
+    /// labels or characters, whose codes depend on the charset.
+    pub fn is_constant(&self) -> bool {
+        match self {
+            Expr::Number(_) | Expr::Symbol(_) => true,
+            Expr::Unary(_, inner) | Expr::Function(_, inner) => inner.is_constant(),
+            Expr::Binary(_, left, right) => left.is_constant() && right.is_constant(),
+            Expr::Str(_) | Expr::Char(_) | Expr::CurrentAddress | Expr::Anonymous(_) => false,
+        }
+    }
+
+    fn collect_symbols<'a>(&'a self, names: &mut Vec<&'a str>) {
+        match self {
+            Expr::Symbol(name) => names.push(name),
+            Expr::Unary(_, inner) | Expr::Function(_, inner) => inner.collect_symbols(names),
+            Expr::Binary(_, left, right) => {
+                left.collect_symbols(names);
+                right.collect_symbols(names);
+            },
+            Expr::Number(_)
+            | Expr::Str(_)
+            | Expr::Char(_)
+            | Expr::CurrentAddress
+            | Expr::Anonymous(_) => {},
+        }
+    }
+}
+
+fn apply_binary(op: BinaryOp, left: i64, right: i64, is_final: bool) -> Result<i64> {
+    Ok(match op {
+        BinaryOp::Mul => left.wrapping_mul(right),
+        BinaryOp::Div | BinaryOp::Mod if right == 0 => {
+            // Placeholder values may legitimately divide by zero
+            if is_final {
+                return Err(anyhow!("Division by zero"));
+            }
+            0
+        },
+        BinaryOp::Div => left.wrapping_div(right),
+        BinaryOp::Mod => left.wrapping_rem(right),
+        BinaryOp::Add => left.wrapping_add(right),
+        BinaryOp::Sub => left.wrapping_sub(right),
+        BinaryOp::Shl => u32::try_from(right)
+            .ok()
+            .and_then(|shift| left.checked_shl(shift))
+            .unwrap_or(0),
+        BinaryOp::Shr => u32::try_from(right)
+            .ok()
+            .and_then(|shift| left.checked_shr(shift))
+            .unwrap_or(if left < 0 { -1 } else { 0 }),
+        BinaryOp::Lt => i64::from(left < right),
+        BinaryOp::Le => i64::from(left <= right),
+        BinaryOp::Gt => i64::from(left > right),
+        BinaryOp::Ge => i64::from(left >= right),
+        BinaryOp::Eq => i64::from(left == right),
+        BinaryOp::Ne => i64::from(left != right),
+        BinaryOp::BitAnd => left & right,
+        BinaryOp::BitXor => left ^ right,
+        BinaryOp::BitOr => left | right,
+        BinaryOp::LogicalAnd => i64::from(left != 0 && right != 0),
+        BinaryOp::LogicalOr => i64::from(left != 0 || right != 0),
+    })
+}
+
+/// Parse a complete expression from a token slice.
+pub fn parse_expr(tokens: &[Token]) -> Result<Expr> {
+    if let Some(offset) = anonymous_reference(tokens) {
+        return Ok(Expr::Anonymous(offset));
+    }
+    let mut parser = ExprParser { tokens, pos: 0 };
+    let expr = parser.binary(0)?;
+    if let Some(token) = tokens.get(parser.pos) {
+        return Err(anyhow!("Unexpected '{}' in expression", token));
+    }
+    Ok(expr)
+}
+
+/// An operand made only of `+` or only of `-` signs refers to an anonymous label.
+fn anonymous_reference(tokens: &[Token]) -> Option<i64> {
+    let sign = match tokens.first()? {
+        Token::Op("+") => 1,
+        Token::Op("-") => -1,
+        _ => return None,
+    };
+    tokens
+        .iter()
+        .all(|token| *token == tokens[0])
+        .then_some(sign * tokens.len() as i64)
+}
+
+struct ExprParser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl ExprParser<'_> {
+    /// Precedence climbing over binary operators binding tighter than `min`.
+    fn binary(&mut self, min: u8) -> Result<Expr> {
+        let mut left = self.unary()?;
+        while let Some(op) = self.tokens.get(self.pos).and_then(BinaryOp::from_token) {
+            if op.precedence() <= min {
+                break;
+            }
+            self.pos += 1;
+            let right = self.binary(op.precedence())?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        let op = match self.tokens.get(self.pos) {
+            Some(Token::Op("-")) => UnaryOp::Neg,
+            Some(Token::Op("~")) => UnaryOp::BitNot,
+            Some(Token::Op("!")) => UnaryOp::LogicalNot,
+            Some(Token::Op("+")) => {
+                self.pos += 1;
+                return self.unary();
+            },
+            _ => return self.primary(),
+        };
+        self.pos += 1;
+        Ok(Expr::Unary(op, Box::new(self.unary()?)))
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .ok_or_else(|| anyhow!("Expected a value"))?;
+        self.pos += 1;
+        match token {
+            Token::Number(value) => Ok(Expr::Number(*value)),
+            Token::Char(text) => Ok(Expr::Char(text.clone())),
+            Token::Str(text) => Ok(Expr::Str(text.clone())),
+            Token::Dollar => Ok(Expr::CurrentAddress),
+            Token::Ident(name) => {
+                let function = match name.to_lowercase().as_str() {
+                    "low" => Some(Function::Low),
+                    "high" => Some(Function::High),
+                    _ => None,
+                };
+                match function {
+                    Some(function) if self.tokens.get(self.pos) == Some(&Token::LParen) => {
+                        self.pos += 1;
+                        let arg = self.parenthesized()?;
+                        Ok(Expr::Function(function, Box::new(arg)))
+                    },
+                    _ => Ok(Expr::Symbol(name.clone())),
+                }
+            },
+            Token::LParen => self.parenthesized(),
+            other => Err(anyhow!("Unexpected '{}' in expression", other)),
+        }
+    }
+
+    /// The rest of a parenthesized expression whose `(` was consumed.
+    fn parenthesized(&mut self) -> Result<Expr> {
+        let inner = self.binary(0)?;
+        match self.tokens.get(self.pos) {
+            Some(Token::RParen) => {
+                self.pos += 1;
+                Ok(inner)
+            },
+            _ => Err(anyhow!("Missing closing parenthesis")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::lexer::tokenize;
+    use std::collections::HashMap;
+
+    struct Symbols(HashMap<&'static str, i64>);
+
+    impl SymbolResolver for Symbols {
+        fn resolve(&self, name: &str) -> Result<i64> {
+            self.0
+                .get(name)
+                .copied()
+                .ok_or_else(|| anyhow!("Undefined symbol: {}", name))
+        }
+
+        fn current_address(&self) -> Result<i64> {
+            Ok(0x9D95)
+        }
+    }
+
+    fn eval(text: &str) -> Result<i64> {
+        let symbols = Symbols(HashMap::from([("start", 0x9D95), ("end", 0x9DA5)]));
+        parse_expr(&tokenize(text)?)?.evaluate(&symbols)
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(eval("2+3*4").unwrap(), 14);
+        assert_eq!(eval("(2+3)*4").unwrap(), 20);
+        assert_eq!(eval("1<<3|1").unwrap(), 9);
+        assert_eq!(eval("10-4-3").unwrap(), 3);
+        assert_eq!(eval("-2*3").unwrap(), -6);
+        assert_eq!(eval("~0 & $FF").unwrap(), 0xFF);
+        assert_eq!(eval("17 % 5 ^ 3").unwrap(), 1);
+    }
+
+    #[test]
+    fn test_symbols_and_current_address() {
+        assert_eq!(eval("(end-start)/2").unwrap(), 8);
+        assert_eq!(eval("start+1").unwrap(), 0x9D96);
+        assert_eq!(eval("$+3").unwrap(), 0x9D98);
+        assert!(eval("missing+1").is_err());
+    }
+
+    #[test]
+    fn test_comparisons_and_logic() {
+        assert_eq!(eval("end > start").unwrap(), 1);
+        assert_eq!(eval("1 == 2 || 3 != 4").unwrap(), 1);
+        assert_eq!(eval("!(1 <= 0) && 0").unwrap(), 0);
+        assert_eq!(eval("0 && missing").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_functions() {
+        assert_eq!(eval("low($1234)").unwrap(), 0x34);
+        assert_eq!(eval("HIGH(end)").unwrap(), 0x9D);
+        assert!(eval("1/0").is_err());
+    }
+
+    #[test]
+    fn test_anonymous_references() {
+        let parse = |text| parse_expr(&tokenize(text).unwrap()).unwrap();
+        assert_eq!(parse("--"), Expr::Anonymous(-2));
+        assert_eq!(parse("+"), Expr::Anonymous(1));
+        assert_eq!(parse("--").to_string(), "--");
+        assert_eq!(
+            parse("-1"),
+            Expr::Unary(UnaryOp::Neg, Box::new(Expr::Number(1)))
+        );
+        assert!(eval("+").is_err());
+    }
+}
@@ -0,0 +1,245 @@
+use anyhow::{anyhow, Result};
+
+use crate::assembler::lexer::{is_ident_char, is_ident_start};
+use crate::assembler::parser::{split_label, strip_comment};
+use crate::diagnostics::{Diagnostic, Span};
+
+/// Directives that lay out constants rather than code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayoutKind {
+    /// `.struct Name` with one field per line
+    Struct,
+    /// `.enum [Name]` with auto-incremented members
+    Enum,
+}
+
+impl LayoutKind {
+    pub fn from_word(word: &str) -> Option<Self> {
+        match word {
+            ".struct" => Some(LayoutKind::Struct),
+            ".enum" => Some(LayoutKind::Enum),
+            _ => None,
+        }
+    }
+
+    pub fn is_end(self, word: &str) -> bool {
+        match self {
+            LayoutKind::Struct => matches!(word, ".endstruct" | ".ends"),
+            LayoutKind::Enum => matches!(word, ".endenum" | ".ende"),
+        }
+    }
+
+    pub fn directive(self) -> &'static str {
+        match self {
+            LayoutKind::Struct => ".struct",
+            LayoutKind::Enum => ".enum",
+        }
+    }
+
+    pub fn end_word(self) -> &'static str {
+        match self {
+            LayoutKind::Struct => ".endstruct",
+            LayoutKind::Enum => ".endenum",
+        }
+    }
+}
+
+/// A `.struct` or `.enum` block being read.
+pub struct Layout {
+    pub kind: LayoutKind,
+    pub name: Option<String>,
+    pub opened_at: Span,
+    /// Offset of the next struct field, or value of the next enum member
+    next: i64,
+}
+
+impl Layout {
+    pub fn open(kind: LayoutKind, argument: &str, span: &Span) -> Result<Self> {
+        let name = argument.trim();
+        let name = if name.is_empty() {
+            None
+        } else if is_name(name) {
+            Some(name.to_string())
+        } else {
+            return Err(anyhow!("Invalid {} name: {}", kind.directive(), name));
+        };
+        if kind == LayoutKind::Struct && name.is_none() {
+            return Err(anyhow!("Expected a name after .struct"));
+        }
+        Ok(Layout {
+            kind,
+            name,
+            opened_at: span.clone(),
+            next: 0,
+        })
+    }
+
+    /// The constants defined by one line of the body.
+    ///
+    /// `evaluate` computes counts and values, which must be known before assembly.
+    pub fn members(
+        &mut self,
+        text: &str,
+        evaluate: impl Fn(&str) -> Result<i64>,
+    ) -> Result<Vec<(String, i64)>> {
+        let line = strip_comment(text).trim();
+        if line.is_empty() {
+            return Ok(Vec::new());
+        }
+        match self.kind {
+            LayoutKind::Struct => self.field(line, evaluate).map(|field| vec![field]),
+            LayoutKind::Enum => line
+                .split(',')
+                .map(|member| self.enum_member(member.trim(), &evaluate))
+                .collect(),
+        }
+    }
+
+    /// The constants defined by the closing line: the size of a struct.
+    pub fn close(self) -> Vec<(String, i64)> {
+        match (self.kind, self.name) {
+            (LayoutKind::Struct, Some(name)) => vec![(format!("{}.size", name), self.next)],
+            _ => Vec::new(),
+        }
+    }
+
+    /// `name[:] type [count]`, where the type is a size or another struct.
+    fn field(
+        &mut self,
+        line: &str,
+        evaluate: impl Fn(&str) -> Result<i64>,
+    ) -> Result<(String, i64)> {
+        let (name, rest) = match split_label(line) {
+            (Some(label), rest) => (label, rest),
+            (None, line) => match line.split_once(char::is_whitespace) {
+                Some((name, rest)) => (name.to_string(), rest.trim()),
+                None => (line.to_string(), ""),
+            },
+        };
+        if !is_name(&name) {
+            return Err(anyhow!("Invalid field name: {}", name));
+        }
+
+        let (field_type, count) = match rest.split_once(char::is_whitespace) {
+            Some((field_type, count)) => (field_type, Some(count.trim())),
+            None => (rest, None),
+        };
+        let count = match count {
+            Some(count) => evaluate(count)?,
+            None => 1,
+        };
+        let size = match field_type.trim_start_matches('.').to_lowercase().as_str() {
+            "" => return Err(anyhow!("Expected a type after field {}", name)),
+            "byte" | "db" => 1,
+            "word" | "dw" => 2,
+            "long" | "dl" => 3,
+            "dword" | "dd" => 4,
+            // The count of .ds is the size in bytes
+            "ds" | "block" => 1,
+            _ => evaluate(&format!("{}.size", field_type)).map_err(|_| {
+                Diagnostic::error(format!("Unknown field type {}", field_type))
+                    .with_help("use .byte, .word, .long, .dword, .ds n or a struct defined above")
+            })?,
+        };
+        if count < 0 {
+            return Err(anyhow!("Field {} cannot have a negative count", name));
+        }
+
+        let offset = self.next;
+        self.next += size * count;
+        let struct_name = self.name.as_deref().unwrap_or_default();
+        Ok((format!("{}.{}", struct_name, name), offset))
+    }
+
+    /// `NAME` or `NAME = value`; later members count up from the last value.
+    fn enum_member(
+        &mut self,
+        member: &str,
+        evaluate: impl Fn(&str) -> Result<i64>,
+    ) -> Result<(String, i64)> {
+        let (name, value) = match member.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(evaluate(value.trim())?)),
+            None => (member, None),
+        };
+        if !is_name(name) {
+            return Err(anyhow!("Invalid enum member: {}", name));
+        }
+        let value = value.unwrap_or(self.next);
+        self.next = value + 1;
+        let name = match &self.name {
+            Some(prefix) => format!("{}.{}", prefix, name),
+            None => name.to_string(),
+        };
+        Ok((name, value))
+    }
+}
+
+fn is_name(name: &str) -> bool {
+    name.starts_with(is_ident_start) && name.chars().all(is_ident_char)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span() -> Span {
+        Span {
+            file: "test.asm".to_string(),
+            line: 1,
+            columns: 0..7,
+            source_line: ".struct".to_string(),
+        }
+    }
+
+    fn number(text: &str) -> Result<i64> {
+        match text {
+            "Point.size" => Ok(2),
+            _ => text.parse().map_err(|_| anyhow!("not a number")),
+        }
+    }
+
+    fn layout_error(line: &str) -> String {
+        let mut layout = Layout::open(LayoutKind::Struct, "Entity", &span()).unwrap();
+        layout.members(line, number).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_struct_fields() {
+        let mut layout = Layout::open(LayoutKind::Struct, "Entity", &span()).unwrap();
+        let mut fields = Vec::new();
+        for line in [
+            "x .byte",
+            "hp: .word",
+            "name .byte 8",
+            "pos Point 2",
+            "; note",
+        ] {
+            fields.extend(layout.members(line, number).unwrap());
+        }
+        fields.extend(layout.close());
+        let expected = [
+            ("Entity.x", 0),
+            ("Entity.hp", 1),
+            ("Entity.name", 3),
+            ("Entity.pos", 11),
+            ("Entity.size", 15),
+        ];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(name, value)| (name.to_string(), *value))
+            .collect();
+        assert_eq!(fields, expected);
+        assert!(layout_error("flag .bool").contains("Unknown field type"));
+    }
+
+    #[test]
+    fn test_enum_members() {
+        let mut layout = Layout::open(LayoutKind::Enum, "", &span()).unwrap();
+        let mut members = layout.members("IDLE, RUNNING", number).unwrap();
+        members.extend(layout.members("DONE = 10", number).unwrap());
+        members.extend(layout.members("NEXT", number).unwrap());
+        let values: Vec<i64> = members.iter().map(|(_, value)| *value).collect();
+        assert_eq!(values, vec![0, 1, 10, 11]);
+        assert_eq!(members[3].0, "NEXT");
+    }
+}
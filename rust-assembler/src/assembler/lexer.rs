@@ -0,0 +1,471 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+
+use crate::ti83plus::Charset;
+
+/// A single lexical token of an operand list.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    /// Register names, condition codes and symbols, with their original case
+    Ident(String),
+    Number(i64),
+    Str(Text),
+    /// A character constant of one or two characters, encoded once the charset is known
+    Char(Text),
+    Comma,
+    LParen,
+    RParen,
+    /// `$` on its own, the current address
+    Dollar,
+    Op(&'static str),
+}
+
+/// Operators, longest first so `<<` is not read as two `<`
+const OPERATORS: [&str; 20] = [
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+", "-", "*", "/", "%", "&", "|", "^", "~",
+    "!", "<", ">",
+];
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Ident(name) => write!(f, "{}", name),
+            Token::Number(value) => write!(f, "{}", value),
+            Token::Str(text) => write!(f, "\"{}\"", text),
+            Token::Char(text) => write!(f, "'{}'", text),
+            Token::Comma => write!(f, ","),
+            Token::LParen => write!(f, "("),
+            Token::RParen => write!(f, ")"),
+            Token::Dollar => write!(f, "$"),
+            Token::Op(op) => write!(f, "{}", op),
+        }
+    }
+}
+
+/// A character of a string or character literal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextChar {
+    /// A character as written, encoded with the selected charset
+    Char(char),
+    /// A `\xNN` escape, which is that byte under every charset
+    Byte(u8),
+}
+
+/// The contents of a string or character literal.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Text(pub Vec<TextChar>);
+
+impl Text {
+    /// The literal as plain text, for file names and messages.
+    pub fn to_plain(&self) -> String {
+        self.0
+            .iter()
+            .map(|ch| match *ch {
+                TextChar::Char(ch) => ch,
+                TextChar::Byte(byte) => char::from(byte),
+            })
+            .collect()
+    }
+
+    /// Encode the literal, copying escaped bytes unchanged.
+    pub fn encode(&self, charset: Charset) -> Result<Vec<u8>> {
+        self.0
+            .iter()
+            .map(|ch| match *ch {
+                TextChar::Char(ch) => charset.encode_char(ch),
+                TextChar::Byte(byte) => Ok(byte),
+            })
+            .collect()
+    }
+}
+
+impl From<&str> for Text {
+    fn from(text: &str) -> Self {
+        Text(text.chars().map(TextChar::Char).collect())
+    }
+}
+
+impl fmt::Display for Text {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for ch in &self.0 {
+            match ch {
+                TextChar::Char(ch) => write!(f, "{}", ch.escape_default())?,
+                TextChar::Byte(byte) => write!(f, "\\x{:02X}", byte)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Split operand text into tokens, ignoring whitespace.
+pub fn tokenize(input: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+
+    while pos < chars.len() {
+        let ch = chars[pos];
+        match ch {
+            c if c.is_whitespace() => pos += 1,
+            ',' => {
+                tokens.push(Token::Comma);
+                pos += 1;
+            },
+            '(' => {
+                tokens.push(Token::LParen);
+                pos += 1;
+            },
+            ')' => {
+                tokens.push(Token::RParen);
+                pos += 1;
+            },
+            '"' => {
+                let (text, next) = lex_string(&chars, pos + 1)?;
+                tokens.push(Token::Str(text));
+                pos = next;
+            },
+            '\'' => {
+                let (token, next) = lex_char_literal(&chars, pos + 1)?;
+                tokens.push(token);
+                pos = next;
+            },
+            '$' if pos + 1 < chars.len() && chars[pos + 1].is_ascii_hexdigit() => {
+                let (value, next) = lex_digits(&chars, pos + 1, 16)?;
+                tokens.push(Token::Number(value));
+                pos = next;
+            },
+            '$' => {
+                tokens.push(Token::Dollar);
+                pos += 1;
+            },
+            // After a value `%` is the modulo operator
+            '%' if pos + 1 < chars.len()
+                && matches!(chars[pos + 1], '0' | '1')
+                && !tokens.last().is_some_and(ends_value) =>
+            {
+                let (value, next) = lex_digits(&chars, pos + 1, 2)?;
+                tokens.push(Token::Number(value));
+                pos = next;
+            },
+            c if c.is_ascii_digit() => {
+                let (value, next) = lex_number(&chars, pos)?;
+                tokens.push(Token::Number(value));
+                pos = next;
+            },
+            c if is_ident_start(c) => {
+                let start = pos;
+                while pos < chars.len() && is_ident_char(chars[pos]) {
+                    pos += 1;
+                }
+                let mut name: String = chars[start..pos].iter().collect();
+                // The shadow register pair is written af'
+                if name.eq_ignore_ascii_case("af") && pos < chars.len() && chars[pos] == '\'' {
+                    name.push('\'');
+                    pos += 1;
+                }
+                tokens.push(Token::Ident(name));
+            },
+            _ => {
+                let rest: String = chars[pos..].iter().take(2).collect();
+                let op = *OPERATORS
+                    .iter()
+                    .find(|op| rest.starts_with(**op))
+                    .ok_or_else(|| anyhow!("Unexpected character '{}' in: {}", ch, input))?;
+                tokens.push(Token::Op(op));
+                pos += op.len();
+            },
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Whether a token can end an operand, making a following `%` an operator.
+fn ends_value(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::Ident(_) | Token::Number(_) | Token::Char(_) | Token::RParen | Token::Dollar
+    )
+}
+
+pub fn is_ident_start(ch: char) -> bool {
+    ch.is_ascii_alphabetic() || ch == '_' || ch == '.'
+}
+
+pub fn is_ident_char(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || ch == '_' || ch == '.'
+}
+
+/// End of the string or character literal starting at `pos`, just after its closing quote.
+///
+/// A `'` right after a name is not a quote, so the `af'` register is not
+/// mistaken for one. An unterminated literal runs to the end of the line.
+pub fn literal_end(chars: &[char], pos: usize) -> Option<usize> {
+    let quote = chars[pos];
+    let opens = match quote {
+        '"' => true,
+        '\'' => pos == 0 || !is_ident_char(chars[pos - 1]),
+        _ => false,
+    };
+    if !opens {
+        return None;
+    }
+    let mut end = pos + 1;
+    while end < chars.len() {
+        match chars[end] {
+            '\\' => end += 2,
+            c if c == quote => return Some(end + 1),
+            _ => end += 1,
+        }
+    }
+    Some(chars.len())
+}
+
+/// Byte offset of the first `target` outside string and character literals.
+pub fn find_unquoted(text: &str, target: char) -> Option<usize> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut pos = 0;
+    let mut offset = 0;
+    while pos < chars.len() {
+        let next = literal_end(&chars, pos).unwrap_or(pos + 1);
+        if chars[pos] == target {
+            return Some(offset);
+        }
+        offset += chars[pos..next.min(chars.len())]
+            .iter()
+            .map(|c| c.len_utf8())
+            .sum::<usize>();
+        pos = next;
+    }
+    None
+}
+
+fn lex_string(chars: &[char], mut pos: usize) -> Result<(Text, usize)> {
+    let mut text = Text::default();
+    while pos < chars.len() {
+        match chars[pos] {
+            '"' => return Ok((text, pos + 1)),
+            '\\' => {
+                let (ch, next) = lex_escape(chars, pos + 1)?;
+                text.0.push(ch);
+                pos = next;
+            },
+            ch => {
+                text.0.push(TextChar::Char(ch));
+                pos += 1;
+            },
+        }
+    }
+    Err(anyhow!("Unterminated string literal"))
+}
+
+/// A `'x'` character literal, or a `'AB'` constant with the first character
+/// in the high byte.
+fn lex_char_literal(chars: &[char], mut pos: usize) -> Result<(Token, usize)> {
+    let mut value = Vec::new();
+    loop {
+        match chars.get(pos) {
+            None => return Err(anyhow!("Unterminated character literal")),
+            Some('\'') => break,
+            Some('\\') => {
+                let (ch, next) = lex_escape(chars, pos + 1)?;
+                value.push(ch);
+                pos = next;
+            },
+            Some(&ch) => {
+                value.push(TextChar::Char(ch));
+                pos += 1;
+            },
+        }
+    }
+    let token = match value.as_slice() {
+        [] => return Err(anyhow!("Empty character literal")),
+        [_] | [_, _] => Token::Char(Text(value)),
+        _ => {
+            return Err(anyhow!(
+                "Character constant '{}' is longer than 2 characters",
+                Text(value)
+            ));
+        },
+    };
+    Ok((token, pos + 1))
+}
+
+/// The character for the escape sequence after a backslash at `pos - 1`.
+fn lex_escape(chars: &[char], pos: usize) -> Result<(TextChar, usize)> {
+    let ch = match chars.get(pos) {
+        Some('n') => '\n',
+        Some('r') => '\r',
+        Some('t') => '\t',
+        Some('0') => '\0',
+        Some(&ch @ ('\\' | '"' | '\'')) => ch,
+        Some('x') => {
+            let digits: String = chars[pos + 1..].iter().take(2).collect();
+            let value = u8::from_str_radix(&digits, 16)
+                .ok()
+                .filter(|_| digits.len() == 2)
+                .ok_or_else(|| {
+                    anyhow!("Expected two hex digits after \\x, found \"{}\"", digits)
+                })?;
+            return Ok((TextChar::Byte(value), pos + 3));
+        },
+        Some(other) => return Err(anyhow!("Unknown escape sequence \\{}", other)),
+        None => return Err(anyhow!("Unterminated string literal")),
+    };
+    Ok((TextChar::Char(ch), pos + 1))
+}
+
+/// A number with a `0x`/`0b` prefix, an `h`/`b` suffix as in `0FFh` and
+/// `101b`, or in decimal.
+fn lex_number(chars: &[char], pos: usize) -> Result<(i64, usize)> {
+    let mut end = pos;
+    while end < chars.len() && is_ident_char(chars[end]) {
+        end += 1;
+    }
+    let word = &chars[pos..end];
+    let digits = &word[..word.len() - 1];
+    match word[word.len() - 1] {
+        'h' | 'H' => return parse_digits(digits, 16).map(|value| (value, end)),
+        'b' | 'B' if !digits.is_empty() && digits.iter().all(|c| matches!(c, '0' | '1')) => {
+            return parse_digits(digits, 2).map(|value| (value, end))
+        },
+        _ => {},
+    }
+    if chars[pos] == '0' && pos + 1 < chars.len() {
+        match chars[pos + 1] {
+            'x' | 'X' => return lex_digits(chars, pos + 2, 16),
+            'b' | 'B' if pos + 2 < chars.len() && chars[pos + 2].is_digit(2) => {
+                return lex_digits(chars, pos + 2, 2)
+            },
+            _ => {},
+        }
+    }
+    lex_digits(chars, pos, 10)
+}
+
+fn lex_digits(chars: &[char], start: usize, radix: u32) -> Result<(i64, usize)> {
+    let mut pos = start;
+    while pos < chars.len() && is_ident_char(chars[pos]) {
+        pos += 1;
+    }
+    parse_digits(&chars[start..pos], radix).map(|value| (value, pos))
+}
+
+fn parse_digits(digits: &[char], radix: u32) -> Result<i64> {
+    let digits: String = digits.iter().collect();
+    i64::from_str_radix(&digits, radix).map_err(|e| anyhow!("Invalid number {}: {}", digits, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize_ignores_whitespace() {
+        assert_eq!(
+            tokenize("a, ( hl )").unwrap(),
+            vec![
+                Token::Ident("a".to_string()),
+                Token::Comma,
+                Token::LParen,
+                Token::Ident("hl".to_string()),
+                Token::RParen,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_numbers() {
+        assert_eq!(
+            tokenize("$FF,0x10,%101,0b11,42").unwrap(),
+            vec![
+                Token::Number(0xFF),
+                Token::Comma,
+                Token::Number(0x10),
+                Token::Comma,
+                Token::Number(5),
+                Token::Comma,
+                Token::Number(3),
+                Token::Comma,
+                Token::Number(42),
+            ]
+        );
+        assert!(tokenize("$9G").is_err());
+        assert_eq!(
+            tokenize("0FFh,1bH,101b,0b").unwrap(),
+            vec![
+                Token::Number(0xFF),
+                Token::Comma,
+                Token::Number(0x1B),
+                Token::Comma,
+                Token::Number(5),
+                Token::Comma,
+                Token::Number(0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_operators() {
+        assert_eq!(
+            tokenize("$+1<<2").unwrap(),
+            vec![
+                Token::Dollar,
+                Token::Op("+"),
+                Token::Number(1),
+                Token::Op("<<"),
+                Token::Number(2),
+            ]
+        );
+        assert_eq!(
+            tokenize("x%10").unwrap(),
+            vec![
+                Token::Ident("x".to_string()),
+                Token::Op("%"),
+                Token::Number(10),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_strings_and_shadow_registers() {
+        assert_eq!(
+            tokenize("\"a,b\",'x'").unwrap(),
+            vec![
+                Token::Str(Text::from("a,b")),
+                Token::Comma,
+                Token::Char(Text::from("x"))
+            ]
+        );
+        assert_eq!(
+            tokenize(r#""say \"hi\"\\\0\x41",'\'','\n'"#).unwrap(),
+            vec![
+                Token::Str(Text(
+                    "say \"hi\"\\\0"
+                        .chars()
+                        .map(TextChar::Char)
+                        .chain([TextChar::Byte(0x41)])
+                        .collect()
+                )),
+                Token::Comma,
+                Token::Char(Text::from("'")),
+                Token::Comma,
+                Token::Char(Text::from("\n")),
+            ]
+        );
+        assert_eq!(
+            tokenize("'AB'").unwrap(),
+            vec![Token::Char(Text::from("AB"))]
+        );
+        assert!(tokenize("'ABC'").is_err());
+        assert!(tokenize("\"\\q\"").is_err());
+        assert_eq!(
+            tokenize("af,af'").unwrap(),
+            vec![
+                Token::Ident("af".to_string()),
+                Token::Comma,
+                Token::Ident("af'".to_string()),
+            ]
+        );
+    }
+}
@@ -1,5 +1,25 @@
+pub mod conditional;
+pub mod context;
 pub mod core;
+pub mod custom;
+pub mod dialect;
+pub mod expr;
+pub mod layout;
+pub mod lexer;
+pub mod operand;
 pub mod parser;
+pub mod preprocessor;
+pub mod repeat;
+pub mod result;
+pub mod symbols;
+pub mod target;
 
+pub use context::{Context, Pass};
 pub use core::Z80Assembler;
+pub use dialect::Dialect;
+pub use expr::Expr;
+pub use operand::{Condition, IndexReg, Operand, Reg16, Reg8, SpecialReg};
 pub use parser::{ParsedLine, Parser};
+pub use result::{AssemblyResult, LineRecord};
+pub use symbols::{Symbol, SymbolKind, SymbolTable};
+pub use target::Target;
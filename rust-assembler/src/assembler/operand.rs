@@ -0,0 +1,385 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+
+use crate::assembler::expr::{parse_expr, Expr, UnaryOp};
+use crate::assembler::lexer::Token;
+use crate::constants::{IX_PREFIX, IY_PREFIX};
+
+/// 8-bit registers that can appear in the `r` field of an opcode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reg8 {
+    A,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+}
+
+impl Reg8 {
+    /// Register code used in the 3-bit `r` field (6 is `(hl)`)
+    pub fn code(self) -> u8 {
+        match self {
+            Reg8::B => 0,
+            Reg8::C => 1,
+            Reg8::D => 2,
+            Reg8::E => 3,
+            Reg8::H => 4,
+            Reg8::L => 5,
+            Reg8::A => 7,
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "a" => Some(Reg8::A),
+            "b" => Some(Reg8::B),
+            "c" => Some(Reg8::C),
+            "d" => Some(Reg8::D),
+            "e" => Some(Reg8::E),
+            "h" => Some(Reg8::H),
+            "l" => Some(Reg8::L),
+            _ => None,
+        }
+    }
+}
+
+/// 16-bit register pairs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reg16 {
+    AF,
+    AFShadow,
+    BC,
+    DE,
+    HL,
+    SP,
+    IX,
+    IY,
+}
+
+impl Reg16 {
+    /// The index register this pair names, if any
+    pub fn index(self) -> Option<IndexReg> {
+        match self {
+            Reg16::IX => Some(IndexReg::IX),
+            Reg16::IY => Some(IndexReg::IY),
+            _ => None,
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "af" => Some(Reg16::AF),
+            "af'" => Some(Reg16::AFShadow),
+            "bc" => Some(Reg16::BC),
+            "de" => Some(Reg16::DE),
+            "hl" => Some(Reg16::HL),
+            "sp" => Some(Reg16::SP),
+            "ix" => Some(Reg16::IX),
+            "iy" => Some(Reg16::IY),
+            _ => None,
+        }
+    }
+}
+
+/// The interrupt vector and memory refresh registers, only read and
+/// written through `a`, and the eZ80 memory base register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpecialReg {
+    I,
+    R,
+    /// Upper byte of 16-bit addresses in Z80 mode on the eZ80
+    MB,
+}
+
+/// Index registers used for `(ix+d)` and `(iy+d)` addressing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexReg {
+    IX,
+    IY,
+}
+
+impl IndexReg {
+    pub fn prefix(self) -> u8 {
+        match self {
+            IndexReg::IX => IX_PREFIX,
+            IndexReg::IY => IY_PREFIX,
+        }
+    }
+}
+
+/// Condition codes for jumps, calls and returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    NZ,
+    Z,
+    NC,
+    C,
+    PO,
+    PE,
+    P,
+    M,
+}
+
+impl Condition {
+    /// Condition code used in the 3-bit `cc` field
+    pub fn code(self) -> u8 {
+        match self {
+            Condition::NZ => 0,
+            Condition::Z => 1,
+            Condition::NC => 2,
+            Condition::C => 3,
+            Condition::PO => 4,
+            Condition::PE => 5,
+            Condition::P => 6,
+            Condition::M => 7,
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "nz" => Some(Condition::NZ),
+            "z" => Some(Condition::Z),
+            "nc" => Some(Condition::NC),
+            "c" => Some(Condition::C),
+            "po" => Some(Condition::PO),
+            "pe" => Some(Condition::PE),
+            "p" => Some(Condition::P),
+            "m" => Some(Condition::M),
+            _ => None,
+        }
+    }
+}
+
+/// A parsed instruction operand.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Reg8(Reg8),
+    Reg16(Reg16),
+    Special(SpecialReg),
+    /// Undocumented 8-bit half of an index register, such as `ixh` or `iyl`
+    IndexHalf {
+        reg: IndexReg,
+        high: bool,
+    },
+    /// Register indirect such as `(hl)` or `(sp)`
+    Indirect(Reg16),
+    /// `(ix+d)` or `(iy+d)`
+    Indexed {
+        reg: IndexReg,
+        disp: Expr,
+    },
+    /// Absolute memory address or port number in parentheses
+    Address(Expr),
+    /// The `(c)` port operand of `in`/`out`
+    PortC,
+    Immediate(Expr),
+    Condition(Condition),
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Reg8(reg) => write!(f, "{}", format!("{:?}", reg).to_lowercase()),
+            Operand::Reg16(Reg16::AFShadow) => write!(f, "af'"),
+            Operand::Reg16(reg) => write!(f, "{}", format!("{:?}", reg).to_lowercase()),
+            Operand::Special(reg) => write!(f, "{}", format!("{:?}", reg).to_lowercase()),
+            Operand::IndexHalf { reg, high } => {
+                let half = if *high { "h" } else { "l" };
+                write!(f, "{}{}", format!("{:?}", reg).to_lowercase(), half)
+            },
+            Operand::Indirect(reg) => write!(f, "({})", Operand::Reg16(*reg)),
+            Operand::Indexed { reg, disp } => {
+                let name = format!("{:?}", reg).to_lowercase();
+                match disp {
+                    Expr::Number(0) => write!(f, "({})", name),
+                    Expr::Unary(UnaryOp::Neg, inner) => write!(f, "({}-{})", name, inner),
+                    _ => write!(f, "({}+{})", name, disp),
+                }
+            },
+            Operand::Address(expr) => write!(f, "({})", expr),
+            Operand::PortC => write!(f, "(c)"),
+            Operand::Immediate(expr) => write!(f, "{}", expr),
+            Operand::Condition(cond) => write!(f, "{}", format!("{:?}", cond).to_lowercase()),
+        }
+    }
+}
+
+impl Operand {
+    /// The expression of an immediate operand, as used by directive arguments.
+    pub fn expression(&self) -> Result<&Expr> {
+        match self {
+            Operand::Immediate(expr) => Ok(expr),
+            other => Err(anyhow!("Expected an expression, found {}", other)),
+        }
+    }
+
+    /// Names of the symbols used by the operand's expression.
+    pub fn symbols(&self) -> Vec<&str> {
+        match self {
+            Operand::Indexed { disp: expr, .. }
+            | Operand::Address(expr)
+            | Operand::Immediate(expr) => expr.symbols(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Reinterpret an operand in condition-code position (`c` is carry, not a register).
+    pub fn into_condition(self) -> Self {
+        let name = match &self {
+            Operand::Reg8(Reg8::C) => "c".to_string(),
+            Operand::Immediate(Expr::Symbol(name)) => name.to_lowercase(),
+            _ => return self,
+        };
+        match Condition::from_name(&name) {
+            Some(cond) => Operand::Condition(cond),
+            None => self,
+        }
+    }
+}
+
+/// Parse a single operand from its tokens.
+pub fn parse_operand(tokens: &[Token]) -> Result<Operand> {
+    if tokens.is_empty() {
+        return Err(anyhow!("Missing operand"));
+    }
+
+    if let [Token::Ident(name)] = tokens {
+        let lower = name.to_lowercase();
+        if let Some(reg) = Reg8::from_name(&lower) {
+            return Ok(Operand::Reg8(reg));
+        }
+        if let Some(reg) = Reg16::from_name(&lower) {
+            return Ok(Operand::Reg16(reg));
+        }
+        match lower.as_str() {
+            "i" => return Ok(Operand::Special(SpecialReg::I)),
+            "r" => return Ok(Operand::Special(SpecialReg::R)),
+            "mb" => return Ok(Operand::Special(SpecialReg::MB)),
+            "ixh" | "ixl" | "iyh" | "iyl" => {
+                let reg = if lower.starts_with("ix") {
+                    IndexReg::IX
+                } else {
+                    IndexReg::IY
+                };
+                let high = lower.ends_with('h');
+                return Ok(Operand::IndexHalf { reg, high });
+            },
+            _ => {},
+        }
+    }
+
+    if tokens[0] == Token::LParen && closing_paren(tokens) == Some(tokens.len() - 1) {
+        return parse_indirect(&tokens[1..tokens.len() - 1]);
+    }
+
+    Ok(Operand::Immediate(parse_expr(tokens)?))
+}
+
+fn parse_indirect(inner: &[Token]) -> Result<Operand> {
+    if let Some(Token::Ident(name)) = inner.first() {
+        let lower = name.to_lowercase();
+        let index = match lower.as_str() {
+            "ix" => Some(IndexReg::IX),
+            "iy" => Some(IndexReg::IY),
+            _ => None,
+        };
+
+        if let Some(reg) = index {
+            let disp = match &inner[1..] {
+                [] => Expr::Number(0),
+                rest @ [Token::Op("+" | "-"), ..] => parse_expr(rest)?,
+                _ => return Err(anyhow!("Invalid indexed addressing format")),
+            };
+            return Ok(Operand::Indexed { reg, disp });
+        }
+
+        if inner.len() == 1 {
+            if lower == "c" {
+                return Ok(Operand::PortC);
+            }
+            if let Some(reg) = Reg16::from_name(&lower) {
+                return Ok(Operand::Indirect(reg));
+            }
+        }
+    }
+
+    Ok(Operand::Address(parse_expr(inner)?))
+}
+
+/// Index of the parenthesis closing the one at the start of `tokens`.
+fn closing_paren(tokens: &[Token]) -> Option<usize> {
+    let mut depth = 0;
+    for (index, token) in tokens.iter().enumerate() {
+        match token {
+            Token::LParen => depth += 1,
+            Token::RParen => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(index);
+                }
+            },
+            _ => {},
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::lexer::tokenize;
+
+    fn operand(text: &str) -> Operand {
+        parse_operand(&tokenize(text).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_parse_registers() {
+        assert_eq!(operand("A"), Operand::Reg8(Reg8::A));
+        assert_eq!(operand("hl"), Operand::Reg16(Reg16::HL));
+        assert_eq!(operand("( HL )"), Operand::Indirect(Reg16::HL));
+        assert_eq!(operand("(c)"), Operand::PortC);
+        assert_eq!(operand("R"), Operand::Special(SpecialReg::R));
+        assert_eq!(
+            operand("IYL"),
+            Operand::IndexHalf {
+                reg: IndexReg::IY,
+                high: false
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_indexed() {
+        assert_eq!(
+            operand("(ix + 5)"),
+            Operand::Indexed {
+                reg: IndexReg::IX,
+                disp: Expr::Number(5)
+            }
+        );
+        assert_eq!(
+            operand("(IY)"),
+            Operand::Indexed {
+                reg: IndexReg::IY,
+                disp: Expr::Number(0)
+            }
+        );
+        assert_eq!(operand("(iy-3)").to_string(), "(iy-3)");
+    }
+
+    #[test]
+    fn test_parse_address_and_immediate() {
+        assert_eq!(operand("($8000)"), Operand::Address(Expr::Number(0x8000)));
+        assert_eq!(
+            operand("label"),
+            Operand::Immediate(Expr::Symbol("label".to_string()))
+        );
+        assert_eq!(
+            operand("c").into_condition(),
+            Operand::Condition(Condition::C)
+        );
+    }
+}
@@ -1,11 +1,23 @@
+use anyhow::{anyhow, Result};
+use std::ops::Range;
+
+use crate::assembler::dialect::Dialect;
+use crate::assembler::expr::{parse_expr, Expr};
+use crate::assembler::lexer::{
+    find_unquoted, is_ident_char, is_ident_start, literal_end, tokenize, Text, Token,
+};
+use crate::assembler::operand::{parse_operand, Operand};
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct ParsedLine {
     pub label: Option<String>,
     pub mnemonic: Option<String>,
-    pub operands: Option<String>,
+    pub operands: Vec<Operand>,
 }
 
-pub struct Parser;
+pub struct Parser {
+    dialect: Dialect,
+}
 
 impl Default for Parser {
     fn default() -> Self {
@@ -15,65 +27,146 @@ impl Default for Parser {
 
 impl Parser {
     pub fn new() -> Self {
-        Parser
+        Parser {
+            dialect: Dialect::default(),
+        }
     }
 
-    pub fn parse_line(&self, line: &str) -> Option<ParsedLine> {
-        // Remove comments
-        let line = if let Some(comment_pos) = line.find(';') {
-            &line[..comment_pos]
-        } else {
-            line
-        };
+    /// Also read the conventions of another assembler.
+    pub fn with_dialect(mut self, dialect: Dialect) -> Self {
+        self.dialect = dialect;
+        self
+    }
 
-        let line = line.trim();
+    pub fn dialect(&self) -> Dialect {
+        self.dialect
+    }
+
+    pub fn parse_line(&self, text: &str) -> Result<Option<ParsedLine>> {
+        let line = strip_comment(text).trim();
         if line.is_empty() {
-            return None;
+            return Ok(None);
         }
 
-        let mut label = None;
-        let mut remaining = line;
-
-        // Check for label
-        if let Some(colon_pos) = line.find(':') {
-            label = Some(line[..colon_pos].trim().to_string());
-            remaining = line[colon_pos + 1..].trim();
+        // A name-first equate is the same as `.equ NAME, value`
+        if let Some((name, directive, value)) = split_equate(line) {
+            let mut operands = vec![Operand::Immediate(Expr::Symbol(name.to_string()))];
+            operands.extend(self.parse_operands(directive, value)?);
+            return Ok(Some(ParsedLine {
+                label: None,
+                mnemonic: Some(directive.to_string()),
+                operands,
+            }));
         }
 
+        let (label, remaining) = self.split_statement(text);
         if remaining.is_empty() {
-            return Some(ParsedLine {
+            return Ok(Some(ParsedLine {
                 label,
                 mnemonic: None,
-                operands: None,
-            });
+                operands: Vec::new(),
+            }));
         }
 
-        // Parse mnemonic and operands
-        let (mnemonic, operands) = if remaining.to_lowercase().starts_with("bcall(") {
-            // Special handling for bcall
-            let start_idx = remaining.find('(').unwrap();
-            let end_idx = remaining.rfind(')').unwrap_or(remaining.len());
-            (
-                "bcall".to_string(),
-                Some(remaining[start_idx + 1..end_idx].trim().to_string()),
-            )
-        } else {
-            // Regular instruction
-            if let Some(space_pos) = remaining.find(|c: char| c.is_whitespace()) {
-                (
-                    remaining[..space_pos].to_lowercase(),
-                    Some(remaining[space_pos..].trim().to_string()),
-                )
-            } else {
-                (remaining.to_lowercase(), None)
-            }
-        };
+        // The mnemonic ends at the first character that cannot be part of it,
+        // so both `bcall(_PutS)` and `bcall _PutS` split the same way
+        let split = remaining
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '.' || c == '_'))
+            .unwrap_or(remaining.len());
+        let mnemonic = remaining[..split].to_lowercase();
+        let operands = self.parse_operands(&mnemonic, &remaining[split..])?;
 
-        Some(ParsedLine {
+        Ok(Some(ParsedLine {
             label,
             mnemonic: Some(mnemonic),
             operands,
-        })
+        }))
+    }
+
+    /// The label of a line, even when the rest of it fails to parse.
+    pub fn parse_label(&self, line: &str) -> Option<String> {
+        if split_equate(strip_comment(line).trim()).is_some() {
+            return None;
+        }
+        self.split_statement(line).0
+    }
+
+    /// The label of a line and the statement after it, without the comment.
+    ///
+    /// Dialects with column-one labels also read a name at the very start of
+    /// the line as a label without a colon. Words starting with `.` or `#`
+    /// there are still directives.
+    pub fn split_statement<'t>(&self, line: &'t str) -> (Option<String>, &'t str) {
+        let code = strip_comment(line);
+        let (label, statement) = split_label(code.trim());
+        if label.is_some()
+            || !self.dialect.column_one_labels()
+            || !code.starts_with(is_ident_start)
+            || code.starts_with('.')
+        {
+            return (label, statement);
+        }
+        let end = code.find(|c: char| !is_ident_char(c)).unwrap_or(code.len());
+        (Some(code[..end].to_string()), code[end..].trim())
+    }
+
+    /// Character columns of the code on a line, without indentation or comment.
+    pub fn code_columns(&self, line: &str) -> Range<usize> {
+        let code = strip_comment(line);
+        let start = code.len() - code.trim_start().len();
+        let end = code.trim_end().len().max(start);
+        line[..start].chars().count()..line[..end].chars().count()
+    }
+
+    /// Tokenize an operand list and parse each comma-separated operand.
+    ///
+    /// Directive arguments are always expressions, and the first operand of
+    /// a conditional jump, call or return is read as a condition code.
+    pub fn parse_operands(&self, mnemonic: &str, text: &str) -> Result<Vec<Operand>> {
+        // Float literals keep their decimal text, which the float encoder reads exactly
+        if matches!(mnemonic, ".float" | ".dfp") {
+            return Ok(self
+                .split_operands(text)
+                .into_iter()
+                .map(|literal| Operand::Immediate(Expr::Str(Text::from(literal.as_str()))))
+                .collect());
+        }
+
+        // `.assume adl=1` names the setting before its value
+        if mnemonic == ".assume" {
+            let (setting, value) = text
+                .split_once('=')
+                .ok_or_else(|| anyhow!(".assume requires a setting such as adl=1"))?;
+            let setting = Expr::Symbol(setting.trim().to_lowercase());
+            let value = parse_expr(&tokenize(value)?)?;
+            return Ok(vec![Operand::Immediate(setting), Operand::Immediate(value)]);
+        }
+
+        let tokens = tokenize(text)?;
+        if tokens.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut operands = Vec::new();
+        for group in tokens.split(|token| *token == Token::Comma) {
+            let operand = if mnemonic.starts_with('.') {
+                Operand::Immediate(parse_expr(group)?)
+            } else {
+                parse_operand(group)?
+            };
+            operands.push(operand);
+        }
+
+        let conditional = match mnemonic {
+            "jp" | "jr" | "call" => operands.len() == 2,
+            "ret" => operands.len() == 1,
+            _ => false,
+        };
+        if conditional {
+            operands[0] = operands[0].clone().into_condition();
+        }
+
+        Ok(operands)
     }
 
     pub fn split_operands(&self, operands: &str) -> Vec<String> {
@@ -81,7 +174,17 @@ impl Parser {
         let mut current = String::new();
         let mut paren_depth = 0;
 
-        for ch in operands.chars() {
+        let chars: Vec<char> = operands.chars().collect();
+        let mut pos = 0;
+        while pos < chars.len() {
+            let ch = chars[pos];
+            // Commas and parentheses inside quotes are part of the literal
+            if let Some(end) = literal_end(&chars, pos) {
+                current.extend(&chars[pos..end]);
+                pos = end;
+                continue;
+            }
+            pos += 1;
             match ch {
                 '(' => {
                     paren_depth += 1;
@@ -107,34 +210,122 @@ impl Parser {
     }
 }
 
+/// The code part of a line, without its comment.
+pub fn strip_comment(line: &str) -> &str {
+    match find_unquoted(line, ';') {
+        Some(comment_pos) => &line[..comment_pos],
+        None => line,
+    }
+}
+
+/// Split a trimmed line into its label and the statement after it.
+pub fn split_label(line: &str) -> (Option<String>, &str) {
+    match find_unquoted(line, ':') {
+        Some(colon_pos) => (
+            Some(line[..colon_pos].trim().to_string()),
+            line[colon_pos + 1..].trim(),
+        ),
+        None => (None, line),
+    }
+}
+
+/// Split an equate written with the name first into the name, the directive
+/// it stands for and the value text.
+///
+/// `NAME .equ value`, `NAME equ value` and `NAME = value` define constants,
+/// `NAME .set value` and `NAME := value` define redefinable symbols. The name
+/// may be followed by a colon.
+pub fn split_equate(line: &str) -> Option<(&str, &'static str, &str)> {
+    let name_end = line.find(|c: char| !is_ident_char(c)).unwrap_or(line.len());
+    let name = &line[..name_end];
+    if !name.starts_with(is_ident_start) {
+        return None;
+    }
+
+    let rest = line[name_end..].trim_start();
+    if let Some(value) = rest.strip_prefix(":=") {
+        return Some((name, ".set", value));
+    }
+    let rest = rest.strip_prefix(':').unwrap_or(rest).trim_start();
+    if let Some(value) = rest.strip_prefix('=') {
+        return (!value.starts_with('=')).then_some((name, ".equ", value));
+    }
+    let word_end = rest.find(|c: char| !is_ident_char(c)).unwrap_or(rest.len());
+    let directive = match rest[..word_end].to_lowercase().as_str() {
+        ".equ" | "equ" => ".equ",
+        ".set" => ".set",
+        _ => return None,
+    };
+    Some((name, directive, &rest[word_end..]))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use crate::assembler::operand::{Condition, Reg16, Reg8};
+
     #[test]
     fn test_parse_simple_instruction() {
         let parser = Parser::new();
-        let result = parser.parse_line("    ld hl, 1234").unwrap();
+        let result = parser.parse_line("    ld hl, 1234").unwrap().unwrap();
         assert_eq!(result.label, None);
         assert_eq!(result.mnemonic, Some("ld".to_string()));
-        assert_eq!(result.operands, Some("hl, 1234".to_string()));
+        assert_eq!(
+            result.operands,
+            vec![
+                Operand::Reg16(Reg16::HL),
+                Operand::Immediate(Expr::Number(1234))
+            ]
+        );
     }
 
     #[test]
     fn test_parse_label_and_instruction() {
         let parser = Parser::new();
-        let result = parser.parse_line("loop:   inc a").unwrap();
+        let result = parser.parse_line("loop:   inc a").unwrap().unwrap();
         assert_eq!(result.label, Some("loop".to_string()));
         assert_eq!(result.mnemonic, Some("inc".to_string()));
-        assert_eq!(result.operands, Some("a".to_string()));
+        assert_eq!(result.operands, vec![Operand::Reg8(Reg8::A)]);
     }
 
     #[test]
     fn test_parse_bcall() {
         let parser = Parser::new();
-        let result = parser.parse_line("    bcall(_ClrLCDFull)").unwrap();
+        let result = parser
+            .parse_line("    bcall(_ClrLCDFull)")
+            .unwrap()
+            .unwrap();
         assert_eq!(result.mnemonic, Some("bcall".to_string()));
-        assert_eq!(result.operands, Some("_ClrLCDFull".to_string()));
+        assert_eq!(
+            result.operands,
+            vec![Operand::Address(Expr::Symbol("_ClrLCDFull".to_string()))]
+        );
+    }
+
+    #[test]
+    fn test_parse_is_case_and_space_insensitive() {
+        let parser = Parser::new();
+        let upper = parser.parse_line("LD A , ( HL )").unwrap().unwrap();
+        let lower = parser.parse_line("ld a,(hl)").unwrap().unwrap();
+        assert_eq!(upper, lower);
+    }
+
+    #[test]
+    fn test_parse_condition() {
+        let parser = Parser::new();
+        let result = parser.parse_line("jr c, loop").unwrap().unwrap();
+        assert_eq!(result.operands[0], Operand::Condition(Condition::C));
+        let result = parser.parse_line("ret c").unwrap().unwrap();
+        assert_eq!(result.operands, vec![Operand::Condition(Condition::C)]);
+    }
+
+    #[test]
+    fn test_code_columns() {
+        let parser = Parser::new();
+        assert_eq!(parser.code_columns("    ld a,b   ; load"), 4..10);
+        assert_eq!(parser.code_columns("; only a comment"), 0..0);
+        assert_eq!(parser.parse_label("loop: ld a,,"), Some("loop".to_string()));
     }
 
     #[test]
@@ -148,5 +339,69 @@ mod tests {
             parser.split_operands("a, b"),
             vec!["a".to_string(), "b".to_string()]
         );
+        assert_eq!(
+            parser.split_operands("\"a, (b\", ','"),
+            vec!["\"a, (b\"".to_string(), "','".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_quoted_separators() {
+        assert_eq!(
+            strip_comment(".db \"Score: 10;\", ';' ; done"),
+            ".db \"Score: 10;\", ';' "
+        );
+        assert_eq!(strip_comment(".db \"\\\";\" ; x"), ".db \"\\\";\" ");
+        assert_eq!(strip_comment("ex af,af' ; swap"), "ex af,af' ");
+        assert_eq!(split_label(".db \"a:b\""), (None, ".db \"a:b\""));
+        assert_eq!(
+            split_label("msg: .db ':'"),
+            (Some("msg".to_string()), ".db ':'")
+        );
+    }
+
+    #[test]
+    fn test_equate_forms() {
+        let parser = Parser::new();
+        let expected = |directive: &str| ParsedLine {
+            label: None,
+            mnemonic: Some(directive.to_string()),
+            operands: vec![
+                Operand::Immediate(Expr::Symbol("SIZE".to_string())),
+                Operand::Immediate(Expr::Number(8)),
+            ],
+        };
+        for line in [
+            ".equ SIZE, 8",
+            "SIZE .equ 8",
+            "SIZE: .EQU 8",
+            "SIZE equ 8",
+            "SIZE = 8",
+            "SIZE=8 ; comment",
+        ] {
+            assert_eq!(parser.parse_line(line).unwrap(), Some(expected(".equ")));
+        }
+        for line in ["SIZE .set 8", "SIZE := 8", ".set SIZE, 8"] {
+            assert_eq!(parser.parse_line(line).unwrap(), Some(expected(".set")));
+        }
+        assert_eq!(split_equate("set 0,a"), None);
+        assert_eq!(split_equate("ld a,b"), None);
+        assert_eq!(split_equate("x == 1"), None);
+        assert_eq!(parser.parse_label("SIZE: .equ bad("), None);
+    }
+
+    #[test]
+    fn test_column_one_labels() {
+        let parser = Parser::new().with_dialect(Dialect::Spasm);
+        let result = parser.parse_line("loop djnz loop").unwrap().unwrap();
+        assert_eq!(result.label, Some("loop".to_string()));
+        assert_eq!(result.mnemonic, Some("djnz".to_string()));
+        assert_eq!(
+            parser.split_statement("done ; finished"),
+            (Some("done".to_string()), "")
+        );
+        assert_eq!(parser.split_statement("    ret"), (None, "ret"));
+        assert_eq!(parser.split_statement(".org $9D93"), (None, ".org $9D93"));
+        assert_eq!(Parser::new().split_statement("ret"), (None, "ret"));
     }
 }
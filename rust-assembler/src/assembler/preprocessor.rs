@@ -0,0 +1,981 @@
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::assembler::conditional::{ConditionalKind, ConditionalStack};
+use crate::assembler::custom::CustomInstruction;
+use crate::assembler::expr::{parse_expr, Expr, SymbolResolver};
+use crate::assembler::layout::{Layout, LayoutKind};
+use crate::assembler::lexer::{
+    find_unquoted, is_ident_char, is_ident_start, literal_end, tokenize,
+};
+use crate::assembler::operand::Operand;
+use crate::assembler::parser::{split_equate, strip_comment, Parser};
+use crate::assembler::repeat::{iteration_count, RepeatBlock, RepeatKind};
+use crate::assembler::symbols::is_anonymous;
+use crate::constants::{MAX_MACRO_DEPTH, MAX_REPETITIONS};
+use crate::diagnostics::{Diagnostic, Span};
+
+/// A source line after macro expansion, ready to be parsed.
+#[derive(Debug, Clone)]
+pub struct ExpandedLine {
+    pub text: String,
+    /// Where the line was written, which is inside the macro body for expanded lines
+    pub span: Span,
+    /// The macro invocations that produced the line, innermost first
+    pub invocations: Vec<Invocation>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Invocation {
+    pub name: String,
+    pub span: Span,
+}
+
+impl ExpandedLine {
+    /// A diagnostic located on this line that also points at every invocation.
+    pub fn diagnostic(&self, error: anyhow::Error) -> Diagnostic {
+        self.invocations.iter().fold(
+            Diagnostic::from_error(error, &self.span),
+            |diagnostic, invocation| {
+                diagnostic.with_related(
+                    format!("in expansion of macro {}", invocation.name),
+                    invocation.span.clone(),
+                )
+            },
+        )
+    }
+}
+
+struct Macro {
+    name: String,
+    /// Parameter names with their default values
+    params: Vec<(String, Option<String>)>,
+    body: Vec<(String, Span)>,
+    /// Labels defined in the body, renamed for every expansion
+    labels: HashSet<String>,
+}
+
+/// Expands `.macro` definitions before the source is parsed.
+///
+/// Errors are returned with the index of the output line they precede, so
+/// they can be reported in source order with the errors of later stages.
+pub struct Preprocessor<'a> {
+    parser: &'a Parser,
+    file_name: &'a str,
+    include_paths: &'a [PathBuf],
+    /// Files being read, outermost first, to detect include cycles
+    include_stack: Vec<(PathBuf, String)>,
+    macros: HashMap<String, Rc<Macro>>,
+    /// `#define` names and their replacement text
+    defines: HashMap<String, String>,
+    /// Values known before assembly, which is all `#if` conditions can use
+    constants: HashMap<String, i64>,
+    conditionals: ConditionalStack,
+    /// The `.rept`, `.for` or `.while` block whose body is being read
+    repeat: Option<RepeatBlock>,
+    /// The `.struct` or `.enum` block being defined
+    layout: Option<Layout>,
+    /// Instructions added with `.addinstr`, by mnemonic
+    instructions: HashMap<String, Vec<CustomInstruction>>,
+    expansions: usize,
+    lines: Vec<ExpandedLine>,
+    diagnostics: Vec<(usize, Diagnostic)>,
+}
+
+impl<'a> Preprocessor<'a> {
+    pub fn new(parser: &'a Parser, file_name: &'a str, include_paths: &'a [PathBuf]) -> Self {
+        Preprocessor {
+            parser,
+            file_name,
+            include_paths,
+            include_stack: Vec::new(),
+            macros: HashMap::new(),
+            defines: HashMap::new(),
+            constants: HashMap::new(),
+            conditionals: ConditionalStack::default(),
+            repeat: None,
+            layout: None,
+            instructions: HashMap::new(),
+            expansions: 0,
+            lines: Vec::new(),
+            diagnostics: Vec::new(),
+        }
+    }
+
+    pub fn run(mut self, source: &str) -> (Vec<ExpandedLine>, Vec<(usize, Diagnostic)>) {
+        if let Ok(path) = fs::canonicalize(self.file_name) {
+            self.include_stack.push((path, self.file_name.to_string()));
+        }
+        self.read_source(self.file_name, source);
+        (self.lines, self.diagnostics)
+    }
+
+    /// Expand every line of one file, collecting its macro definitions.
+    fn read_source(&mut self, file: &str, source: &str) {
+        let source_lines: Vec<(&str, Span)> = source
+            .lines()
+            .enumerate()
+            .map(|(index, text)| (text, self.span(file, index + 1, text)))
+            .collect();
+        let mut lines = source_lines.into_iter();
+        let depth = self.conditionals.depth();
+
+        while let Some((text, span)) = lines.next() {
+            let header = self.statement_of(text);
+            let reading_body = self.repeat.is_some() || !self.conditionals.is_active();
+            if first_word(header) != ".macro" || reading_body {
+                self.process(ExpandedLine {
+                    text: text.to_string(),
+                    span,
+                    invocations: Vec::new(),
+                });
+                continue;
+            }
+
+            let mut body = Vec::new();
+            let mut closed = false;
+            for (body_text, body_span) in lines.by_ref() {
+                if matches!(
+                    first_word(self.statement_of(body_text)).as_str(),
+                    ".endm" | ".endmacro"
+                ) {
+                    closed = true;
+                    break;
+                }
+                body.push((body_text.to_string(), body_span));
+            }
+
+            let result = if closed {
+                self.define_macro(&header[".macro".len()..], body)
+            } else {
+                Err(anyhow!("Missing .endm for this macro definition"))
+            };
+            if let Err(e) = result {
+                let position = self.lines.len();
+                self.diagnostics
+                    .push((position, Diagnostic::from_error(e, &span)));
+            }
+        }
+        self.close_blocks(depth);
+    }
+
+    /// The statement of a line, without label and comment.
+    fn statement_of<'t>(&self, text: &'t str) -> &'t str {
+        self.parser.split_statement(text).1
+    }
+
+    fn span(&self, file: &str, line: usize, text: &str) -> Span {
+        Span {
+            file: file.to_string(),
+            line,
+            columns: self.parser.code_columns(text),
+            source_line: text.to_string(),
+        }
+    }
+
+    /// Parse a `name(arg1, arg2=default)` header and store the macro.
+    fn define_macro(&mut self, header: &str, body: Vec<(String, Span)>) -> Result<()> {
+        let header = header.trim();
+        let name_end = header
+            .find(|c: char| !is_ident_char(c))
+            .unwrap_or(header.len());
+        let name = &header[..name_end];
+        if !name.starts_with(is_ident_start) {
+            return Err(anyhow!("Expected a macro name after .macro"));
+        }
+        if self.macros.contains_key(&name.to_lowercase()) {
+            return Err(anyhow!("Duplicate definition of macro {}", name));
+        }
+
+        let mut params = Vec::new();
+        for param in self.split_arguments(&header[name_end..]) {
+            let (param, default) = match param.split_once('=') {
+                Some((param, default)) => (param.trim(), Some(default.trim().to_string())),
+                None => (param.as_str(), None),
+            };
+            if !param.starts_with(is_ident_start) || !param.chars().all(is_ident_char) {
+                return Err(anyhow!("Invalid macro parameter name: {}", param));
+            }
+            params.push((param.to_string(), default));
+        }
+
+        let labels = body
+            .iter()
+            .filter_map(|(text, _)| self.parser.parse_label(text))
+            .filter(|label| !is_anonymous(label))
+            .collect();
+
+        self.macros.insert(
+            name.to_lowercase(),
+            Rc::new(Macro {
+                name: name.to_string(),
+                params,
+                body,
+                labels,
+            }),
+        );
+        Ok(())
+    }
+
+    /// Handle preprocessor directives, then expand macros and defines.
+    fn process(&mut self, line: ExpandedLine) {
+        let statement = self.statement_of(&line.text);
+        let word = first_word(statement);
+        let argument = statement[word.len()..].to_string();
+
+        // The replacement text of a `#define` keeps its separators until it is used
+        if word != "#define" {
+            if let Some(statements) = self.split_statements(&line) {
+                for statement in statements {
+                    self.process(statement);
+                }
+                return;
+            }
+        }
+
+        if let Some(block) = &mut self.repeat {
+            if block.collect(&word, &line.text, &line.span) {
+                let block = self.repeat.take().expect("a block is being read");
+                if word != block.kind.end_word() {
+                    let error = anyhow!(
+                        "Expected {} to close .{}, found {}",
+                        block.kind.end_word(),
+                        block.kind.name(),
+                        word
+                    );
+                    self.report(&line, Err(error));
+                }
+                // Errors in the count or condition point at the opening line
+                let opener = ExpandedLine {
+                    text: block.opened_at.source_line.clone(),
+                    span: block.opened_at.clone(),
+                    invocations: block.invocations.clone(),
+                };
+                let result = self.repeat_block(&block);
+                self.report(&opener, result);
+            }
+            return;
+        }
+
+        if let Some(kind) = ConditionalKind::from_word(&word) {
+            let result = self.conditional(kind, &word, &argument, &line.span);
+            self.report(&line, result);
+            return;
+        }
+        if !self.conditionals.is_active() {
+            return;
+        }
+        if self.layout.is_some() {
+            let result = self.layout_line(&word, &line);
+            self.report(&line, result);
+            return;
+        }
+
+        let result = match word.as_str() {
+            "#define" => self.define(&argument, &line.span),
+            "#undef" => {
+                self.defines.remove(argument.trim());
+                Ok(())
+            },
+            ".include" | "#include" => self.include(&argument, &line),
+            ".addinstr" => CustomInstruction::parse(&argument).map(|(mnemonic, instruction)| {
+                self.instructions
+                    .entry(mnemonic)
+                    .or_default()
+                    .push(instruction);
+            }),
+            ".macro" => Err(anyhow!("Macros cannot be defined inside a macro")),
+            ".endm" | ".endmacro" => Err(anyhow!("{} without a matching .macro", word)),
+            _ if RepeatKind::is_end(&word) => Err(anyhow!("{} without a matching loop", word)),
+            ".endstruct" | ".ends" | ".endenum" | ".ende" => {
+                Err(anyhow!("{} without a matching .struct or .enum", word))
+            },
+            _ if LayoutKind::from_word(&word).is_some() => {
+                let kind = LayoutKind::from_word(&word).expect("checked above");
+                Layout::open(kind, &argument, &line.span).map(|layout| {
+                    self.layout = Some(layout);
+                })
+            },
+            _ => {
+                if let Some(kind) = RepeatKind::from_word(&word) {
+                    let block = RepeatBlock::new(kind, &argument, &line.span, &line.invocations);
+                    self.repeat = Some(block);
+                    return;
+                }
+                self.expand(line);
+                return;
+            },
+        };
+        self.report(&line, result);
+    }
+
+    /// Replace defined names, then expand a macro invocation or emit the line.
+    fn expand(&mut self, mut line: ExpandedLine) {
+        if !self.defines.is_empty() {
+            line.text = self.expand_defines(&line.text);
+            if let Some(statements) = self.split_statements(&line) {
+                for statement in statements {
+                    self.process(statement);
+                }
+                return;
+            }
+        }
+
+        let code = strip_comment(&line.text).trim();
+        let (label, statement) = self.parser.split_statement(&line.text);
+        let word = first_word(statement);
+        let Some(definition) = self.macros.get(&word).cloned() else {
+            if matches!(word.as_str(), ".equ" | ".set") || split_equate(code).is_some() {
+                self.track_constant(&line.text);
+            }
+            let custom = self.instructions.get(&word).and_then(|instructions| {
+                let args = &statement[word.len()..];
+                instructions
+                    .iter()
+                    .find_map(|instruction| instruction.expand(args))
+            });
+            if let Some(data) = custom {
+                line.text = match label {
+                    Some(label) => format!("{}: {}", label, data),
+                    None => data,
+                };
+            }
+            self.lines.push(line);
+            return;
+        };
+
+        let replacements = match self.bind(&definition, &statement[word.len()..], &line) {
+            Ok(replacements) => replacements,
+            Err(e) => {
+                self.report(&line, Err(e));
+                return;
+            },
+        };
+
+        // The invocation's own label stays at the address of the expansion
+        if let Some(label) = label {
+            self.lines.push(ExpandedLine {
+                text: format!("{}:", label),
+                ..line.clone()
+            });
+        }
+
+        let mut invocations = vec![Invocation {
+            name: definition.name.clone(),
+            span: line.span.clone(),
+        }];
+        invocations.extend(line.invocations);
+
+        let depth = self.conditionals.depth();
+        for (text, span) in &definition.body {
+            self.process(ExpandedLine {
+                text: substitute(text, &replacements),
+                span: span.clone(),
+                invocations: invocations.clone(),
+            });
+        }
+        self.close_blocks(depth);
+    }
+
+    /// The statements of a line written as `a \ b`, in dialects that allow it.
+    ///
+    /// Each statement keeps the location of the line. `None` when the line
+    /// holds a single statement.
+    fn split_statements(&self, line: &ExpandedLine) -> Option<Vec<ExpandedLine>> {
+        if !self.parser.dialect().backslash_separators() {
+            return None;
+        }
+        let mut rest = strip_comment(&line.text);
+        find_unquoted(rest, '\\')?;
+
+        let mut statements = Vec::new();
+        while let Some(pos) = find_unquoted(rest, '\\') {
+            statements.push(&rest[..pos]);
+            rest = &rest[pos + 1..];
+        }
+        statements.push(rest);
+        let lines = statements
+            .into_iter()
+            .enumerate()
+            .map(|(index, text)| ExpandedLine {
+                // Only the first statement can start with a column-one label
+                text: if index == 0 {
+                    text.to_string()
+                } else {
+                    format!(" {}", text.trim())
+                },
+                ..line.clone()
+            })
+            .collect();
+        Some(lines)
+    }
+
+    fn report(&mut self, line: &ExpandedLine, result: Result<()>) {
+        if let Err(e) = result {
+            let position = self.lines.len();
+            self.diagnostics.push((position, line.diagnostic(e)));
+        }
+    }
+
+    /// Report and close the blocks left open at the end of a file or expansion.
+    ///
+    /// Conditionals opened past `depth` are closed; a repetition or layout
+    /// block can only be open in the innermost file or expansion.
+    fn close_blocks(&mut self, depth: usize) {
+        let position = self.lines.len();
+        let mut unclosed = self.conditionals.close_from(depth);
+        if let Some(block) = self.repeat.take() {
+            let error = Diagnostic::error(format!(
+                "Missing {} for this .{}",
+                block.kind.end_word(),
+                block.kind.name()
+            ));
+            unclosed.push(error.with_span(block.opened_at));
+        }
+        if let Some(layout) = self.layout.take() {
+            let error = Diagnostic::error(format!(
+                "Missing {} for this {}",
+                layout.kind.end_word(),
+                layout.kind.directive()
+            ));
+            unclosed.push(error.with_span(layout.opened_at));
+        }
+        for diagnostic in unclosed {
+            self.diagnostics.push((position, diagnostic));
+        }
+    }
+
+    /// Define the struct fields or enum members on one line of a layout block.
+    fn layout_line(&mut self, word: &str, line: &ExpandedLine) -> Result<()> {
+        let mut layout = self.layout.take().expect("a layout block is open");
+        if layout.kind.is_end(word) {
+            for (name, value) in layout.close() {
+                self.define_constant(name, value, line);
+            }
+            return Ok(());
+        }
+
+        let usage = match layout.kind {
+            LayoutKind::Struct => "field count",
+            LayoutKind::Enum => "enum value",
+        };
+        let members = layout.members(&line.text, |text| self.evaluate(text, usage));
+        self.layout = Some(layout);
+        for (name, value) in members? {
+            self.define_constant(name, value, line);
+        }
+        Ok(())
+    }
+
+    /// Emit an `.equ` for a value computed by the preprocessor.
+    fn define_constant(&mut self, name: String, value: i64, line: &ExpandedLine) {
+        self.lines.push(ExpandedLine {
+            text: format!(".equ {},{}", name, value),
+            span: line.span.clone(),
+            invocations: line.invocations.clone(),
+        });
+        self.constants.insert(name, value);
+    }
+
+    /// Expand the body of a finished `.rept`, `.for` or `.while` block.
+    fn repeat_block(&mut self, block: &RepeatBlock) -> Result<()> {
+        let labels = block.labels(self.parser);
+        match block.kind {
+            RepeatKind::Rept => {
+                let count = self.evaluate(&block.argument, "repeat count")?;
+                let count = u64::try_from(count)
+                    .map_err(|_| anyhow!(".rept count cannot be negative: {}", count))?;
+                check_repetitions(count)?;
+                for _ in 0..count {
+                    self.iterate(block, &labels, None);
+                }
+            },
+            RepeatKind::For => {
+                let arguments = self.parser.split_operands(&block.argument);
+                let (name, start, end, step) = match arguments.as_slice() {
+                    [name, start, end] => (name, start, end, None),
+                    [name, start, end, step] => (name, start, end, Some(step)),
+                    _ => {
+                        return Err(anyhow!(
+                            ".for requires a name, start, end and optional step"
+                        ))
+                    },
+                };
+                if !name.starts_with(is_ident_start) || !name.chars().all(is_ident_char) {
+                    return Err(anyhow!("Invalid .for variable name: {}", name));
+                }
+                let start = self.evaluate(start, "loop bound")?;
+                let end = self.evaluate(end, "loop bound")?;
+                let step = match step {
+                    Some(step) => self.evaluate(step, "loop bound")?,
+                    None => 1,
+                };
+                if step == 0 {
+                    return Err(anyhow!(".for step cannot be 0"));
+                }
+                let count = iteration_count(start, end, step);
+                check_repetitions(count)?;
+                for index in 0..count as i64 {
+                    self.iterate(block, &labels, Some((name, start + index * step)));
+                }
+            },
+            RepeatKind::While => {
+                let mut count = 0;
+                while self.evaluate(&block.argument, "condition")? != 0 {
+                    count += 1;
+                    check_repetitions(count)?;
+                    self.iterate(block, &labels, None);
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Expand one iteration, with the `.for` variable if there is one.
+    fn iterate(
+        &mut self,
+        block: &RepeatBlock,
+        labels: &HashSet<String>,
+        variable: Option<(&str, i64)>,
+    ) {
+        self.expansions += 1;
+        let mut replacements: HashMap<String, String> = labels
+            .iter()
+            .map(|label| {
+                let unique = format!(
+                    "{}.{}.{}",
+                    block.kind.name(),
+                    self.expansions,
+                    label.trim_start_matches('.')
+                );
+                (label.clone(), unique)
+            })
+            .collect();
+        if let Some((name, value)) = variable {
+            replacements.insert(name.to_string(), value.to_string());
+        }
+
+        let depth = self.conditionals.depth();
+        for (text, span) in &block.body {
+            // `{i}` pastes the value into names, like `row_{i}:`
+            let text = match variable {
+                Some((name, value)) => text.replace(&format!("{{{}}}", name), &value.to_string()),
+                None => text.clone(),
+            };
+            self.process(ExpandedLine {
+                text: substitute(&text, &replacements),
+                span: span.clone(),
+                invocations: block.invocations.clone(),
+            });
+        }
+        self.close_blocks(depth);
+    }
+
+    fn conditional(
+        &mut self,
+        kind: ConditionalKind,
+        directive: &str,
+        argument: &str,
+        span: &Span,
+    ) -> Result<()> {
+        let mut conditionals = std::mem::take(&mut self.conditionals);
+        let condition = || self.condition(kind, directive, argument);
+        let result = match kind {
+            ConditionalKind::If | ConditionalKind::IfDef | ConditionalKind::IfNDef => {
+                conditionals.open(directive, span, condition)
+            },
+            ConditionalKind::ElseIf => conditionals.else_if(directive, condition),
+            ConditionalKind::Else => conditionals.otherwise(directive),
+            ConditionalKind::EndIf => conditionals.close(directive),
+        };
+        self.conditionals = conditionals;
+        result
+    }
+
+    fn condition(&self, kind: ConditionalKind, directive: &str, argument: &str) -> Result<bool> {
+        match kind {
+            ConditionalKind::IfDef | ConditionalKind::IfNDef => {
+                let name = argument.trim();
+                if !name.starts_with(is_ident_start) || !name.chars().all(is_ident_char) {
+                    return Err(anyhow!("Expected a name after {}", directive));
+                }
+                let defined = self.defines.contains_key(name) || self.constants.contains_key(name);
+                Ok(defined == (kind == ConditionalKind::IfDef))
+            },
+            _ => Ok(self.evaluate(argument, "condition")? != 0),
+        }
+    }
+
+    /// Evaluate an expression that must be known before assembly.
+    fn evaluate(&self, text: &str, usage: &'static str) -> Result<i64> {
+        let text = self.expand_defines(text);
+        let expr = parse_expr(&tokenize(&text)?)?;
+        expr.evaluate(&Constants {
+            values: &self.constants,
+            usage,
+        })
+    }
+
+    /// Handle `#define NAME [replacement]`.
+    fn define(&mut self, argument: &str, span: &Span) -> Result<()> {
+        let argument = argument.trim();
+        let name_end = argument
+            .find(|c: char| !is_ident_char(c))
+            .unwrap_or(argument.len());
+        let name = &argument[..name_end];
+        if !name.starts_with(is_ident_start) {
+            return Err(anyhow!("Expected a name after #define"));
+        }
+        if argument[name_end..].starts_with('(') {
+            if !self.parser.dialect().parameterized_defines() {
+                return Err(
+                    Diagnostic::error("#define with parameters is not supported")
+                        .with_help("use .macro for parameterized code")
+                        .into(),
+                );
+            }
+            // `#define name(a, b) text` is a one-line macro
+            let close = argument
+                .find(')')
+                .ok_or_else(|| anyhow!("Missing ) after the #define parameters"))?;
+            let body = format!(" {}", argument[close + 1..].trim());
+            return self.define_macro(&argument[..=close], vec![(body, span.clone())]);
+        }
+        self.defines
+            .insert(name.to_string(), argument[name_end..].trim().to_string());
+        Ok(())
+    }
+
+    /// Define a name from outside the source, like `-D NAME=value`.
+    pub fn add_define(&mut self, name: &str, value: &str) {
+        self.defines.insert(name.to_string(), value.to_string());
+    }
+
+    /// Make a constant visible to `#if` conditions.
+    pub fn add_constant(&mut self, name: &str, value: u32) {
+        self.constants.insert(name.to_string(), i64::from(value));
+    }
+
+    /// Replace defined names until none are left, so defines may use other defines.
+    fn expand_defines(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for _ in 0..MAX_MACRO_DEPTH {
+            let expanded = substitute(&text, &self.defines);
+            if expanded == text {
+                break;
+            }
+            text = expanded;
+        }
+        text
+    }
+
+    /// Remember `.equ` and `.set` values that are already known, for later conditions.
+    fn track_constant(&mut self, text: &str) {
+        let Ok(Some(parsed)) = self.parser.parse_line(text) else {
+            return;
+        };
+        if let [Operand::Immediate(Expr::Symbol(name)), Operand::Immediate(value)] =
+            parsed.operands.as_slice()
+        {
+            let constants = Constants {
+                values: &self.constants,
+                usage: "constant",
+            };
+            if let Ok(value) = value.evaluate(&constants) {
+                self.constants.insert(name.clone(), value);
+            }
+        }
+    }
+
+    /// Read an included file in place of the `.include` line.
+    fn include(&mut self, argument: &str, line: &ExpandedLine) -> Result<()> {
+        let name = quoted_file_name(argument)
+            .ok_or_else(|| anyhow!("Expected a quoted file name after .include"))?;
+        let path = find_file(name, &line.span.file, self.include_paths)?;
+        let canonical = fs::canonicalize(&path)
+            .map_err(|e| anyhow!("Cannot read {}: {}", path.display(), e))?;
+
+        if let Some(start) = self
+            .include_stack
+            .iter()
+            .position(|(open, _)| *open == canonical)
+        {
+            let mut chain: Vec<String> = self.include_stack[start..]
+                .iter()
+                .map(|(_, file)| file.clone())
+                .collect();
+            chain.push(path.display().to_string());
+            return Err(anyhow!("Include cycle: {}", chain.join(" -> ")));
+        }
+
+        let source = fs::read_to_string(&path)
+            .map_err(|e| anyhow!("Cannot read {}: {}", path.display(), e))?;
+        let file = path.display().to_string();
+        self.include_stack.push((canonical, file.clone()));
+        self.read_source(&file, &source);
+        self.include_stack.pop();
+        Ok(())
+    }
+
+    /// Match invocation arguments to parameters and name this expansion's labels.
+    fn bind(
+        &mut self,
+        definition: &Macro,
+        arguments: &str,
+        line: &ExpandedLine,
+    ) -> Result<HashMap<String, String>> {
+        if line.invocations.len() >= MAX_MACRO_DEPTH {
+            return Err(Diagnostic::error(format!(
+                "Macro expansion nested more than {} levels deep",
+                MAX_MACRO_DEPTH
+            ))
+            .with_help(format!("check {} for unbounded recursion", definition.name))
+            .into());
+        }
+
+        let arguments = self.split_arguments(arguments);
+        if arguments.len() > definition.params.len() {
+            return Err(anyhow!(
+                "Macro {} takes {} arguments, found {}",
+                definition.name,
+                definition.params.len(),
+                arguments.len()
+            ));
+        }
+
+        let mut replacements = HashMap::new();
+        for (index, (param, default)) in definition.params.iter().enumerate() {
+            let value = match (arguments.get(index).filter(|arg| !arg.is_empty()), default) {
+                (Some(argument), _) => argument.clone(),
+                (None, Some(default)) => default.clone(),
+                (None, None) => {
+                    return Err(anyhow!(
+                        "Missing argument {} for macro {}",
+                        param,
+                        definition.name
+                    ))
+                },
+            };
+            replacements.insert(param.clone(), value);
+        }
+
+        self.expansions += 1;
+        for label in &definition.labels {
+            let unique = format!(
+                "{}.{}.{}",
+                definition.name,
+                self.expansions,
+                label.trim_start_matches('.')
+            );
+            replacements.entry(label.clone()).or_insert(unique);
+        }
+        Ok(replacements)
+    }
+
+    /// Split `(a, b)` or `a, b` into trimmed arguments.
+    fn split_arguments(&self, text: &str) -> Vec<String> {
+        let text = text.trim();
+        let text = match text.strip_prefix('(').and_then(|t| t.strip_suffix(')')) {
+            Some(inner) if balanced(inner) => inner,
+            _ => text,
+        };
+        if text.trim().is_empty() {
+            return Vec::new();
+        }
+        self.parser.split_operands(text)
+    }
+}
+
+/// Resolves conditions and repeat counts against the values known before assembly.
+struct Constants<'a> {
+    values: &'a HashMap<String, i64>,
+    /// What is being evaluated, for error messages
+    usage: &'static str,
+}
+
+impl SymbolResolver for Constants<'_> {
+    fn resolve(&self, name: &str) -> Result<i64> {
+        self.values.get(name).copied().ok_or_else(|| {
+            Diagnostic::error(format!("Undefined symbol in {}: {}", self.usage, name))
+                .with_help(format!(
+                    "a {} can only use #define names and constants defined above it",
+                    self.usage
+                ))
+                .into()
+        })
+    }
+
+    fn current_address(&self) -> Result<i64> {
+        Err(anyhow!("$ cannot be used in a {}", self.usage))
+    }
+}
+
+fn check_repetitions(count: u64) -> Result<()> {
+    if count > MAX_REPETITIONS as u64 {
+        return Err(Diagnostic::error(format!(
+            "Repetition exceeds {} iterations",
+            MAX_REPETITIONS
+        ))
+        .with_help("check the loop bounds or that the .while condition becomes false")
+        .into());
+    }
+    Ok(())
+}
+
+/// The lowercase mnemonic, directive or macro name a statement starts with,
+/// including the `#` of preprocessor directives.
+fn first_word(statement: &str) -> String {
+    let (hash, rest) = match statement.strip_prefix('#') {
+        Some(rest) => ("#", rest),
+        None => ("", statement),
+    };
+    let word: String = rest.chars().take_while(|&c| is_ident_char(c)).collect();
+    format!("{}{}", hash, word.to_lowercase())
+}
+
+/// The file name in `"name"` or `<name>`.
+fn quoted_file_name(argument: &str) -> Option<&str> {
+    let argument = argument.trim();
+    argument
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+        .or_else(|| {
+            argument
+                .strip_prefix('<')
+                .and_then(|rest| rest.strip_suffix('>'))
+        })
+}
+
+/// Find a file next to the file that refers to it, then in the include paths.
+pub fn find_file(name: &str, from_file: &str, include_paths: &[PathBuf]) -> Result<PathBuf> {
+    let directory = Path::new(from_file).parent().unwrap_or(Path::new(""));
+    std::iter::once(directory.join(name))
+        .chain(include_paths.iter().map(|path| path.join(name)))
+        .find(|candidate| candidate.is_file())
+        .ok_or_else(|| {
+            Diagnostic::error(format!("Cannot find file \"{}\"", name))
+                .with_note(format!(
+                    "searched next to {} and in {} include path{}",
+                    from_file,
+                    include_paths.len(),
+                    if include_paths.len() == 1 { "" } else { "s" }
+                ))
+                .with_help("add the directory that contains it with -I")
+                .into()
+        })
+}
+
+/// Whether every parenthesis in `text` is closed inside it.
+fn balanced(text: &str) -> bool {
+    let mut depth = 0;
+    for ch in text.chars() {
+        match ch {
+            '(' => depth += 1,
+            ')' if depth == 0 => return false,
+            ')' => depth -= 1,
+            _ => {},
+        }
+    }
+    depth == 0
+}
+
+/// Replace whole identifiers outside of strings and comments.
+fn substitute(text: &str, replacements: &HashMap<String, String>) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut result = String::with_capacity(text.len());
+    let mut pos = 0;
+
+    while pos < chars.len() {
+        let ch = chars[pos];
+        if let Some(end) = literal_end(&chars, pos) {
+            result.extend(&chars[pos..end]);
+            pos = end;
+        } else if ch == ';' {
+            result.extend(&chars[pos..]);
+            break;
+        } else if is_ident_char(ch) || ch == '$' {
+            // Numbers such as `$FF` or `0x10` are copied as a whole
+            let start = pos;
+            pos += 1;
+            while pos < chars.len() && is_ident_char(chars[pos]) {
+                pos += 1;
+            }
+            let word: String = chars[start..pos].iter().collect();
+            match replacements.get(&word) {
+                Some(replacement) if is_ident_start(ch) => result.push_str(replacement),
+                _ => result.push_str(&word),
+            }
+        } else {
+            result.push(ch);
+            pos += 1;
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(source: &str) -> Vec<String> {
+        let parser = Parser::new();
+        let (lines, diagnostics) = Preprocessor::new(&parser, "test.asm", &[]).run(source);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        lines
+            .into_iter()
+            .map(|line| line.text.trim().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_substitute_whole_identifiers() {
+        let replacements = HashMap::from([
+            ("x".to_string(), "count".to_string()),
+            ("FF".to_string(), "1".to_string()),
+        ]);
+        assert_eq!(
+            substitute("ld a,x+xy ; x", &replacements),
+            "ld a,count+xy ; x"
+        );
+        assert_eq!(
+            substitute(".db \"x\",'x',$FF", &replacements),
+            ".db \"x\",'x',$FF"
+        );
+    }
+
+    #[test]
+    fn test_expand_with_defaults() {
+        let source =
+            ".macro load(reg, value=0)\n    ld reg,value\n.endm\n    load(a, 5)\n    load b";
+        assert_eq!(expand(source), vec!["ld a,5", "ld b,0"]);
+    }
+
+    #[test]
+    fn test_labels_are_unique_per_expansion() {
+        let source = ".macro wait\n.loop: djnz .loop\n.endm\n    wait\n    wait";
+        assert_eq!(
+            expand(source),
+            vec![
+                "wait.1.loop: djnz wait.1.loop",
+                "wait.2.loop: djnz wait.2.loop"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_recursion_limit() {
+        let parser = Parser::new();
+        let source = ".macro forever\n    forever\n.endm\n    forever";
+        let (_, diagnostics) = Preprocessor::new(&parser, "test.asm", &[]).run(source);
+        assert_eq!(diagnostics.len(), 1);
+        let diagnostic = &diagnostics[0].1;
+        assert!(diagnostic.message.contains("nested more than"));
+        assert_eq!(diagnostic.span.as_ref().unwrap().line, 2);
+        assert_eq!(diagnostic.related.len(), MAX_MACRO_DEPTH);
+        assert_eq!(diagnostic.related.last().unwrap().span.line, 4);
+    }
+}
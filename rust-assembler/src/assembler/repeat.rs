@@ -0,0 +1,147 @@
+use std::collections::HashSet;
+
+use crate::assembler::parser::Parser;
+use crate::assembler::preprocessor::Invocation;
+use crate::assembler::symbols::is_anonymous;
+use crate::diagnostics::Span;
+
+/// Repetition directives and the directive that ends each of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepeatKind {
+    /// `.rept count`
+    Rept,
+    /// `.for name, start, end[, step]`
+    For,
+    /// `.while condition`
+    While,
+}
+
+impl RepeatKind {
+    pub fn from_word(word: &str) -> Option<Self> {
+        match word {
+            ".rept" => Some(RepeatKind::Rept),
+            ".for" => Some(RepeatKind::For),
+            ".while" => Some(RepeatKind::While),
+            _ => None,
+        }
+    }
+
+    pub fn is_end(word: &str) -> bool {
+        matches!(word, ".endr" | ".endfor" | ".endw")
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RepeatKind::Rept => "rept",
+            RepeatKind::For => "for",
+            RepeatKind::While => "while",
+        }
+    }
+
+    pub fn end_word(self) -> &'static str {
+        match self {
+            RepeatKind::Rept => ".endr",
+            RepeatKind::For => ".endfor",
+            RepeatKind::While => ".endw",
+        }
+    }
+}
+
+/// A repetition block whose body is still being read.
+pub struct RepeatBlock {
+    pub kind: RepeatKind,
+    /// Text after the directive: the count, loop range or condition
+    pub argument: String,
+    pub opened_at: Span,
+    /// Invocations of the macro the block is written in, if any
+    pub invocations: Vec<Invocation>,
+    pub body: Vec<(String, Span)>,
+    /// Number of nested blocks opened in the body and not yet closed
+    nesting: usize,
+}
+
+impl RepeatBlock {
+    pub fn new(
+        kind: RepeatKind,
+        argument: &str,
+        opened_at: &Span,
+        invocations: &[Invocation],
+    ) -> Self {
+        RepeatBlock {
+            kind,
+            argument: argument.trim().to_string(),
+            opened_at: opened_at.clone(),
+            invocations: invocations.to_vec(),
+            body: Vec::new(),
+            nesting: 0,
+        }
+    }
+
+    /// Add a body line, returning true once the line closing this block is reached.
+    pub fn collect(&mut self, word: &str, text: &str, span: &Span) -> bool {
+        if RepeatKind::from_word(word).is_some() {
+            self.nesting += 1;
+        } else if RepeatKind::is_end(word) {
+            if self.nesting == 0 {
+                return true;
+            }
+            self.nesting -= 1;
+        }
+        self.body.push((text.to_string(), span.clone()));
+        false
+    }
+
+    /// Labels defined in the body, renamed for every iteration.
+    ///
+    /// Labels that interpolate the loop variable, like `row_{i}`, are already
+    /// unique and keep their name.
+    pub fn labels(&self, parser: &Parser) -> HashSet<String> {
+        self.body
+            .iter()
+            .filter_map(|(text, _)| parser.parse_label(text))
+            .filter(|label| !is_anonymous(label) && !label.contains('{'))
+            .collect()
+    }
+}
+
+/// Number of values from `start` to `end` inclusive, counting by `step`.
+pub fn iteration_count(start: i64, end: i64, step: i64) -> u64 {
+    if step > 0 && start <= end {
+        (end - start) as u64 / step as u64 + 1
+    } else if step < 0 && start >= end {
+        (start - end) as u64 / step.unsigned_abs() + 1
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_iteration_count() {
+        assert_eq!(iteration_count(0, 7, 1), 8);
+        assert_eq!(iteration_count(0, 7, 2), 4);
+        assert_eq!(iteration_count(10, 0, -5), 3);
+        assert_eq!(iteration_count(1, 0, 1), 0);
+        assert_eq!(iteration_count(0, 1, -1), 0);
+    }
+
+    #[test]
+    fn test_collect_stops_at_matching_end() {
+        let span = Span {
+            file: "test.asm".to_string(),
+            line: 1,
+            columns: 0..5,
+            source_line: ".rept 2".to_string(),
+        };
+        let mut block = RepeatBlock::new(RepeatKind::Rept, " 2", &span, &[]);
+        assert!(!block.collect(".for", ".for i,0,1", &span));
+        assert!(!block.collect(".endfor", ".endfor", &span));
+        assert!(!block.collect("nop", "x_{i}: nop", &span));
+        assert!(block.collect(".endr", ".endr", &span));
+        assert_eq!(block.body.len(), 3);
+        assert!(block.labels(&Parser::new()).is_empty());
+    }
+}
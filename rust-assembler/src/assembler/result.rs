@@ -0,0 +1,69 @@
+use std::collections::BTreeMap;
+
+use crate::assembler::symbols::SymbolTable;
+use crate::diagnostics::{Diagnostic, Span};
+use crate::instructions::cycles::Cycles;
+
+/// Everything produced by a successful assembly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssemblyResult {
+    /// The assembled machine code
+    pub bytes: Vec<u8>,
+    /// Every label and constant, plus the built-in symbols the program uses
+    pub symbols: SymbolTable,
+    /// One record per non-blank source line, in source order
+    pub lines: Vec<LineRecord>,
+    /// Warnings and `.echo` output, in source order
+    pub warnings: Vec<Diagnostic>,
+    /// ROM calls the program references, by `bcall`, `call` or `.dw`, with their addresses
+    pub rom_calls: BTreeMap<String, u32>,
+}
+
+impl AssemblyResult {
+    /// A listing of every line with its address, bytes and T-states.
+    ///
+    /// Lines that emit more than four bytes continue on the following rows.
+    pub fn listing(&self) -> String {
+        let mut listing = String::new();
+        for line in &self.lines {
+            let mut rows = line.bytes.chunks(4);
+            let first = rows.next().unwrap_or(&[]);
+            let cycles = line.cycles.map(|c| c.to_string()).unwrap_or_default();
+            let row = format!(
+                "{:04X}  {:<11}  {:>5}  {}",
+                line.address,
+                hex_bytes(first),
+                cycles,
+                line.span.source_line
+            );
+            listing.push_str(row.trim_end());
+            listing.push('\n');
+            for (index, chunk) in rows.enumerate() {
+                let address = line.address as usize + (index + 1) * 4;
+                listing.push_str(&format!("{:04X}  {}\n", address, hex_bytes(chunk)));
+            }
+        }
+        listing
+    }
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    bytes.join(" ")
+}
+
+/// Address and encoding of a single source line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineRecord {
+    pub span: Span,
+    pub address: u32,
+    pub bytes: Vec<u8>,
+    /// T-states of the line's instruction; `None` for directives, labels and ROM calls
+    pub cycles: Option<Cycles>,
+}
+
+impl LineRecord {
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+}
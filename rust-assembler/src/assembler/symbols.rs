@@ -0,0 +1,304 @@
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::assembler::target::Target;
+use crate::diagnostics::{Diagnostic, Span};
+
+/// How a symbol was defined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Label,
+    /// Constant defined with `.equ`, or predefined by the assembler options
+    Equate,
+    /// Constant defined with `.set`, which may be redefined by another `.set`
+    Set,
+    /// TI-OS system variable such as `penCol`
+    SysVar,
+    /// TI-OS ROM call such as `_PutS`
+    RomCall,
+    /// TI-OS restart vector such as `rBR_CALL`, called with `rst`
+    Restart,
+}
+
+impl fmt::Display for SymbolKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SymbolKind::Label => "label",
+            SymbolKind::Equate => "equate",
+            SymbolKind::Set => "set",
+            SymbolKind::SysVar => "system variable",
+            SymbolKind::RomCall => "ROM call",
+            SymbolKind::Restart => "restart vector",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A symbol with its value and where it is defined and used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub kind: SymbolKind,
+    pub value: u32,
+    /// `None` for built-in and predefined symbols
+    pub defined_at: Option<Span>,
+    pub references: Vec<Span>,
+}
+
+/// Every symbol known to an assembly, sorted by name.
+///
+/// User definitions shadow the built-in TI-OS system variables and ROM calls
+/// of the target. Built-in symbols only appear in the table once they are
+/// referenced.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    symbols: BTreeMap<String, Symbol>,
+    target: Target,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A table with the built-in symbols of `target`.
+    pub fn for_target(target: Target) -> Self {
+        SymbolTable {
+            symbols: BTreeMap::new(),
+            target,
+        }
+    }
+
+    /// Define a symbol, rejecting a second definition of the same name.
+    ///
+    /// `.set` symbols can be redefined by another `.set` anywhere. Callers
+    /// define every other symbol once, even when a line runs in both passes.
+    pub fn define(
+        &mut self,
+        name: &str,
+        kind: SymbolKind,
+        value: u32,
+        site: Option<&Span>,
+    ) -> Result<()> {
+        if let Some(existing) = self.symbols.get_mut(name) {
+            let builtin = matches!(
+                existing.kind,
+                SymbolKind::SysVar | SymbolKind::RomCall | SymbolKind::Restart
+            );
+            let redefinable = existing.kind == SymbolKind::Set && kind == SymbolKind::Set;
+
+            if redefinable {
+                existing.value = value;
+                return Ok(());
+            }
+            if !builtin {
+                let note = match &existing.defined_at {
+                    Some(first) => format!("first defined at {}:{}", first.file, first.line),
+                    None => "first defined by the assembler options".to_string(),
+                };
+                let mut error =
+                    Diagnostic::error(format!("Duplicate definition of {}", name)).with_note(note);
+                if kind == SymbolKind::Set {
+                    error = error.with_help(format!("{} is not a .set symbol", name));
+                }
+                return Err(error.into());
+            }
+        }
+
+        self.symbols.insert(
+            name.to_string(),
+            Symbol {
+                kind,
+                value,
+                defined_at: site.cloned(),
+                references: Vec::new(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Value of a defined or built-in symbol.
+    pub fn value(&self, name: &str) -> Option<u32> {
+        match self.symbols.get(name) {
+            Some(symbol) => Some(symbol.value),
+            None => self.builtin(name).map(|(_, value)| value),
+        }
+    }
+
+    /// Name under which `name` is looked up from inside the `scope` label.
+    ///
+    /// A local name (`.loop` or `_loop`) refers to the local label of the
+    /// enclosing scope when there is one, and otherwise to the global symbol
+    /// of that name, so ROM calls like `_PutS` keep working. A `_name` that
+    /// matches both is ambiguous.
+    pub fn scoped_name(&self, name: &str, scope: Option<&str>) -> Result<String> {
+        let local = match scope {
+            Some(scope) if is_local(name) => qualify(scope, name),
+            _ => return Ok(name.to_string()),
+        };
+        if !self.symbols.contains_key(&local) {
+            return Ok(name.to_string());
+        }
+        if self.value(name).is_some() {
+            return Err(Diagnostic::error(format!(
+                "Ambiguous symbol {}: both the local label {} and a global symbol match",
+                name, local
+            ))
+            .with_help(format!("write {} for the local label or rename it", local))
+            .into());
+        }
+        Ok(local)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.get(name)
+    }
+
+    /// Record a use of `name`; unknown names are ignored.
+    pub fn add_reference(&mut self, name: &str, site: &Span) {
+        if !self.symbols.contains_key(name) {
+            let Some((kind, value)) = self.builtin(name) else {
+                return;
+            };
+            self.symbols.insert(
+                name.to_string(),
+                Symbol {
+                    kind,
+                    value,
+                    defined_at: None,
+                    references: Vec::new(),
+                },
+            );
+        }
+        if let Some(symbol) = self.symbols.get_mut(name) {
+            symbol.references.push(site.clone());
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Symbol)> {
+        self.symbols
+            .iter()
+            .map(|(name, symbol)| (name.as_str(), symbol))
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    fn builtin(&self, name: &str) -> Option<(SymbolKind, u32)> {
+        if let Some(address) = self.target.sys_var(name) {
+            Some((SymbolKind::SysVar, address))
+        } else if let Some(vector) = self.target.restart(name) {
+            Some((SymbolKind::Restart, vector))
+        } else {
+            self.target
+                .rom_call(name)
+                .map(|address| (SymbolKind::RomCall, address))
+        }
+    }
+}
+
+/// Whether a label is local to the preceding global label.
+pub fn is_local(name: &str) -> bool {
+    name.len() > 1 && (name.starts_with('.') || name.starts_with('_'))
+}
+
+/// Whether a label definition is anonymous (`@@`, `+` or `-`).
+pub fn is_anonymous(name: &str) -> bool {
+    matches!(name, "@@" | "+" | "-")
+}
+
+/// Full name of a local label, e.g. `draw.loop` for `.loop` or `_loop` after `draw:`.
+pub fn qualify(scope: &str, local: &str) -> String {
+    format!("{}.{}", scope, &local[1..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn site(line: usize) -> Span {
+        Span {
+            file: "prog.asm".to_string(),
+            line,
+            columns: 0..1,
+            source_line: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_duplicate_definition_is_an_error() {
+        let mut table = SymbolTable::new();
+        table
+            .define("loop", SymbolKind::Label, 0x9D95, Some(&site(1)))
+            .unwrap();
+        // Expansions of a macro body share the body line as their site
+        assert!(table
+            .define("loop", SymbolKind::Label, 0x9D95, Some(&site(1)))
+            .is_err());
+
+        let error = table
+            .define("loop", SymbolKind::Label, 0x9DA0, Some(&site(5)))
+            .unwrap_err()
+            .downcast::<Diagnostic>()
+            .unwrap();
+        assert_eq!(error.message, "Duplicate definition of loop");
+        assert_eq!(error.notes, vec!["first defined at prog.asm:1"]);
+    }
+
+    #[test]
+    fn test_set_symbols_can_be_redefined() {
+        let mut table = SymbolTable::new();
+        table
+            .define("count", SymbolKind::Set, 1, Some(&site(1)))
+            .unwrap();
+        table
+            .define("count", SymbolKind::Set, 2, Some(&site(2)))
+            .unwrap();
+        assert_eq!(table.value("count"), Some(2));
+        assert!(table
+            .define("count", SymbolKind::Equate, 3, Some(&site(3)))
+            .is_err());
+    }
+
+    #[test]
+    fn test_local_names_resolve_in_scope() {
+        let mut table = SymbolTable::new();
+        table
+            .define("draw.loop", SymbolKind::Label, 0x9D95, Some(&site(2)))
+            .unwrap();
+        assert_eq!(
+            table.scoped_name(".loop", Some("draw")).unwrap(),
+            "draw.loop"
+        );
+        assert_eq!(
+            table.scoped_name("_loop", Some("draw")).unwrap(),
+            "draw.loop"
+        );
+        assert_eq!(table.scoped_name(".loop", Some("main")).unwrap(), ".loop");
+        assert_eq!(table.scoped_name("_PutS", Some("draw")).unwrap(), "_PutS");
+
+        table
+            .define("draw.PutS", SymbolKind::Label, 0x9DA0, Some(&site(3)))
+            .unwrap();
+        assert!(table.scoped_name("_PutS", Some("draw")).is_err());
+    }
+
+    #[test]
+    fn test_builtin_symbols_are_added_when_referenced() {
+        let mut table = SymbolTable::new();
+        assert_eq!(table.value("penCol"), Some(0x86D7));
+        assert!(table.is_empty());
+
+        table.add_reference("_PutS", &site(3));
+        table.add_reference("nowhere", &site(4));
+        let symbol = table.get("_PutS").unwrap();
+        assert_eq!(symbol.kind, SymbolKind::RomCall);
+        assert_eq!(symbol.references, vec![site(3)]);
+        assert_eq!(table.len(), 1);
+    }
+}
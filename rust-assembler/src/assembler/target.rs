@@ -0,0 +1,99 @@
+use crate::constants::{ASM_PRGM_HEADER, CE_PRGM_HEADER, TI83_PLUS_ORIGIN, TI84_PLUS_CE_ORIGIN};
+use crate::ti83plus;
+use crate::ti84pce;
+
+/// Calculator model the program is assembled for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Target {
+    #[default]
+    TI83Plus,
+    /// eZ80 in ADL mode, with 24-bit addresses and registers
+    TI84PlusCE,
+}
+
+impl Target {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "ti83plus" | "ti83p" | "83p" => Some(Target::TI83Plus),
+            "ti84pce" | "ti84plusce" | "ce" => Some(Target::TI84PlusCE),
+            _ => None,
+        }
+    }
+
+    /// Load address of assembly programs, used until the first `.org`
+    pub fn origin(self) -> u32 {
+        match self {
+            Target::TI83Plus => u32::from(TI83_PLUS_ORIGIN),
+            Target::TI84PlusCE => TI84_PLUS_CE_ORIGIN,
+        }
+    }
+
+    /// Bytes at the origin that mark the program as assembly
+    pub fn header(self) -> [u8; 2] {
+        match self {
+            Target::TI83Plus => ASM_PRGM_HEADER,
+            Target::TI84PlusCE => CE_PRGM_HEADER,
+        }
+    }
+
+    /// Address of the first instruction after the header
+    pub fn program_start(self) -> u32 {
+        self.origin() + self.header().len() as u32
+    }
+
+    /// Whether the CPU is an eZ80, which starts programs in ADL mode
+    pub fn is_ez80(self) -> bool {
+        self == Target::TI84PlusCE
+    }
+
+    /// Addresses wrap at 64K on the Z80 and at 16M on the eZ80
+    pub fn address_mask(self) -> u32 {
+        if self.is_ez80() {
+            0xFF_FFFF
+        } else {
+            0xFFFF
+        }
+    }
+
+    /// Variable type of programs in .8xp files
+    pub fn program_type(self) -> u8 {
+        match self {
+            Target::TI83Plus => 0x05,
+            // Assembly programs on the CE are protected so the editor will not open them
+            Target::TI84PlusCE => 0x06,
+        }
+    }
+
+    /// Address of a TI-OS ROM call such as `_PutS`
+    pub fn rom_call(self, name: &str) -> Option<u32> {
+        match self {
+            Target::TI83Plus => ti83plus::rom_calls::ROM_CALLS
+                .get(name)
+                .copied()
+                .map(u32::from),
+            Target::TI84PlusCE => ti84pce::rom_calls::ROM_CALLS.get(name).copied(),
+        }
+    }
+
+    /// Vector of a TI-OS restart such as `rBR_CALL`; the TI-84 Plus CE has none
+    pub fn restart(self, name: &str) -> Option<u32> {
+        match self {
+            Target::TI83Plus => ti83plus::rom_calls::RESTARTS
+                .get(name)
+                .copied()
+                .map(u32::from),
+            Target::TI84PlusCE => None,
+        }
+    }
+
+    /// Address of a TI-OS system variable such as `penCol`
+    pub fn sys_var(self, name: &str) -> Option<u32> {
+        match self {
+            Target::TI83Plus => ti83plus::sys_vars::SYS_VARS
+                .get(name)
+                .copied()
+                .map(u32::from),
+            Target::TI84PlusCE => ti84pce::sys_vars::SYS_VARS.get(name).copied(),
+        }
+    }
+}
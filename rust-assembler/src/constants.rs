@@ -13,10 +13,27 @@ pub const ASM_PRGM_HEADER: [u8; 2] = [0xBB, 0x6D];
 /// Maximum program name length for TI-83 Plus
 pub const MAX_PROGRAM_NAME_LENGTH: usize = 8;
 
+// TI-84 Plus CE specific constants
+/// Default origin address for TI-84 Plus CE programs, so that the code
+/// after the header starts at userMem ($D1A881)
+pub const TI84_PLUS_CE_ORIGIN: u32 = 0xD1A87F;
+
+/// Header bytes of eZ80 assembly programs for the TI-84 Plus CE
+pub const CE_PRGM_HEADER: [u8; 2] = [0xEF, 0x7B];
+
 // Z80 CPU specific constants
 /// RST 28h instruction for bcall
 pub const RST_28H: u8 = 0xEF;
 
+/// CALL nn instruction
+pub const CALL_NN: u8 = 0xCD;
+
+/// JP nn instruction
+pub const JP_NN: u8 = 0xC3;
+
+/// ROM routine that bjump calls to jump to the address after it
+pub const BJUMP_ADDRESS: u16 = 0x0050;
+
 /// Maximum displacement for indexed addressing (IX+d, IY+d)
 pub const MAX_INDEX_DISPLACEMENT: i8 = 127;
 pub const MIN_INDEX_DISPLACEMENT: i8 = -128;
@@ -71,3 +88,9 @@ pub const LCD_COMMAND_PORT: u8 = 0x10;
 
 /// LCD data port
 pub const LCD_DATA_PORT: u8 = 0x11;
+
+// Assembler limits
+/// Maximum nesting depth of macro expansions
+pub const MAX_MACRO_DEPTH: usize = 64;
+/// Maximum number of iterations of a `.rept`, `.for` or `.while` block
+pub const MAX_REPETITIONS: usize = 65536;
@@ -0,0 +1,246 @@
+//! Structured assembler diagnostics
+//!
+//! Every problem found while assembling is reported as a [`Diagnostic`]
+//! pointing at the offending source line. The assembler keeps going after
+//! an error so that a single run reports everything it can find.
+
+use std::fmt;
+use std::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    /// Informational output such as `.echo`
+    Note,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Note => write!(f, "note"),
+        }
+    }
+}
+
+/// Location of a diagnostic in the source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub file: String,
+    /// 1-based line number
+    pub line: usize,
+    /// 0-based character columns covered by the span
+    pub columns: Range<usize>,
+    /// Full text of the source line, used to render the snippet
+    pub source_line: String,
+}
+
+/// A single error or warning with its location and explanations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Option<Span>,
+    pub notes: Vec<String>,
+    pub help: Option<String>,
+    /// Further locations involved, such as the macro invocations that produced the line
+    pub related: Vec<Related>,
+}
+
+/// A secondary location shown below a diagnostic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Related {
+    pub message: String,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            message: message.into(),
+            span: None,
+            notes: Vec::new(),
+            help: None,
+            related: Vec::new(),
+        }
+    }
+
+    pub fn warning(message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..Diagnostic::error(message)
+        }
+    }
+
+    pub fn note(message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity: Severity::Note,
+            ..Diagnostic::error(message)
+        }
+    }
+
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn with_help(mut self, help: impl Into<String>) -> Self {
+        self.help = Some(help.into());
+        self
+    }
+
+    pub fn with_related(mut self, message: impl Into<String>, span: Span) -> Self {
+        self.related.push(Related {
+            message: message.into(),
+            span,
+        });
+        self
+    }
+
+    /// Convert a handler error into a diagnostic located at `span`.
+    ///
+    /// Handlers may return a `Diagnostic` directly to attach notes or help;
+    /// any other error becomes a plain error message.
+    pub fn from_error(error: anyhow::Error, span: &Span) -> Self {
+        let diagnostic = match error.downcast::<Diagnostic>() {
+            Ok(diagnostic) => diagnostic,
+            Err(error) => Diagnostic::error(error.to_string()),
+        };
+        if diagnostic.span.is_some() {
+            diagnostic
+        } else {
+            diagnostic.with_span(span.clone())
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+/// Renders the diagnostic in the style of rustc, with the source line and carets.
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}: {}", self.severity, self.message)?;
+
+        let gutter = match &self.span {
+            Some(span) => write_snippet(f, span)?,
+            None => String::new(),
+        };
+
+        for note in &self.notes {
+            writeln!(f, "{} = note: {}", gutter, note)?;
+        }
+        if let Some(help) = &self.help {
+            writeln!(f, "{} = help: {}", gutter, help)?;
+        }
+        for related in &self.related {
+            writeln!(f, "note: {}", related.message)?;
+            write_snippet(f, &related.span)?;
+        }
+        Ok(())
+    }
+}
+
+/// Write the location, source line and carets of a span, returning the gutter.
+fn write_snippet(f: &mut fmt::Formatter<'_>, span: &Span) -> Result<String, fmt::Error> {
+    let gutter = " ".repeat(span.line.to_string().len());
+    let source_line = span.source_line.replace('\t', " ");
+    let start = span.columns.start.min(source_line.chars().count());
+    let width = span.columns.len().max(1);
+    writeln!(
+        f,
+        "{}--> {}:{}:{}",
+        gutter,
+        span.file,
+        span.line,
+        span.columns.start + 1
+    )?;
+    writeln!(f, "{} |", gutter)?;
+    writeln!(f, "{} | {}", span.line, source_line)?;
+    writeln!(f, "{} | {}{}", gutter, " ".repeat(start), "^".repeat(width))?;
+    Ok(gutter)
+}
+
+/// All diagnostics from a failed assembly, in source order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembleError {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl AssembleError {
+    pub fn error_count(&self) -> usize {
+        self.diagnostics.iter().filter(|d| d.is_error()).count()
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for diagnostic in &self.diagnostics {
+            writeln!(f, "{}", diagnostic)?;
+        }
+        let count = self.error_count();
+        write!(
+            f,
+            "could not assemble due to {} error{}",
+            count,
+            if count == 1 { "" } else { "s" }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_snippet() {
+        let diagnostic = Diagnostic::error("Unknown instruction: foo a")
+            .with_span(Span {
+                file: "prog.asm".to_string(),
+                line: 12,
+                columns: 4..9,
+                source_line: "    foo a ; bad".to_string(),
+            })
+            .with_help("check the spelling of the mnemonic");
+
+        assert_eq!(
+            diagnostic.to_string(),
+            "error: Unknown instruction: foo a\n  \
+             --> prog.asm:12:5\n   \
+             |\n\
+             12 |     foo a ; bad\n   \
+             |     ^^^^^\n   \
+             = help: check the spelling of the mnemonic\n"
+        );
+    }
+
+    #[test]
+    fn test_from_error_keeps_help() {
+        let span = Span {
+            file: "prog.asm".to_string(),
+            line: 1,
+            columns: 0..3,
+            source_line: "nop".to_string(),
+        };
+        let error: anyhow::Error = Diagnostic::error("bad").with_help("fix it").into();
+        let diagnostic = Diagnostic::from_error(error, &span);
+        assert_eq!(diagnostic.help.as_deref(), Some("fix it"));
+        assert_eq!(diagnostic.span, Some(span.clone()));
+
+        let diagnostic = Diagnostic::from_error(anyhow::anyhow!("plain"), &span);
+        assert_eq!(diagnostic.message, "plain");
+    }
+}
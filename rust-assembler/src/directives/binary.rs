@@ -0,0 +1,71 @@
+use anyhow::{anyhow, Result};
+use std::fs;
+use std::path::PathBuf;
+
+use crate::assembler::context::Context;
+use crate::assembler::expr::Expr;
+use crate::assembler::operand::Operand;
+use crate::assembler::preprocessor::find_file;
+use crate::diagnostics::Diagnostic;
+
+/// Bytes of an `.incbin "file"[, offset[, length]]` directive.
+///
+/// The file is looked up like an `.include` from `from_file`. The offset and
+/// length decide the size of the line, so `ctx` must resolve them in pass one.
+pub fn handle_incbin(
+    operands: &[Operand],
+    from_file: &str,
+    include_paths: &[PathBuf],
+    ctx: &Context,
+) -> Result<Vec<u8>> {
+    let (file, offset, length) = match operands {
+        [file] => (file, None, None),
+        [file, offset] => (file, Some(offset), None),
+        [file, offset, length] => (file, Some(offset), Some(length)),
+        _ => return Err(anyhow!(".incbin requires a file name, offset and length")),
+    };
+    let Expr::Str(name) = file.expression()? else {
+        return Err(anyhow!(".incbin requires a quoted file name"));
+    };
+    let name = name.to_plain();
+
+    let path = find_file(&name, from_file, include_paths)?;
+    let data = fs::read(&path).map_err(|e| anyhow!("Cannot read \"{}\": {}", path.display(), e))?;
+
+    let known = |operand: Option<&Operand>| -> Result<Option<usize>> {
+        let Some(operand) = operand else {
+            return Ok(None);
+        };
+        let value = ctx.known_value(operand.expression()?)?;
+        usize::try_from(value)
+            .map(Some)
+            .map_err(|_| anyhow!(".incbin offset and length cannot be negative"))
+    };
+    let offset = known(offset)?.unwrap_or(0);
+    let length = known(length)?.unwrap_or(data.len().saturating_sub(offset));
+
+    if offset > data.len() {
+        return Err(Diagnostic::error(format!(
+            ".incbin offset {} is past the end of \"{}\"",
+            offset, name
+        ))
+        .with_note(format!("the file is {} bytes long", data.len()))
+        .into());
+    }
+    if length > data.len() - offset {
+        return Err(Diagnostic::error(format!(
+            ".incbin range {}..{} is past the end of \"{}\"",
+            offset,
+            offset + length,
+            name
+        ))
+        .with_note(format!("the file is {} bytes long", data.len()))
+        .with_help(format!(
+            "at most {} bytes can be read from offset {}",
+            data.len() - offset,
+            offset
+        ))
+        .into());
+    }
+    Ok(data[offset..offset + length].to_vec())
+}
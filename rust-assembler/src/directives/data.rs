@@ -1,109 +1,118 @@
-use crate::utils::immediate::parse_immediate;
-use anyhow::Result;
-use std::collections::HashMap;
+use crate::assembler::context::Context;
+use crate::assembler::expr::Expr;
+use crate::assembler::operand::Operand;
+use crate::ti83plus::float::encode_float_literal;
+use anyhow::{anyhow, Result};
 
 pub fn handle_data_directive(
     mnemonic: &str,
-    operands: Option<&str>,
-    labels: &HashMap<String, u16>,
-    constants: &HashMap<String, u16>,
+    operands: &[Operand],
+    ctx: &Context,
 ) -> Result<Option<Vec<u8>>> {
     let mut result = Vec::new();
 
     match mnemonic {
         ".db" => {
-            if let Some(ops) = operands {
-                let values = parse_db_operands(ops);
-                for value in values {
-                    if value.starts_with('"') && value.ends_with('"') {
-                        // String value
-                        let text = &value[1..value.len() - 1];
-                        let text = text
-                            .replace("\\n", "\n")
-                            .replace("\\r", "\r")
-                            .replace("\\t", "\t");
-                        for ch in text.chars() {
-                            result.push(ch as u8);
-                        }
-                    } else if let Some(&label_addr) = labels.get(value.as_str()) {
-                        result.push((label_addr & 0xff) as u8);
-                    } else {
-                        let byte_val = parse_immediate(&value, constants)?;
-                        result.push((byte_val & 0xff) as u8);
-                    }
-                }
-                return Ok(Some(result));
+            for operand in operands {
+                push_byte_or_string(&mut result, operand, ctx)?;
             }
         },
+        ".asciz" | ".asciiz" => {
+            // The terminator follows the last operand, so `"Hi",13` is one string
+            for operand in operands {
+                push_byte_or_string(&mut result, operand, ctx)?;
+            }
+            result.push(0);
+        },
         ".dw" => {
-            if let Some(ops) = operands {
-                let values: Vec<&str> = ops.split(',').map(|s| s.trim()).collect();
-                for value in values {
-                    let word = if let Some(&label_addr) = labels.get(value) {
-                        label_addr
-                    } else {
-                        parse_immediate(value, constants)?
-                    };
-                    result.push((word & 0xff) as u8);
-                    result.push(((word >> 8) & 0xff) as u8);
-                }
-                return Ok(Some(result));
+            for operand in operands {
+                let word = ctx.value(operand.expression()?)?;
+                result.push((word & 0xff) as u8);
+                result.push(((word >> 8) & 0xff) as u8);
+            }
+        },
+        ".dl" | ".dd" => {
+            // 24-bit eZ80 long words and 32-bit double words
+            let width = if mnemonic == ".dl" { 3 } else { 4 };
+            for operand in operands {
+                let value = ctx.eval(operand.expression()?)?;
+                result.extend_from_slice(&value.to_le_bytes()[..width]);
+            }
+        },
+        ".float" | ".dfp" => {
+            for operand in operands {
+                let Expr::Str(literal) = operand.expression()? else {
+                    return Err(anyhow!("{} requires decimal numbers", mnemonic));
+                };
+                result.extend(encode_float_literal(&literal.to_plain())?);
+            }
+        },
+        ".ds" | ".block" | ".fill" => {
+            let (count, fill) = match operands {
+                [count] => (count, None),
+                [count, fill] => (count, Some(fill)),
+                _ => {
+                    return Err(anyhow!(
+                        "{} requires a count and an optional fill value",
+                        mnemonic
+                    ))
+                },
+            };
+            let count = byte_count(mnemonic, ctx.known_value(count.expression()?)?)?;
+            result = vec![fill_byte(fill, ctx)?; count];
+        },
+        ".align" => {
+            let (boundary, fill) = match operands {
+                [boundary] => (boundary, None),
+                [boundary, fill] => (boundary, Some(fill)),
+                _ => {
+                    return Err(anyhow!(
+                        ".align requires a boundary and an optional fill value"
+                    ))
+                },
+            };
+            let boundary = ctx.known_value(boundary.expression()?)?;
+            if !(1..=0x10000).contains(&boundary) {
+                return Err(anyhow!(
+                    ".align boundary must be between 1 and 65536, found {}",
+                    boundary
+                ));
             }
+            // Computed from the current address in both passes, so it follows any shift
+            let address = i64::from(ctx.current_address);
+            let padding = (boundary - address % boundary) % boundary;
+            result = vec![fill_byte(fill, ctx)?; padding as usize];
         },
-        _ => {},
+        _ => return Ok(None),
     }
 
-    Ok(None)
+    Ok(Some(result))
 }
 
-pub fn estimate_data_size(mnemonic: &str, operands: &str) -> usize {
-    match mnemonic {
-        ".db" => {
-            let values = parse_db_operands(operands);
-            let mut count = 0;
-            for value in values {
-                if value.starts_with('"') && value.ends_with('"') {
-                    // String value - count characters
-                    let text = &value[1..value.len() - 1];
-                    let text = text
-                        .replace("\\n", "\n")
-                        .replace("\\r", "\r")
-                        .replace("\\t", "\t");
-                    count += text.len();
-                } else {
-                    // Single byte
-                    count += 1;
-                }
-            }
-            count
+fn push_byte_or_string(result: &mut Vec<u8>, operand: &Operand, ctx: &Context) -> Result<()> {
+    match operand.expression()? {
+        Expr::Str(text) => result.extend(text.encode(ctx.charset)?),
+        value => {
+            result.push(ctx.byte(value)?);
         },
-        ".dw" => operands.split(',').count() * 2,
-        _ => 0,
     }
+    Ok(())
 }
 
-fn parse_db_operands(operands: &str) -> Vec<String> {
-    let mut values = Vec::new();
-    let mut current = String::new();
-    let mut in_string = false;
-
-    for ch in operands.chars() {
-        match ch {
-            '"' => {
-                in_string = !in_string;
-                current.push(ch);
-            },
-            ',' if !in_string => {
-                values.push(current.trim().to_string());
-                current.clear();
-            },
-            _ => current.push(ch),
-        }
+fn fill_byte(fill: Option<&Operand>, ctx: &Context) -> Result<u8> {
+    match fill {
+        Some(fill) => ctx.byte(fill.expression()?),
+        None => Ok(0),
     }
+}
 
-    if !current.is_empty() {
-        values.push(current.trim().to_string());
+fn byte_count(mnemonic: &str, count: i64) -> Result<usize> {
+    if !(0..=0x10000).contains(&count) {
+        return Err(anyhow!(
+            "{} count must be between 0 and 65536, found {}",
+            mnemonic,
+            count
+        ));
     }
-
-    values
+    Ok(count as usize)
 }
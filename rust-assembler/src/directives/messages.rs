@@ -0,0 +1,49 @@
+use anyhow::{anyhow, Result};
+
+use crate::assembler::context::Context;
+use crate::assembler::expr::Expr;
+use crate::assembler::operand::Operand;
+use crate::diagnostics::Diagnostic;
+
+/// Text of an `.error`, `.warning` or `.echo` directive.
+///
+/// Strings are copied as they are and every other operand is replaced by
+/// its value, so `.echo "size: ", end - start` prints `size: 42`.
+pub fn format_message(operands: &[Operand], ctx: &Context) -> Result<String> {
+    let mut message = String::new();
+    for operand in operands {
+        match operand.expression()? {
+            Expr::Str(text) => message.push_str(&text.to_plain()),
+            value => message.push_str(&ctx.eval(value)?.to_string()),
+        }
+    }
+    Ok(message)
+}
+
+/// Check an `.assert expr[, "message"]` directive once addresses are final.
+pub fn check_assertion(operands: &[Operand], ctx: &Context) -> Result<()> {
+    let (condition, message) = match operands {
+        [condition] => (condition.expression()?, None),
+        [condition, message @ ..] => (condition.expression()?, Some(message)),
+        [] => return Err(anyhow!(".assert requires a condition")),
+    };
+    if ctx.eval(condition)? != 0 {
+        return Ok(());
+    }
+
+    let mut error = Diagnostic::error(match message {
+        Some(message) => format!("Assertion failed: {}", format_message(message, ctx)?),
+        None => "Assertion failed".to_string(),
+    })
+    .with_note(format!("condition: {}", condition));
+
+    // Show the values that made the condition false
+    let mut names = condition.symbols();
+    names.dedup();
+    for name in names {
+        if let Ok(value) = ctx.eval(&Expr::Symbol(name.to_string())) {
+            error = error.with_note(format!("{} = {} (${:04X})", name, value, value));
+        }
+    }
+    Err(error.into())
+}
@@ -1,3 +1,7 @@
+pub mod binary;
 pub mod data;
+pub mod messages;
 
-pub use data::{estimate_data_size, handle_data_directive};
+pub use binary::handle_incbin;
+pub use data::handle_data_directive;
+pub use messages::{check_assertion, format_message};
@@ -1,83 +1,87 @@
+use crate::assembler::context::{Context, Pass};
+use crate::assembler::expr::Expr;
+use crate::assembler::operand::{Operand, Reg16};
+use crate::constants::CB_PREFIX;
 use anyhow::{anyhow, Result};
 
 /// Handle CB prefix bit manipulation instructions
-pub fn handle_bit_instruction(mnemonic: &str, operands: Option<&str>) -> Result<Option<Vec<u8>>> {
-    let mut result = Vec::new();
-
-    if let Some(ops) = operands {
-        let parts: Vec<&str> = ops.split(',').map(|s| s.trim()).collect();
-
-        match mnemonic {
-            "bit" | "res" | "set" => {
-                if parts.len() != 2 {
-                    return Err(anyhow!("{} requires bit number and register", mnemonic));
-                }
-
-                let bit_num = parts[0]
-                    .parse::<u8>()
-                    .map_err(|_| anyhow!("Invalid bit number: {}", parts[0]))?;
-                if bit_num > 7 {
-                    return Err(anyhow!("Bit number must be 0-7"));
-                }
-
-                let reg_code = get_register_code(parts[1])?;
-
-                result.push(0xcb); // CB prefix
-
-                let opcode = match mnemonic {
-                    "bit" => 0x40 + (bit_num << 3) + reg_code,
-                    "res" => 0x80 + (bit_num << 3) + reg_code,
-                    "set" => 0xc0 + (bit_num << 3) + reg_code,
-                    _ => unreachable!(),
-                };
-                result.push(opcode);
-
-                return Ok(Some(result));
-            },
-
-            "rlc" | "rrc" | "rl" | "rr" | "sla" | "sra" | "srl" | "sll" => {
-                if parts.len() != 1 {
-                    return Err(anyhow!("{} requires one register operand", mnemonic));
-                }
-
-                let reg_code = get_register_code(parts[0])?;
+///
+/// On `(ix+d)` and `(iy+d)` the index prefix comes first and the
+/// displacement sits between CB and the opcode, as in `DD CB d 46`.
+pub fn handle_bit_instruction(
+    mnemonic: &str,
+    operands: &[Operand],
+    ctx: &Context,
+) -> Result<Option<Vec<u8>>> {
+    let (opcode, target) = match mnemonic {
+        "bit" | "res" | "set" => {
+            let [Operand::Immediate(bit), reg] = operands else {
+                return Err(anyhow!("{} requires bit number and register", mnemonic));
+            };
+            (bit_opcode(mnemonic, bit, ctx)?, reg)
+        },
+
+        // sll is undocumented and encoded with the other undocumented instructions
+        "rlc" | "rrc" | "rl" | "rr" | "sla" | "sra" | "srl" => {
+            let [reg] = operands else {
+                return Err(anyhow!("{} requires one register operand", mnemonic));
+            };
+            (shift_opcode(mnemonic), reg)
+        },
+
+        _ => return Ok(None),
+    };
+
+    Ok(Some(encode_cb(opcode, target, ctx)?))
+}
 
-                result.push(0xcb); // CB prefix
+/// Encode a CB instruction from its opcode for register 0 and its target.
+pub(crate) fn encode_cb(opcode: u8, target: &Operand, ctx: &Context) -> Result<Vec<u8>> {
+    let opcode = opcode + get_register_code(target)?;
+    let code = match target {
+        Operand::Indexed { reg, disp } => {
+            vec![reg.prefix(), CB_PREFIX, ctx.displacement(disp)?, opcode]
+        },
+        _ => vec![CB_PREFIX, opcode],
+    };
+    Ok(code)
+}
 
-                let opcode = match mnemonic {
-                    "rlc" => 0x00 + reg_code,
-                    "rrc" => 0x08 + reg_code,
-                    "rl" => 0x10 + reg_code,
-                    "rr" => 0x18 + reg_code,
-                    "sla" => 0x20 + reg_code,
-                    "sra" => 0x28 + reg_code,
-                    "sll" => 0x30 + reg_code, // Undocumented
-                    "srl" => 0x38 + reg_code,
-                    _ => unreachable!(),
-                };
-                result.push(opcode);
+/// Opcode of `bit`, `res` or `set` with the bit number filled in and register 0.
+pub(crate) fn bit_opcode(mnemonic: &str, bit: &Expr, ctx: &Context) -> Result<u8> {
+    // A bit defined further down is only known in pass two
+    let bit_num = ctx.value(bit)?;
+    if ctx.pass == Pass::Emit && bit_num > 7 {
+        return Err(anyhow!("Bit number must be 0-7"));
+    }
 
-                return Ok(Some(result));
-            },
+    let base = match mnemonic {
+        "bit" => 0x40,
+        "res" => 0x80,
+        _ => 0xc0,
+    };
+    Ok(base + ((bit_num as u8 & 7) << 3))
+}
 
-            _ => {},
-        }
+/// Opcode of a rotate or shift on register 0.
+pub(crate) fn shift_opcode(mnemonic: &str) -> u8 {
+    match mnemonic {
+        "rlc" => 0x00,
+        "rrc" => 0x08,
+        "rl" => 0x10,
+        "rr" => 0x18,
+        "sla" => 0x20,
+        "sra" => 0x28,
+        "sll" => 0x30,
+        _ => 0x38,
     }
-
-    Ok(None)
 }
 
 /// Get CB instruction register code (0-7)
-fn get_register_code(reg: &str) -> Result<u8> {
+fn get_register_code(reg: &Operand) -> Result<u8> {
     match reg {
-        "b" => Ok(0),
-        "c" => Ok(1),
-        "d" => Ok(2),
-        "e" => Ok(3),
-        "h" => Ok(4),
-        "l" => Ok(5),
-        "(hl)" => Ok(6),
-        "a" => Ok(7),
+        Operand::Reg8(reg) => Ok(reg.code()),
+        Operand::Indirect(Reg16::HL) | Operand::Indexed { .. } => Ok(6),
         _ => Err(anyhow!("Invalid register for CB instruction: {}", reg)),
     }
 }
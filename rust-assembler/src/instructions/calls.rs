@@ -1,71 +1,45 @@
-use crate::utils::immediate::parse_immediate;
+use crate::assembler::context::{Context, Pass};
+use crate::assembler::operand::Operand;
+use crate::diagnostics::Diagnostic;
 use anyhow::Result;
-use std::collections::HashMap;
 
+/// Handle CALL, CALL cc, RST and RET cc
+///
+/// RST takes one of the eight restart vectors, $00 to $38 in steps of $08,
+/// and encodes it in the opcode, so `rst 28h` is the single byte $EF.
 pub fn handle_call_instruction(
     mnemonic: &str,
-    operands: Option<&str>,
-    labels: &HashMap<String, u16>,
-    constants: &HashMap<String, u16>,
+    operands: &[Operand],
+    ctx: &Context,
 ) -> Result<Option<Vec<u8>>> {
     let mut result = Vec::new();
 
-    match mnemonic {
-        "call" => {
-            if let Some(ops) = operands {
-                // Handle conditional calls
-                let (condition, target) = if ops.contains(',') {
-                    let parts: Vec<&str> = ops.split(',').map(|s| s.trim()).collect();
-                    (Some(parts[0]), parts[1])
-                } else {
-                    (None, ops.trim())
-                };
-
-                let address = if let Some(&label_addr) = labels.get(target) {
-                    label_addr
-                } else {
-                    parse_immediate(target, constants)?
-                };
-
-                // Encode conditional call
-                match condition {
-                    Some("nz") => result.push(0xc4),
-                    Some("z") => result.push(0xcc),
-                    Some("nc") => result.push(0xd4),
-                    Some("c") => result.push(0xdc),
-                    Some("po") => result.push(0xe4),
-                    Some("pe") => result.push(0xec),
-                    Some("p") => result.push(0xf4),
-                    Some("m") => result.push(0xfc),
-                    None => result.push(0xcd), // Unconditional CALL
-                    _ => return Ok(None),
-                }
-
-                result.push((address & 0xff) as u8);
-                result.push(((address >> 8) & 0xff) as u8);
-                return Ok(Some(result));
-            }
+    match (mnemonic, operands) {
+        ("call", [Operand::Immediate(target)]) => {
+            result.push(0xcd); // Unconditional CALL
+            result.extend(ctx.word(target)?);
+        },
+        ("call", [Operand::Condition(condition), Operand::Immediate(target)]) => {
+            result.push(0xc4 | (condition.code() << 3));
+            result.extend(ctx.word(target)?);
         },
-        "ret" => {
-            if let Some(condition) = operands {
-                // Conditional returns
-                match condition.trim() {
-                    "nz" => result.push(0xc0),
-                    "z" => result.push(0xc8),
-                    "nc" => result.push(0xd0),
-                    "c" => result.push(0xd8),
-                    "po" => result.push(0xe0),
-                    "pe" => result.push(0xe8),
-                    "p" => result.push(0xf0),
-                    "m" => result.push(0xf8),
-                    _ => return Ok(None),
-                }
-                return Ok(Some(result));
+        ("rst", [Operand::Immediate(target)]) => {
+            let vector = ctx.value(target)?;
+            if ctx.pass == Pass::Emit && vector & !0x38 != 0 {
+                return Err(
+                    Diagnostic::error(format!("Invalid rst target: ${:02X}", vector))
+                        .with_help("rst can only call $00, $08, $10, $18, $20, $28, $30 or $38")
+                        .into(),
+                );
             }
-            // Unconditional RET handled by main opcode table
+            result.push(0xc7 | (vector & 0x38) as u8);
+        },
+        // Unconditional RET handled by main opcode table
+        ("ret", [Operand::Condition(condition)]) => {
+            result.push(0xc0 | (condition.code() << 3));
         },
-        _ => {},
+        _ => return Ok(None),
     }
 
-    Ok(None)
+    Ok(Some(result))
 }
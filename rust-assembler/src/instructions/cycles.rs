@@ -0,0 +1,197 @@
+use std::fmt;
+use std::ops::{Add, AddAssign};
+
+/// T-states taken by one instruction, or the total of several.
+///
+/// Conditional jumps, calls and returns, `djnz` and the repeating block
+/// instructions such as `ldir` take longer when the branch is taken or the
+/// instruction repeats. Every other instruction has the same count in both
+/// fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Cycles {
+    /// T-states when the branch is taken or the block instruction repeats
+    pub taken: u32,
+    /// T-states when the branch is not taken or the block instruction ends
+    pub not_taken: u32,
+}
+
+impl Cycles {
+    pub fn fixed(t_states: u32) -> Self {
+        Cycles {
+            taken: t_states,
+            not_taken: t_states,
+        }
+    }
+
+    pub fn branch(taken: u32, not_taken: u32) -> Self {
+        Cycles { taken, not_taken }
+    }
+
+    /// Whether the count depends on a branch or repetition.
+    pub fn is_conditional(self) -> bool {
+        self.taken != self.not_taken
+    }
+}
+
+impl Add for Cycles {
+    type Output = Cycles;
+
+    fn add(self, other: Cycles) -> Cycles {
+        Cycles {
+            taken: self.taken + other.taken,
+            not_taken: self.not_taken + other.not_taken,
+        }
+    }
+}
+
+impl AddAssign for Cycles {
+    fn add_assign(&mut self, other: Cycles) {
+        *self = *self + other;
+    }
+}
+
+/// `12/7` for a conditional count, otherwise the single count.
+impl fmt::Display for Cycles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_conditional() {
+            write!(f, "{}/{}", self.taken, self.not_taken)
+        } else {
+            write!(f, "{}", self.taken)
+        }
+    }
+}
+
+/// T-states of the Z80 instruction encoded in `code`.
+///
+/// The opcode is decoded from its fields, so every instruction the
+/// handlers emit is covered, including the undocumented ones. Counts are
+/// for the Z80 without memory wait states.
+pub fn instruction_cycles(code: &[u8]) -> Option<Cycles> {
+    let cycles = match code {
+        [] => return None,
+        [0xcb, opcode, ..] => Cycles::fixed(cb_cycles(*opcode)),
+        [0xed, opcode, ..] => ed_cycles(*opcode),
+        // BIT only reads (ix+d), the other DDCB instructions also write it back
+        [0xdd | 0xfd, 0xcb, _, opcode, ..] => Cycles::fixed(if opcode >> 6 == 1 { 20 } else { 23 }),
+        [0xdd | 0xfd, opcode, ..] => indexed_cycles(*opcode),
+        [opcode, ..] => main_cycles(*opcode),
+    };
+    Some(cycles)
+}
+
+/// Split an opcode into its `x`, `y` and `z` fields (bits 7-6, 5-3 and 2-0).
+fn fields(opcode: u8) -> (u8, u8, u8) {
+    (opcode >> 6, (opcode >> 3) & 7, opcode & 7)
+}
+
+fn main_cycles(opcode: u8) -> Cycles {
+    let (x, y, z) = fields(opcode);
+    let t_states = match (x, z) {
+        // NOP, EX AF,AF', DJNZ, JR and JR cc
+        (0, 0) => match y {
+            0 | 1 => 4,
+            2 => return Cycles::branch(13, 8),
+            3 => 12,
+            _ => return Cycles::branch(12, 7),
+        },
+        // LD rr,nn and ADD HL,rr
+        (0, 1) if y & 1 == 0 => 10,
+        (0, 1) => 11,
+        // Loads through (BC), (DE) and (nn)
+        (0, 2) => match y {
+            0..=3 => 7,
+            4 | 5 => 16,
+            _ => 13,
+        },
+        (0, 3) => 6,
+        // INC and DEC r, or (HL)
+        (0, 4 | 5) if y == 6 => 11,
+        (0, 4 | 5) => 4,
+        // LD r,n, or (HL),n
+        (0, 6) if y == 6 => 10,
+        (0, 6) => 7,
+        (0, 7) => 4,
+        // LD r,r' reads or writes memory when either is (HL); 0x76 is HALT
+        (1, _) if (y == 6) != (z == 6) => 7,
+        (2, 6) => 7,
+        (1 | 2, _) => 4,
+        (3, 0) => return Cycles::branch(11, 5),
+        // POP, RET, EXX, JP (HL) and LD SP,HL
+        (3, 1) => match y {
+            3 | 5 => 4,
+            7 => 6,
+            _ => 10,
+        },
+        (3, 2) => 10,
+        // JP nn, OUT (n),A, IN A,(n), EX (SP),HL, EX DE,HL, DI and EI
+        (3, 3) => match y {
+            0 => 10,
+            2 | 3 => 11,
+            4 => 19,
+            _ => 4,
+        },
+        (3, 4) => return Cycles::branch(17, 10),
+        // PUSH and CALL
+        (3, 5) if y & 1 == 0 => 11,
+        (3, 5) => 17,
+        (3, 6) => 7,
+        // RST
+        _ => 11,
+    };
+    Cycles::fixed(t_states)
+}
+
+fn cb_cycles(opcode: u8) -> u32 {
+    let (x, _, z) = fields(opcode);
+    match (x, z) {
+        (1, 6) => 12,
+        (_, 6) => 15,
+        _ => 8,
+    }
+}
+
+fn ed_cycles(opcode: u8) -> Cycles {
+    let (x, y, z) = fields(opcode);
+    let t_states = match (x, z) {
+        // IN r,(C) and OUT (C),r
+        (1, 0 | 1) => 12,
+        // SBC HL,rr and ADC HL,rr
+        (1, 2) => 15,
+        // LD (nn),rr and LD rr,(nn)
+        (1, 3) => 20,
+        // RETN and RETI
+        (1, 5) => 14,
+        // LD I,A, LD R,A, LD A,I, LD A,R, RRD and RLD
+        (1, 7) => match y {
+            0..=3 => 9,
+            4 | 5 => 18,
+            _ => 8,
+        },
+        // Block instructions; the repeating forms run 21 T-states per repeat
+        (2, 0..=3) if y >= 6 => return Cycles::branch(21, 16),
+        (2, 0..=3) if y >= 4 => 16,
+        // NEG, IM and the opcodes that act as NOP
+        _ => 8,
+    };
+    Cycles::fixed(t_states)
+}
+
+/// An unprefixed instruction with HL replaced by IX or IY.
+///
+/// The prefix adds 4 T-states, and reading the displacement and adding it
+/// to the index register adds 8 more to instructions on `(ix+d)`.
+fn indexed_cycles(opcode: u8) -> Cycles {
+    let (x, y, z) = fields(opcode);
+    let displacement = match (x, z) {
+        (0, 4..=6) => y == 6,
+        (1, _) => (y == 6) != (z == 6),
+        (2, _) => z == 6,
+        _ => false,
+    };
+    match opcode {
+        // LD (IX+d),n reads the value while the address is added
+        0x36 => Cycles::fixed(19),
+        _ if displacement => main_cycles(opcode) + Cycles::fixed(12),
+        _ => main_cycles(opcode) + Cycles::fixed(4),
+    }
+}
@@ -0,0 +1,148 @@
+use crate::assembler::context::Context;
+use crate::assembler::expr::{BinaryOp, Expr, UnaryOp};
+use crate::assembler::operand::{IndexReg, Operand, Reg16, Reg8, SpecialReg};
+use crate::constants::ED_PREFIX;
+use crate::instructions::loads::pair_code;
+use anyhow::Result;
+
+/// Handle the instructions the eZ80 adds to the Z80
+///
+/// LEA and PEA add a displacement to an index register without reading
+/// memory, MLT multiplies the two halves of a register pair and TST is an
+/// AND that only sets the flags. The new LD forms move whole register pairs
+/// through `(hl)` and `(ix+d)`.
+pub fn handle_ez80_instruction(
+    mnemonic: &str,
+    operands: &[Operand],
+    ctx: &Context,
+) -> Result<Option<Vec<u8>>> {
+    let code = match (mnemonic, operands) {
+        ("lea", [Operand::Reg16(dest), src]) => {
+            let Some((ix, disp)) = index_offset(src) else {
+                return Ok(None);
+            };
+            let opcode = match (dest, ix) {
+                (Reg16::BC | Reg16::DE | Reg16::HL, IndexReg::IX) => 0x02 | (pair_code(*dest) << 4),
+                (Reg16::BC | Reg16::DE | Reg16::HL, IndexReg::IY) => 0x03 | (pair_code(*dest) << 4),
+                (Reg16::IX, IndexReg::IX) => 0x32,
+                (Reg16::IY, IndexReg::IY) => 0x33,
+                (Reg16::IX, IndexReg::IY) => 0x54,
+                (Reg16::IY, IndexReg::IX) => 0x55,
+                _ => return Ok(None),
+            };
+            vec![ED_PREFIX, opcode, ctx.displacement(&disp)?]
+        },
+        ("pea", [src]) => {
+            let Some((ix, disp)) = index_offset(src) else {
+                return Ok(None);
+            };
+            let opcode = match ix {
+                IndexReg::IX => 0x65,
+                IndexReg::IY => 0x66,
+            };
+            vec![ED_PREFIX, opcode, ctx.displacement(&disp)?]
+        },
+        ("mlt", [Operand::Reg16(reg @ (Reg16::BC | Reg16::DE | Reg16::HL | Reg16::SP))]) => {
+            vec![ED_PREFIX, 0x4c | (pair_code(*reg) << 4)]
+        },
+        // TST A,s, where `a,` may be left out
+        ("tst", [Operand::Reg8(Reg8::A), src] | [src]) => match src {
+            Operand::Reg8(reg) => vec![ED_PREFIX, 0x04 | (reg.code() << 3)],
+            Operand::Indirect(Reg16::HL) => vec![ED_PREFIX, 0x34],
+            Operand::Immediate(expr) => vec![ED_PREFIX, 0x64, ctx.byte(expr)?],
+            _ => return Ok(None),
+        },
+        ("ld", [dest, src]) => return handle_ez80_load(dest, src, ctx),
+        _ => return Ok(None),
+    };
+
+    Ok(Some(code))
+}
+
+fn handle_ez80_load(dest: &Operand, src: &Operand, ctx: &Context) -> Result<Option<Vec<u8>>> {
+    let code = match (dest, src) {
+        // LD rr,(HL) and LD (HL),rr
+        (
+            Operand::Reg16(reg @ (Reg16::BC | Reg16::DE | Reg16::HL)),
+            Operand::Indirect(Reg16::HL),
+        ) => {
+            vec![ED_PREFIX, 0x07 | (pair_code(*reg) << 4)]
+        },
+        (Operand::Reg16(Reg16::IX), Operand::Indirect(Reg16::HL)) => vec![ED_PREFIX, 0x37],
+        (Operand::Reg16(Reg16::IY), Operand::Indirect(Reg16::HL)) => vec![ED_PREFIX, 0x31],
+        (
+            Operand::Indirect(Reg16::HL),
+            Operand::Reg16(reg @ (Reg16::BC | Reg16::DE | Reg16::HL)),
+        ) => {
+            vec![ED_PREFIX, 0x0f | (pair_code(*reg) << 4)]
+        },
+        (Operand::Indirect(Reg16::HL), Operand::Reg16(Reg16::IX)) => vec![ED_PREFIX, 0x3f],
+        (Operand::Indirect(Reg16::HL), Operand::Reg16(Reg16::IY)) => vec![ED_PREFIX, 0x3e],
+        // LD rr,(IX+d) and LD (IX+d),rr
+        (Operand::Reg16(reg), Operand::Indexed { reg: ix, disp }) => {
+            let opcode = match reg {
+                Reg16::BC | Reg16::DE | Reg16::HL => 0x07 | (pair_code(*reg) << 4),
+                // The same index register as the base, or the other one
+                _ if reg.index() == Some(*ix) => 0x37,
+                Reg16::IX | Reg16::IY => 0x31,
+                _ => return Ok(None),
+            };
+            vec![ix.prefix(), opcode, ctx.displacement(disp)?]
+        },
+        (Operand::Indexed { reg: ix, disp }, Operand::Reg16(reg)) => {
+            let opcode = match reg {
+                Reg16::BC | Reg16::DE | Reg16::HL => 0x0f | (pair_code(*reg) << 4),
+                _ if reg.index() == Some(*ix) => 0x3f,
+                Reg16::IX | Reg16::IY => 0x3e,
+                _ => return Ok(None),
+            };
+            vec![ix.prefix(), opcode, ctx.displacement(disp)?]
+        },
+        (Operand::Reg8(Reg8::A), Operand::Special(SpecialReg::MB)) => vec![ED_PREFIX, 0x6e],
+        (Operand::Special(SpecialReg::MB), Operand::Reg8(Reg8::A)) => vec![ED_PREFIX, 0x6d],
+        (Operand::Special(SpecialReg::I), Operand::Reg16(Reg16::HL)) => vec![ED_PREFIX, 0xc7],
+        (Operand::Reg16(Reg16::HL), Operand::Special(SpecialReg::I)) => vec![ED_PREFIX, 0xd7],
+        _ => return Ok(None),
+    };
+
+    Ok(Some(code))
+}
+
+/// The prefix byte of an instruction suffix and whether its immediates are 24-bit.
+///
+/// The first letter sets the data width (short or long) and the last the
+/// width of immediate values, as in `ld.sis hl,$1234` in ADL mode.
+pub fn suffix_prefix(suffix: &str) -> Option<(u8, bool)> {
+    match suffix {
+        "sis" => Some((0x40, false)),
+        "lis" => Some((0x49, false)),
+        "sil" => Some((0x52, true)),
+        "lil" => Some((0x5b, true)),
+        _ => None,
+    }
+}
+
+/// The index register and displacement of an `ix+d` operand, written
+/// without parentheses because no memory is read.
+fn index_offset(operand: &Operand) -> Option<(IndexReg, Expr)> {
+    match operand {
+        Operand::Reg16(reg) => reg.index().map(|ix| (ix, Expr::Number(0))),
+        Operand::Indexed { reg, disp } => Some((*reg, disp.clone())),
+        Operand::Immediate(Expr::Binary(op @ (BinaryOp::Add | BinaryOp::Sub), base, disp)) => {
+            let Expr::Symbol(name) = base.as_ref() else {
+                return None;
+            };
+            let ix = match name.to_lowercase().as_str() {
+                "ix" => IndexReg::IX,
+                "iy" => IndexReg::IY,
+                _ => return None,
+            };
+            let disp = match op {
+                BinaryOp::Add => disp.as_ref().clone(),
+                _ => Expr::Unary(UnaryOp::Neg, disp.clone()),
+            };
+            Some((ix, disp))
+        },
+        _ => None,
+    }
+}
@@ -1,6 +1,7 @@
-use crate::utils::immediate::parse_immediate;
-use anyhow::{anyhow, Result};
-use std::collections::HashMap;
+use crate::assembler::context::Context;
+use crate::assembler::expr::Expr;
+use crate::assembler::operand::{IndexReg, Operand, Reg16, Reg8};
+use anyhow::Result;
 
 /// Handle IX/IY indexed operations like LD A,(IX+d)
 ///
@@ -9,316 +10,130 @@ use std::collections::HashMap;
 /// Programs should PUSH IX before use and POP IX after.
 pub fn handle_index_instruction(
     mnemonic: &str,
-    operands: Option<&str>,
-    labels: &HashMap<String, u16>,
-    constants: &HashMap<String, u16>,
+    operands: &[Operand],
+    ctx: &Context,
 ) -> Result<Option<Vec<u8>>> {
-    let mut result = Vec::new();
-
-    if let Some(ops) = operands {
-        if mnemonic == "push" && ops == "ix" {
-            result.push(0xdd);
-            result.push(0xe5);
-            return Ok(Some(result));
-        } else if mnemonic == "push" && ops == "iy" {
-            result.push(0xfd);
-            result.push(0xe5);
-            return Ok(Some(result));
-        } else if mnemonic == "pop" && ops == "ix" {
-            result.push(0xdd);
-            result.push(0xe1);
-            return Ok(Some(result));
-        } else if mnemonic == "pop" && ops == "iy" {
-            result.push(0xfd);
-            result.push(0xe1);
-            return Ok(Some(result));
-        }
-
-        if mnemonic == "ld" {
-            if ops.starts_with("ix,") {
-                let value_str = ops[3..].trim();
-                if !value_str.starts_with('(') {
-                    let value = if let Some(&label_addr) = labels.get(value_str) {
-                        label_addr
-                    } else {
-                        parse_immediate(value_str, constants)?
-                    };
-                    result.push(0xdd);
-                    result.push(0x21);
-                    result.push((value & 0xff) as u8);
-                    result.push(((value >> 8) & 0xff) as u8);
-                    return Ok(Some(result));
-                }
-            } else if ops.starts_with("iy,") {
-                let value_str = ops[3..].trim();
-                if !value_str.starts_with('(') {
-                    // LD IY,nn
-                    let value = if let Some(&label_addr) = labels.get(value_str) {
-                        label_addr
-                    } else {
-                        parse_immediate(value_str, constants)?
-                    };
-                    result.push(0xfd);
-                    result.push(0x21);
-                    result.push((value & 0xff) as u8);
-                    result.push(((value >> 8) & 0xff) as u8);
-                    return Ok(Some(result));
-                }
-            }
-
-            if ops.contains("(ix+") || ops.contains("(ix-") || ops.contains("(ix)") {
-                return handle_ix_indexed_load(ops, constants);
-            } else if ops.contains("(iy+") || ops.contains("(iy-") || ops.contains("(iy)") {
-                return handle_iy_indexed_load(ops, constants);
-            }
-
-            if ops.starts_with('(') && ops.contains("),ix") {
-                let addr_str = &ops[1..ops.find(')').unwrap()];
-                let address = parse_immediate(addr_str, constants)?;
-                result.push(0xdd); // IX prefix
-                result.push(0x22); // LD (nn),HL opcode
-                result.push((address & 0xff) as u8);
-                result.push(((address >> 8) & 0xff) as u8);
-                return Ok(Some(result));
-            } else if ops.starts_with('(') && ops.contains("),iy") {
-                let addr_str = &ops[1..ops.find(')').unwrap()];
-                let address = parse_immediate(addr_str, constants)?;
-                result.push(0xfd); // IY prefix
-                result.push(0x22); // LD (nn),HL opcode
-                result.push((address & 0xff) as u8);
-                result.push(((address >> 8) & 0xff) as u8);
-                return Ok(Some(result));
-            }
-
-            if ops.starts_with("ix,(") && ops.ends_with(')') {
-                let addr_str = &ops[4..ops.len() - 1];
-                let address = parse_immediate(addr_str, constants)?;
-                result.push(0xdd); // IX prefix
-                result.push(0x2a); // LD HL,(nn) opcode
-                result.push((address & 0xff) as u8);
-                result.push(((address >> 8) & 0xff) as u8);
-                return Ok(Some(result));
-            } else if ops.starts_with("iy,(") && ops.ends_with(')') {
-                let addr_str = &ops[4..ops.len() - 1];
-                let address = parse_immediate(addr_str, constants)?;
-                result.push(0xfd); // IY prefix
-                result.push(0x2a); // LD HL,(nn) opcode
-                result.push((address & 0xff) as u8);
-                result.push(((address >> 8) & 0xff) as u8);
-                return Ok(Some(result));
-            }
-        }
-
-        if mnemonic == "inc" && ops == "ix" {
-            result.push(0xdd); // IX prefix
-            result.push(0x23); // INC HL opcode
-            return Ok(Some(result));
-        } else if mnemonic == "inc" && ops == "iy" {
-            result.push(0xfd); // IY prefix
-            result.push(0x23); // INC HL opcode
-            return Ok(Some(result));
-        } else if mnemonic == "dec" && ops == "ix" {
-            result.push(0xdd); // IX prefix
-            result.push(0x2b); // DEC HL opcode
-            return Ok(Some(result));
-        } else if mnemonic == "dec" && ops == "iy" {
-            result.push(0xfd); // IY prefix
-            result.push(0x2b); // DEC HL opcode
-            return Ok(Some(result));
-        }
+    // ALU A,(IX+d), where `a,` may be left out
+    if let (
+        Some(alu),
+        [Operand::Reg8(Reg8::A), Operand::Indexed { reg, disp }] | [Operand::Indexed { reg, disp }],
+    ) = (alu_code(mnemonic), operands)
+    {
+        return Ok(Some(indexed(*reg, 0x86 | (alu << 3), disp, ctx)?));
+    }
 
-        if mnemonic == "add" {
-            if ops.starts_with("ix,") {
-                let reg = ops[3..].trim();
-                let opcode = match reg {
-                    "bc" => 0x09,
-                    "de" => 0x19,
-                    "ix" => 0x29,
-                    "sp" => 0x39,
-                    _ => return Ok(None),
-                };
-                result.push(0xdd); // IX prefix
-                result.push(opcode);
-                return Ok(Some(result));
-            } else if ops.starts_with("iy,") {
-                let reg = ops[3..].trim();
-                let opcode = match reg {
-                    "bc" => 0x09,
-                    "de" => 0x19,
-                    "iy" => 0x29,
-                    "sp" => 0x39,
+    let code = match (mnemonic, operands) {
+        // INC/DEC (HL) opcodes with the index prefix and displacement
+        ("inc", [Operand::Indexed { reg, disp }]) => Some(indexed(*reg, 0x34, disp, ctx)?),
+        ("dec", [Operand::Indexed { reg, disp }]) => Some(indexed(*reg, 0x35, disp, ctx)?),
+        ("push", [op]) => index_reg(op).map(|ix| vec![ix.prefix(), 0xe5]),
+        ("pop", [op]) => index_reg(op).map(|ix| vec![ix.prefix(), 0xe1]),
+        // INC/DEC HL opcodes with the index prefix
+        ("inc", [op]) => index_reg(op).map(|ix| vec![ix.prefix(), 0x23]),
+        ("dec", [op]) => index_reg(op).map(|ix| vec![ix.prefix(), 0x2b]),
+        // JP (HL) opcode with the index prefix
+        (
+            "jp",
+            [Operand::Indexed {
+                reg,
+                disp: Expr::Number(0),
+            }],
+        ) => Some(vec![reg.prefix(), 0xe9]),
+        // EX (SP),HL opcode with the index prefix
+        ("ex", [Operand::Indirect(Reg16::SP), op]) => {
+            index_reg(op).map(|ix| vec![ix.prefix(), 0xe3])
+        },
+        ("add", [dest, src]) => match index_reg(dest) {
+            Some(ix) => {
+                let opcode = match src {
+                    Operand::Reg16(Reg16::BC) => 0x09,
+                    Operand::Reg16(Reg16::DE) => 0x19,
+                    Operand::Reg16(Reg16::SP) => 0x39,
+                    op if index_reg(op) == Some(ix) => 0x29,
                     _ => return Ok(None),
                 };
-                result.push(0xfd); // IY prefix
-                result.push(opcode);
-                return Ok(Some(result));
-            }
-        }
-
-        if mnemonic == "ex" {
-            if ops == "(sp),ix" {
-                result.push(0xdd); // IX prefix
-                result.push(0xe3); // EX (SP),HL opcode
-                return Ok(Some(result));
-            } else if ops == "(sp),iy" {
-                result.push(0xfd); // IY prefix
-                result.push(0xe3); // EX (SP),HL opcode
-                return Ok(Some(result));
-            }
-        }
-
-        if mnemonic == "jp" {
-            if ops == "(ix)" {
-                result.push(0xdd); // IX prefix
-                result.push(0xe9); // JP (HL) opcode
-                return Ok(Some(result));
-            } else if ops == "(iy)" {
-                result.push(0xfd); // IY prefix
-                result.push(0xe9); // JP (HL) opcode
-                return Ok(Some(result));
-            }
-        }
-
-        if mnemonic == "ld" && ops == "sp,ix" {
-            result.push(0xdd); // IX prefix
-            result.push(0xf9); // LD SP,HL opcode
-            return Ok(Some(result));
-        } else if mnemonic == "ld" && ops == "sp,iy" {
-            result.push(0xfd); // IY prefix
-            result.push(0xf9); // LD SP,HL opcode
-            return Ok(Some(result));
-        }
-    }
+                Some(vec![ix.prefix(), opcode])
+            },
+            None => None,
+        },
+        ("ld", [dest, src]) => handle_index_load(dest, src, ctx)?,
+        _ => None,
+    };
 
-    Ok(None)
+    Ok(code)
 }
 
-fn handle_ix_indexed_load(ops: &str, constants: &HashMap<String, u16>) -> Result<Option<Vec<u8>>> {
-    let mut result = Vec::new();
-
-    // Parse the displacement from (IX+d) or (IX-d)
-    let (reg, displacement) = parse_indexed_operand(ops, "ix", constants)?;
-
-    result.push(0xdd); // IX prefix
-
-    // Determine the opcode based on the operation
-    if let Some(dest_reg) = reg {
-        if ops.starts_with(&format!("{},(ix", dest_reg)) {
-            // LD r,(IX+d)
-            let opcode = match dest_reg.as_str() {
-                "a" => 0x7e,
-                "b" => 0x46,
-                "c" => 0x4e,
-                "d" => 0x56,
-                "e" => 0x5e,
-                "h" => 0x66,
-                "l" => 0x6e,
-                _ => return Ok(None),
-            };
-            result.push(opcode);
-        } else if ops.contains(&format!("),{}", dest_reg)) {
-            // LD (IX+d),r
-            let opcode = match dest_reg.as_str() {
-                "a" => 0x77,
-                "b" => 0x70,
-                "c" => 0x71,
-                "d" => 0x72,
-                "e" => 0x73,
-                "h" => 0x74,
-                "l" => 0x75,
-                _ => return Ok(None),
-            };
-            result.push(opcode);
-        }
+fn handle_index_load(dest: &Operand, src: &Operand, ctx: &Context) -> Result<Option<Vec<u8>>> {
+    if let Some(ix) = index_reg(dest) {
+        let code = match src {
+            // LD IX,nn
+            Operand::Immediate(expr) => with_address(ix, 0x21, expr, ctx)?,
+            // LD IX,(nn) uses the LD HL,(nn) opcode
+            Operand::Address(expr) => with_address(ix, 0x2a, expr, ctx)?,
+            _ => return Ok(None),
+        };
+        return Ok(Some(code));
     }
 
-    result.push(displacement as u8);
-    Ok(Some(result))
-}
-
-fn handle_iy_indexed_load(ops: &str, constants: &HashMap<String, u16>) -> Result<Option<Vec<u8>>> {
-    let mut result = Vec::new();
-
-    // Parse the displacement from (IY+d) or (IY-d)
-    let (reg, displacement) = parse_indexed_operand(ops, "iy", constants)?;
-
-    result.push(0xfd); // IY prefix
-
-    // Determine the opcode based on the operation
-    if let Some(dest_reg) = reg {
-        if ops.starts_with(&format!("{},(iy", dest_reg)) {
-            // LD r,(IY+d)
-            let opcode = match dest_reg.as_str() {
-                "a" => 0x7e,
-                "b" => 0x46,
-                "c" => 0x4e,
-                "d" => 0x56,
-                "e" => 0x5e,
-                "h" => 0x66,
-                "l" => 0x6e,
-                _ => return Ok(None),
-            };
-            result.push(opcode);
-        } else if ops.contains(&format!("),{}", dest_reg)) {
-            // LD (IY+d),r
-            let opcode = match dest_reg.as_str() {
-                "a" => 0x77,
-                "b" => 0x70,
-                "c" => 0x71,
-                "d" => 0x72,
-                "e" => 0x73,
-                "h" => 0x74,
-                "l" => 0x75,
-                _ => return Ok(None),
-            };
-            result.push(opcode);
-        }
+    if let Some(ix) = index_reg(src) {
+        let code = match dest {
+            // LD (nn),IX uses the LD (nn),HL opcode
+            Operand::Address(expr) => with_address(ix, 0x22, expr, ctx)?,
+            // LD SP,IX uses the LD SP,HL opcode
+            Operand::Reg16(Reg16::SP) => vec![ix.prefix(), 0xf9],
+            _ => return Ok(None),
+        };
+        return Ok(Some(code));
     }
 
-    result.push(displacement as u8);
-    Ok(Some(result))
-}
+    let code = match (dest, src) {
+        // LD r,(IX+d)
+        (Operand::Reg8(reg), Operand::Indexed { reg: ix, disp }) => {
+            indexed(*ix, 0x46 | (reg.code() << 3), disp, ctx)?
+        },
+        // LD (IX+d),r
+        (Operand::Indexed { reg: ix, disp }, Operand::Reg8(reg)) => {
+            indexed(*ix, 0x70 | reg.code(), disp, ctx)?
+        },
+        // LD (IX+d),n has the value after the displacement
+        (Operand::Indexed { reg: ix, disp }, Operand::Immediate(expr)) => {
+            let mut code = indexed(*ix, 0x36, disp, ctx)?;
+            code.push(ctx.byte(expr)?);
+            code
+        },
+        _ => return Ok(None),
+    };
 
-fn parse_indexed_operand(
-    ops: &str,
-    index_reg: &str,
-    constants: &HashMap<String, u16>,
-) -> Result<(Option<String>, i8)> {
-    // Extract register and displacement from patterns like "a,(ix+5)" or "(iy-3),b"
-    let parts: Vec<&str> = ops.split(',').collect();
-    if parts.len() != 2 {
-        return Err(anyhow!("Invalid indexed operand format"));
-    }
+    Ok(Some(code))
+}
 
-    let (reg, indexed) = if parts[0].contains(index_reg) {
-        (Some(parts[1].trim().to_string()), parts[0])
-    } else {
-        (Some(parts[0].trim().to_string()), parts[1])
+/// The 3-bit operation field of the 8-bit arithmetic and logic instructions.
+pub(crate) fn alu_code(mnemonic: &str) -> Option<u8> {
+    let code = match mnemonic {
+        "add" => 0,
+        "adc" => 1,
+        "sub" => 2,
+        "sbc" => 3,
+        "and" => 4,
+        "xor" => 5,
+        "or" => 6,
+        "cp" => 7,
+        _ => return None,
     };
+    Some(code)
+}
 
-    // Extract displacement from (IX+d) or (IY+d)
-    let pattern = format!("({}+", index_reg);
-    let pattern_neg = format!("({}-", index_reg);
+fn index_reg(operand: &Operand) -> Option<IndexReg> {
+    match operand {
+        Operand::Reg16(reg) => reg.index(),
+        _ => None,
+    }
+}
 
-    let displacement = if let Some(pos) = indexed.find(&pattern) {
-        let start = pos + pattern.len();
-        let end = indexed[start..]
-            .find(')')
-            .ok_or_else(|| anyhow!("Missing closing parenthesis"))?;
-        let disp_str = &indexed[start..start + end];
-        parse_immediate(disp_str, constants)? as i8
-    } else if let Some(pos) = indexed.find(&pattern_neg) {
-        let start = pos + pattern_neg.len();
-        let end = indexed[start..]
-            .find(')')
-            .ok_or_else(|| anyhow!("Missing closing parenthesis"))?;
-        let disp_str = &indexed[start..start + end];
-        -(parse_immediate(disp_str, constants)? as i8)
-    } else if indexed.contains(&format!("({})", index_reg)) {
-        0i8
-    } else {
-        return Err(anyhow!("Invalid indexed addressing format"));
-    };
+fn with_address(ix: IndexReg, opcode: u8, address: &Expr, ctx: &Context) -> Result<Vec<u8>> {
+    Ok([vec![ix.prefix(), opcode], ctx.word(address)?].concat())
+}
 
-    Ok((reg, displacement))
+fn indexed(ix: IndexReg, opcode: u8, disp: &Expr, ctx: &Context) -> Result<Vec<u8>> {
+    Ok(vec![ix.prefix(), opcode, ctx.displacement(disp)?])
 }
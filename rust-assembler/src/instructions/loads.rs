@@ -1,159 +1,60 @@
-use crate::assembler::parser::Parser;
+use crate::assembler::context::Context;
+use crate::assembler::expr::Expr;
+use crate::assembler::operand::{Operand, Reg16, Reg8};
+use crate::constants::ED_PREFIX;
 use crate::instructions::opcodes::REG_LOAD_IMMEDIATE;
-use crate::ti83plus::sys_vars::SYS_VARS;
-use crate::utils::immediate::parse_immediate;
 use anyhow::Result;
-use std::collections::HashMap;
 
-pub fn handle_load_instruction(
-    operands: &str,
-    labels: &HashMap<String, u16>,
-    constants: &HashMap<String, u16>,
-    parser: &Parser,
-) -> Result<Option<Vec<u8>>> {
-    let mut result = Vec::new();
-    let parts = parser.split_operands(operands);
-
-    if parts.len() != 2 {
+pub fn handle_load_instruction(operands: &[Operand], ctx: &Context) -> Result<Option<Vec<u8>>> {
+    let [dest, src] = operands else {
         return Ok(None);
-    }
-
-    let dest = &parts[0];
-    let src = &parts[1];
-
-    // LD r,n (8-bit immediate) - but only if source is not a register
-    if let Some(&opcode) = REG_LOAD_IMMEDIATE.get(dest.as_str()) {
-        if !src.starts_with('(') && !is_register(src) {
-            result.push(opcode);
-            let value = if let Some(&label_addr) = labels.get(src.as_str()) {
-                label_addr
-            } else {
-                parse_immediate(src, constants)?
-            };
-            result.push((value & 0xff) as u8);
-            return Ok(Some(result));
-        }
-    }
-
-    // LD HL,(nn)
-    if dest == "hl" && src.starts_with('(') && src.ends_with(')') {
-        let addr_str = &src[1..src.len() - 1];
-        let address = if let Some(&sys_var) = SYS_VARS.get(addr_str) {
-            sys_var
-        } else {
-            parse_immediate(addr_str, constants)?
-        };
-        result.push(0x2a); // LD HL,(nn)
-        result.push((address & 0xff) as u8);
-        result.push(((address >> 8) & 0xff) as u8);
-        return Ok(Some(result));
-    }
-
-    // LD HL,nn (16-bit immediate)
-    if dest == "hl" {
-        let value = if let Some(&label_addr) = labels.get(src.as_str()) {
-            label_addr
-        } else if let Some(&sys_var) = SYS_VARS.get(src.as_str()) {
-            sys_var
-        } else {
-            parse_immediate(src, constants)?
-        };
-        result.push(0x21); // LD HL,nn
-        result.push((value & 0xff) as u8);
-        result.push(((value >> 8) & 0xff) as u8);
-        return Ok(Some(result));
-    }
-
-    // LD BC,nn
-    if dest == "bc" {
-        let value = if let Some(&label_addr) = labels.get(src.as_str()) {
-            label_addr
-        } else {
-            parse_immediate(src, constants)?
-        };
-        result.push(0x01);
-        result.push((value & 0xff) as u8);
-        result.push(((value >> 8) & 0xff) as u8);
-        return Ok(Some(result));
-    }
-
-    // LD DE,nn
-    if dest == "de" {
-        let value = if let Some(&label_addr) = labels.get(src.as_str()) {
-            label_addr
-        } else {
-            parse_immediate(src, constants)?
-        };
-        result.push(0x11);
-        result.push((value & 0xff) as u8);
-        result.push(((value >> 8) & 0xff) as u8);
-        return Ok(Some(result));
-    }
-
-    // LD SP,nn
-    if dest == "sp" {
-        let value = if let Some(&label_addr) = labels.get(src.as_str()) {
-            label_addr
-        } else {
-            parse_immediate(src, constants)?
-        };
-        result.push(0x31);
-        result.push((value & 0xff) as u8);
-        result.push(((value >> 8) & 0xff) as u8);
-        return Ok(Some(result));
-    }
-
-    // LD (nn),HL
-    if dest.starts_with('(') && dest.ends_with(')') && src == "hl" {
-        let addr_str = &dest[1..dest.len() - 1];
-        let address = if let Some(&sys_var) = SYS_VARS.get(addr_str) {
-            sys_var
-        } else {
-            parse_immediate(addr_str, constants)?
-        };
-        result.push(0x22); // LD (nn),HL
-        result.push((address & 0xff) as u8);
-        result.push(((address >> 8) & 0xff) as u8);
-        return Ok(Some(result));
-    }
-
-    // LD A,(nn)
-    if dest == "a" && src.starts_with('(') && src.ends_with(')') {
-        let inner = &src[1..src.len() - 1];
-        // Check if it's a register pair first
-        if !["bc", "de", "hl"].contains(&inner) {
-            let address = if let Some(&sys_var) = SYS_VARS.get(inner) {
-                sys_var
-            } else {
-                parse_immediate(inner, constants)?
-            };
-            result.push(0x3a); // LD A,(nn)
-            result.push((address & 0xff) as u8);
-            result.push(((address >> 8) & 0xff) as u8);
-            return Ok(Some(result));
-        }
-    }
+    };
+
+    let code = match (dest, src) {
+        // LD r,n (8-bit immediate)
+        (Operand::Reg8(_) | Operand::Indirect(Reg16::HL), Operand::Immediate(expr)) => {
+            let opcode = REG_LOAD_IMMEDIATE[dest.to_string().as_str()];
+            vec![opcode, ctx.byte(expr)?]
+        },
+        // LD HL,(nn)
+        (Operand::Reg16(Reg16::HL), Operand::Address(expr)) => with_address(0x2a, expr, ctx)?,
+        // LD HL,nn
+        (Operand::Reg16(Reg16::HL), Operand::Immediate(expr)) => with_address(0x21, expr, ctx)?,
+        // LD BC,nn / LD DE,nn / LD SP,nn
+        (Operand::Reg16(Reg16::BC), Operand::Immediate(expr)) => with_address(0x01, expr, ctx)?,
+        (Operand::Reg16(Reg16::DE), Operand::Immediate(expr)) => with_address(0x11, expr, ctx)?,
+        (Operand::Reg16(Reg16::SP), Operand::Immediate(expr)) => with_address(0x31, expr, ctx)?,
+        // LD (nn),HL
+        (Operand::Address(expr), Operand::Reg16(Reg16::HL)) => with_address(0x22, expr, ctx)?,
+        // LD BC/DE/SP,(nn) and LD (nn),BC/DE/SP have an ED prefix
+        (Operand::Reg16(reg @ (Reg16::BC | Reg16::DE | Reg16::SP)), Operand::Address(expr)) => {
+            let code = with_address(0x4b | (pair_code(*reg) << 4), expr, ctx)?;
+            [vec![ED_PREFIX], code].concat()
+        },
+        (Operand::Address(expr), Operand::Reg16(reg @ (Reg16::BC | Reg16::DE | Reg16::SP))) => {
+            let code = with_address(0x43 | (pair_code(*reg) << 4), expr, ctx)?;
+            [vec![ED_PREFIX], code].concat()
+        },
+        // LD A,(nn)
+        (Operand::Reg8(Reg8::A), Operand::Address(expr)) => with_address(0x3a, expr, ctx)?,
+        // LD (nn),A
+        (Operand::Address(expr), Operand::Reg8(Reg8::A)) => with_address(0x32, expr, ctx)?,
+        _ => return Ok(None), // Not handled here
+    };
+
+    Ok(Some(code))
+}
 
-    // LD (nn),A
-    if dest.starts_with('(') && dest.ends_with(')') && src == "a" {
-        let addr_str = &dest[1..dest.len() - 1];
-        // Check if it's a register pair first
-        if !["bc", "de", "hl"].contains(&addr_str.as_ref()) {
-            let address = if let Some(&sys_var) = SYS_VARS.get(addr_str) {
-                sys_var
-            } else {
-                parse_immediate(addr_str, constants)?
-            };
-            result.push(0x32); // LD (nn),A
-            result.push((address & 0xff) as u8);
-            result.push(((address >> 8) & 0xff) as u8);
-            return Ok(Some(result));
-        }
+/// The `rr` field of BC, DE, HL or SP in 16-bit opcodes.
+pub(crate) fn pair_code(reg: Reg16) -> u8 {
+    match reg {
+        Reg16::BC => 0,
+        Reg16::DE => 1,
+        Reg16::HL => 2,
+        _ => 3,
     }
-
-    Ok(None) // Not handled here
 }
 
-fn is_register(s: &str) -> bool {
-    matches!(s, "a" | "b" | "c" | "d" | "e" | "h" | "l" | "(hl)")
+fn with_address(opcode: u8, address: &Expr, ctx: &Context) -> Result<Vec<u8>> {
+    Ok([vec![opcode], ctx.word(address)?].concat())
 }
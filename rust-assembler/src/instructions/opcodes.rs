@@ -71,6 +71,11 @@ pub static OPCODES: phf::Map<&'static str, u32> = phf_map! {
     "ld a,(de)" => 0x1a,
     "ld (bc),a" => 0x02,
     "ld (de),a" => 0x12,
+    "ld sp,hl" => 0xf9,
+    "ld i,a" => 0xed47,
+    "ld a,i" => 0xed57,
+    "ld r,a" => 0xed4f,
+    "ld a,r" => 0xed5f,
 
     // Basic instructions
     "nop" => 0x00,
@@ -78,6 +83,7 @@ pub static OPCODES: phf::Map<&'static str, u32> = phf_map! {
     "ret" => 0xc9,
     "reti" => 0xed4d,
     "retn" => 0xed45,
+    "jp (hl)" => 0xe9,
 
     // Stack operations
     "push af" => 0xf5,
@@ -201,6 +207,14 @@ pub static OPCODES: phf::Map<&'static str, u32> = phf_map! {
     "add hl,de" => 0x19,
     "add hl,hl" => 0x29,
     "add hl,sp" => 0x39,
+    "adc hl,bc" => 0xed4a,
+    "adc hl,de" => 0xed5a,
+    "adc hl,hl" => 0xed6a,
+    "adc hl,sp" => 0xed7a,
+    "sbc hl,bc" => 0xed42,
+    "sbc hl,de" => 0xed52,
+    "sbc hl,hl" => 0xed62,
+    "sbc hl,sp" => 0xed72,
 
     // Exchange
     "ex de,hl" => 0xeb,
@@ -234,6 +248,8 @@ pub static OPCODES: phf::Map<&'static str, u32> = phf_map! {
     "im 0" => 0xed46,
     "im 1" => 0xed56,
     "im 2" => 0xed5e,
+    "rld" => 0xed6f,
+    "rrd" => 0xed67,
 };
 
 // 8-bit register load immediate opcodes
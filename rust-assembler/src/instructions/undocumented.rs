@@ -0,0 +1,131 @@
+use crate::assembler::context::Context;
+use crate::assembler::expr::Expr;
+use crate::assembler::operand::{IndexReg, Operand, Reg8};
+use crate::constants::ED_PREFIX;
+use crate::diagnostics::Diagnostic;
+use crate::instructions::bitops::{bit_opcode, encode_cb, shift_opcode};
+use crate::instructions::index::alu_code;
+use anyhow::Result;
+
+/// Handle the undocumented Z80 instructions
+///
+/// These work on every TI-83 Plus, but are not part of the official
+/// instruction set, so the caller warns about them unless they are enabled.
+/// They are the halves of the index registers (`ld a,ixh`), `sll`,
+/// `in f,(c)`, `out (c),0`, and rotates, `res` and `set` on `(ix+d)` that
+/// also copy the result into a register (`res 7,(ix+5),a`).
+pub fn handle_undocumented_instruction(
+    mnemonic: &str,
+    operands: &[Operand],
+    ctx: &Context,
+) -> Result<Option<Vec<u8>>> {
+    if operands
+        .iter()
+        .any(|op| matches!(op, Operand::IndexHalf { .. }))
+    {
+        return handle_half_instruction(mnemonic, operands, ctx);
+    }
+
+    let code = match (mnemonic, operands) {
+        ("sll", [target]) => encode_cb(shift_opcode(mnemonic), target, ctx)?,
+        // DDCB rotates and shifts with a register to copy the result into
+        (
+            "rlc" | "rrc" | "rl" | "rr" | "sla" | "sra" | "sll" | "srl",
+            [target @ Operand::Indexed { .. }, Operand::Reg8(copy)],
+        ) => with_copy(shift_opcode(mnemonic), target, *copy, ctx)?,
+        (
+            "res" | "set",
+            [Operand::Immediate(bit), target @ Operand::Indexed { .. }, Operand::Reg8(copy)],
+        ) => with_copy(bit_opcode(mnemonic, bit, ctx)?, target, *copy, ctx)?,
+        // IN F,(C) only sets the flags
+        ("in", [Operand::PortC]) => vec![ED_PREFIX, 0x70],
+        ("in", [Operand::Immediate(Expr::Symbol(flags)), Operand::PortC])
+            if flags.eq_ignore_ascii_case("f") =>
+        {
+            vec![ED_PREFIX, 0x70]
+        },
+        ("out", [Operand::PortC, Operand::Immediate(Expr::Number(0))]) => vec![ED_PREFIX, 0x71],
+        _ => return Ok(None),
+    };
+
+    Ok(Some(code))
+}
+
+/// Instructions on `ixh`, `ixl`, `iyh` and `iyl`, which are the `h` and `l`
+/// forms with an index prefix.
+fn handle_half_instruction(
+    mnemonic: &str,
+    operands: &[Operand],
+    ctx: &Context,
+) -> Result<Option<Vec<u8>>> {
+    if let (Some(alu), [Operand::Reg8(Reg8::A), src] | [src]) = (alu_code(mnemonic), operands) {
+        if let Some((Some(ix), code)) = byte_reg(src) {
+            return Ok(Some(vec![ix.prefix(), 0x80 | (alu << 3) | code]));
+        }
+    }
+
+    let code = match (mnemonic, operands) {
+        ("inc", [op]) => half(op).map(|(ix, code)| vec![ix.prefix(), 0x04 | (code << 3)]),
+        ("dec", [op]) => half(op).map(|(ix, code)| vec![ix.prefix(), 0x05 | (code << 3)]),
+        ("ld", [dest, Operand::Immediate(expr)]) => match half(dest) {
+            Some((ix, code)) => {
+                let value = ctx.byte(expr)?;
+                Some(vec![ix.prefix(), 0x06 | (code << 3), value])
+            },
+            None => None,
+        },
+        ("ld", [dest, src]) => {
+            let (Some((dest_ix, dest_code)), Some((src_ix, src_code))) =
+                (byte_reg(dest), byte_reg(src))
+            else {
+                return Ok(None);
+            };
+            let mixed = match (dest_ix, src_ix) {
+                (Some(dest_ix), Some(src_ix)) => dest_ix != src_ix,
+                _ => uses_hl(dest) || uses_hl(src),
+            };
+            if mixed {
+                return Err(Diagnostic::error(format!("Cannot load {} into {}", src, dest))
+                    .with_note("after an index prefix, h and l stand for the halves of that index register")
+                    .into());
+            }
+            dest_ix
+                .or(src_ix)
+                .map(|ix| vec![ix.prefix(), 0x40 | (dest_code << 3) | src_code])
+        },
+        _ => None,
+    };
+
+    Ok(code)
+}
+
+/// Encode a DDCB instruction that also copies the result into `copy`.
+///
+/// The register takes the place of the `(hl)` code 6 in the last byte.
+fn with_copy(opcode: u8, target: &Operand, copy: Reg8, ctx: &Context) -> Result<Vec<u8>> {
+    let mut code = encode_cb(opcode, target, ctx)?;
+    if let Some(last) = code.last_mut() {
+        *last = (*last & !0x07) | copy.code();
+    }
+    Ok(code)
+}
+
+/// The index register and `r` field code of an index register half.
+fn half(operand: &Operand) -> Option<(IndexReg, u8)> {
+    match operand {
+        Operand::IndexHalf { reg, high } => Some((*reg, if *high { 4 } else { 5 })),
+        _ => None,
+    }
+}
+
+/// The `r` field code of an 8-bit register, with the index register of a half.
+fn byte_reg(operand: &Operand) -> Option<(Option<IndexReg>, u8)> {
+    match operand {
+        Operand::Reg8(reg) => Some((None, reg.code())),
+        _ => half(operand).map(|(ix, code)| (Some(ix), code)),
+    }
+}
+
+fn uses_hl(operand: &Operand) -> bool {
+    matches!(operand, Operand::Reg8(Reg8::H | Reg8::L))
+}
@@ -1,20 +1,27 @@
 //! Z80 Assembler for TI-83 Plus Calculator Programs
 //!
 //! This crate provides a complete Z80 assembler implementation
-//! specifically designed for creating TI-83 Plus calculator programs.
+//! specifically designed for creating TI-83 Plus calculator programs,
+//! with an eZ80 mode for the TI-84 Plus CE.
 //!
 //! # Features
 //! - Full Z80 instruction set support
 //! - TI-83 Plus specific ROM calls
 //! - Label and constant support
+//! - rustc-style diagnostics reporting every error in one run
 //! - Generates valid .8xp files
 
 pub mod assembler;
 pub mod constants;
+pub mod diagnostics;
 pub mod directives;
 pub mod instructions;
 pub mod ti83plus;
-pub mod utils;
+pub mod ti84pce;
 
-pub use assembler::Z80Assembler;
-pub use ti83plus::TI8XPGenerator;
+pub use assembler::{
+    AssemblyResult, Dialect, LineRecord, Symbol, SymbolKind, SymbolTable, Target, Z80Assembler,
+};
+pub use diagnostics::{AssembleError, Diagnostic, Severity, Span};
+pub use instructions::Cycles;
+pub use ti83plus::{Charset, TI8XPGenerator};
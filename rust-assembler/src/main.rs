@@ -2,8 +2,9 @@ use anyhow::Result;
 use clap::Parser as ClapParser;
 use std::fs;
 use std::path::PathBuf;
+use std::process;
 
-use z80asm::{TI8XPGenerator, Z80Assembler};
+use z80asm::{Dialect, TI8XPGenerator, Target, Z80Assembler};
 
 #[derive(ClapParser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -17,6 +18,44 @@ struct Args {
     /// Program name (defaults to input filename, max 8 chars)
     #[arg(short, long)]
     name: Option<String>,
+
+    /// Directory to search for included files (can be repeated)
+    #[arg(short = 'I', long = "include", value_name = "DIR")]
+    include_paths: Vec<PathBuf>,
+
+    /// Define a name for #ifdef and #if, as NAME or NAME=value (can be repeated)
+    #[arg(short = 'D', long = "define", value_name = "NAME[=VALUE]")]
+    defines: Vec<String>,
+
+    /// Also accept the syntax of another assembler: spasm, tasm or brass
+    #[arg(long, value_name = "NAME", value_parser = parse_dialect, default_value = "native")]
+    dialect: Dialect,
+
+    /// Calculator to assemble for: ti83plus, or ti84pce for the eZ80 TI-84 Plus CE
+    #[arg(long, value_name = "MODEL", value_parser = parse_target, default_value = "ti83plus")]
+    target: Target,
+
+    /// Allow undocumented instructions such as sll and ld a,ixh without warnings
+    #[arg(long)]
+    undocumented: bool,
+
+    /// Write a listing with the address, bytes and T-states of each line
+    #[arg(short, long, value_name = "FILE")]
+    listing: Option<PathBuf>,
+}
+
+fn parse_dialect(name: &str) -> Result<Dialect, String> {
+    Dialect::from_name(name).ok_or_else(|| {
+        format!(
+            "unknown dialect {}, expected native, spasm, tasm or brass",
+            name
+        )
+    })
+}
+
+fn parse_target(name: &str) -> Result<Target, String> {
+    Target::from_name(name)
+        .ok_or_else(|| format!("unknown target {}, expected ti83plus or ti84pce", name))
 }
 
 fn main() -> Result<()> {
@@ -49,21 +88,49 @@ fn main() -> Result<()> {
     let source = fs::read_to_string(&args.input)?;
 
     // Create assembler instance
-    let mut assembler = Z80Assembler::new();
+    let mut assembler = Z80Assembler::new()
+        .with_file_name(args.input.display().to_string())
+        .with_dialect(args.dialect)
+        .with_target(args.target)
+        .with_undocumented(args.undocumented);
+    for path in args.include_paths {
+        assembler = assembler.with_include_path(path);
+    }
+    for define in args.defines {
+        let (name, value) = define.split_once('=').unwrap_or((&define, "1"));
+        assembler = assembler.with_define(name, value);
+    }
 
-    // Add TI-83 Plus header if not present
-    let processed_source = if !source.contains(".org") {
-        format!(".org $9D93\n.db $BB,$6D\n{}", source)
-    } else {
-        source.clone()
-    };
+    // Add the assembly program header if not present. The header bytes are added
+    // to the output rather than the source so that line numbers in errors stay correct.
+    let add_header = !source.contains(".org");
+    if add_header {
+        assembler = assembler.with_origin(args.target.program_start());
+    }
 
     // Assemble the code
-    let code = assembler.assemble(&processed_source)?;
+    let result = match assembler.assemble(&source) {
+        Ok(result) => result,
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(1);
+        },
+    };
+    for diagnostic in &result.warnings {
+        eprintln!("{}", diagnostic);
+    }
+    if let Some(path) = &args.listing {
+        fs::write(path, result.listing())?;
+    }
+    let code = if add_header {
+        [args.target.header().as_slice(), &result.bytes].concat()
+    } else {
+        result.bytes
+    };
     println!("✓ Assembled {} bytes", code.len());
 
     // Generate .8xp file
-    let output = TI8XPGenerator::create_8xp(&program_name, &code);
+    let output = TI8XPGenerator::create_8xp_for(args.target, &program_name, &code);
     let output_size = output.len();
 
     // Write output file
@@ -78,7 +145,11 @@ fn main() -> Result<()> {
     println!("\nTo test:");
     println!("1. Visit https://www.cemetech.net/projects/jstified/");
     println!("2. Drag {} onto the calculator", output_file.display());
-    println!("3. Run with: Asm(prgm{})", program_name);
+    if args.target.is_ez80() {
+        println!("3. Run with: prgm{}", program_name);
+    } else {
+        println!("3. Run with: Asm(prgm{})", program_name);
+    }
 
     Ok(())
 }
@@ -0,0 +1,185 @@
+use anyhow::{anyhow, Result};
+use phf::phf_map;
+
+use crate::diagnostics::Diagnostic;
+
+/// Encoding of string literals in `.db` and `.asciz`, chosen with `.charset`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Charset {
+    /// Every character is its own byte value; only `\0` to `\xFF` can be encoded
+    #[default]
+    Ascii,
+    /// The TI-83 Plus large and small fonts used by `_PutS` and `_VPutS`
+    Ti,
+}
+
+impl Charset {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "ascii" => Some(Charset::Ascii),
+            "ti" | "ti83plus" => Some(Charset::Ti),
+            _ => None,
+        }
+    }
+
+    /// Encode text, failing on the first character this charset cannot represent.
+    pub fn encode(self, text: &str) -> Result<Vec<u8>> {
+        text.chars().map(|ch| self.encode_char(ch)).collect()
+    }
+
+    /// Encode a single character, as in a `'x'` character constant.
+    pub fn encode_char(self, ch: char) -> Result<u8> {
+        match self {
+            Charset::Ascii => u8::try_from(ch).map_err(|_| {
+                Diagnostic::error(format!("Character '{}' has no single-byte code", ch))
+                    .with_help("use .charset ti to encode text for the calculator fonts")
+                    .into()
+            }),
+            Charset::Ti => {
+                if let Some(&code) = TI_FONT.get(&ch) {
+                    Ok(code)
+                } else if ch.is_ascii() {
+                    // Control characters stay raw bytes so `\0` still terminates strings
+                    Ok(ch as u8)
+                } else {
+                    Err(anyhow!(
+                        "Character '{}' (U+{:04X}) is not in the TI-83 Plus font",
+                        ch,
+                        ch as u32
+                    ))
+                }
+            },
+        }
+    }
+}
+
+/// TI-83 Plus font codes for characters that are not at their ASCII position.
+pub static TI_FONT: phf::Map<char, u8> = phf_map! {
+    // Math symbols
+    '▶' => 0x05,
+    '►' => 0x05,
+    '∫' => 0x08,
+    '×' => 0x09,
+    '□' => 0x0A,
+    '⁺' => 0x0B,
+    '·' => 0x0C,
+    '³' => 0x0E,
+    '√' => 0x10,
+    '²' => 0x12,
+    '∠' => 0x13,
+    '°' => 0x14,
+    'ʳ' => 0x15,
+    'ᵀ' => 0x16,
+    '≤' => 0x17,
+    '≠' => 0x18,
+    '≥' => 0x19,
+    '⁻' => 0x1A,
+    'ᴇ' => 0x1B,
+    '→' => 0x1C,
+    '↑' => 0x1E,
+    '↓' => 0x1F,
+    // θ takes the place of [, which moves to 0xC1
+    'θ' => 0x5B,
+    '[' => 0xC1,
+    // Subscript digits
+    '₀' => 0x80,
+    '₁' => 0x81,
+    '₂' => 0x82,
+    '₃' => 0x83,
+    '₄' => 0x84,
+    '₅' => 0x85,
+    '₆' => 0x86,
+    '₇' => 0x87,
+    '₈' => 0x88,
+    '₉' => 0x89,
+    // Accented letters
+    'Á' => 0x8A,
+    'À' => 0x8B,
+    'Â' => 0x8C,
+    'Ä' => 0x8D,
+    'á' => 0x8E,
+    'à' => 0x8F,
+    'â' => 0x90,
+    'ä' => 0x91,
+    'É' => 0x92,
+    'È' => 0x93,
+    'Ê' => 0x94,
+    'Ë' => 0x95,
+    'é' => 0x96,
+    'è' => 0x97,
+    'ê' => 0x98,
+    'ë' => 0x99,
+    'Í' => 0x9A,
+    'Ì' => 0x9B,
+    'Î' => 0x9C,
+    'Ï' => 0x9D,
+    'í' => 0x9E,
+    'ì' => 0x9F,
+    'î' => 0xA0,
+    'ï' => 0xA1,
+    'Ó' => 0xA2,
+    'Ò' => 0xA3,
+    'Ô' => 0xA4,
+    'Ö' => 0xA5,
+    'ó' => 0xA6,
+    'ò' => 0xA7,
+    'ô' => 0xA8,
+    'ö' => 0xA9,
+    'Ú' => 0xAA,
+    'Ù' => 0xAB,
+    'Û' => 0xAC,
+    'Ü' => 0xAD,
+    'ú' => 0xAE,
+    'ù' => 0xAF,
+    'û' => 0xB0,
+    'ü' => 0xB1,
+    'Ç' => 0xB2,
+    'ç' => 0xB3,
+    'Ñ' => 0xB4,
+    'ñ' => 0xB5,
+    '´' => 0xB6,
+    '¨' => 0xB8,
+    '¿' => 0xB9,
+    '¡' => 0xBA,
+    // Greek letters
+    'α' => 0xBB,
+    'β' => 0xBC,
+    'γ' => 0xBD,
+    'Δ' => 0xBE,
+    'δ' => 0xBF,
+    'ε' => 0xC0,
+    'λ' => 0xC2,
+    'μ' => 0xC3,
+    'π' => 0xC4,
+    'ρ' => 0xC5,
+    'Σ' => 0xC6,
+    'σ' => 0xC7,
+    'τ' => 0xC8,
+    'φ' => 0xC9,
+    'Ω' => 0xCA,
+    // Punctuation and boxes
+    '…' => 0xCE,
+    '◀' => 0xCF,
+    '◄' => 0xCF,
+    '■' => 0xD0,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_ti_font() {
+        assert_eq!(
+            Charset::Ti.encode("θ→x²≤[é]\0").unwrap(),
+            vec![0x5B, 0x1C, b'x', 0x12, 0x17, 0xC1, 0x96, b']', 0]
+        );
+        assert!(Charset::Ti.encode("日").is_err());
+    }
+
+    #[test]
+    fn test_encode_ascii() {
+        assert_eq!(Charset::Ascii.encode("A\u{FF}").unwrap(), vec![0x41, 0xFF]);
+        assert!(Charset::Ascii.encode("θ").is_err());
+    }
+}
@@ -0,0 +1,199 @@
+//! TI-83 Plus floating-point numbers
+//!
+//! A real number is 9 bytes: a sign and type byte, an exponent biased by
+//! $80, and 14 BCD mantissa digits. Complex numbers are two such numbers,
+//! the real part followed by the imaginary part, both with the complex type.
+
+use anyhow::{anyhow, Result};
+
+use crate::diagnostics::Diagnostic;
+
+/// Object type of a real number in the first byte
+pub const REAL_OBJ: u8 = 0x00;
+/// Object type of each half of a complex number
+pub const CPLX_OBJ: u8 = 0x0C;
+
+const MANTISSA_DIGITS: usize = 14;
+const MAX_EXPONENT: i64 = 99;
+
+/// A decimal number normalized to the calculator's precision.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TiFloat {
+    pub negative: bool,
+    /// Power of ten of the first mantissa digit
+    pub exponent: i64,
+    /// 14 decimal digits, the first one non-zero unless the number is zero
+    pub digits: [u8; MANTISSA_DIGITS],
+}
+
+impl TiFloat {
+    /// Parse a decimal literal such as `3.14159` or `-2.5e-3` without going
+    /// through binary floating point. Extra digits are rounded half up.
+    pub fn parse(text: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid floating-point number: {}", text);
+        let (negative, unsigned) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text.strip_prefix('+').unwrap_or(text)),
+        };
+        let (mantissa, exponent) = match unsigned.split_once(['e', 'E']) {
+            Some((mantissa, exponent)) => {
+                (mantissa, exponent.parse::<i64>().map_err(|_| invalid())?)
+            },
+            None => (unsigned, 0),
+        };
+        let (integer, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        let all_digits = integer.chars().chain(fraction.chars());
+        if integer.len() + fraction.len() == 0 || !all_digits.clone().all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+
+        let digits: Vec<u8> = all_digits.map(|c| c as u8 - b'0').collect();
+        let Some(first) = digits.iter().position(|&d| d != 0) else {
+            return Ok(TiFloat::zero());
+        };
+        // Position of the first significant digit relative to the decimal point
+        let mut exponent = integer.len() as i64 - 1 - first as i64 + exponent;
+        let mut mantissa = [0; MANTISSA_DIGITS];
+        for (slot, &digit) in mantissa.iter_mut().zip(&digits[first..]) {
+            *slot = digit;
+        }
+
+        if digits.get(first + MANTISSA_DIGITS).is_some_and(|&d| d >= 5) {
+            // Round up, carrying into a new leading digit when every digit is 9
+            let mut index = MANTISSA_DIGITS;
+            loop {
+                if index == 0 {
+                    mantissa = [0; MANTISSA_DIGITS];
+                    mantissa[0] = 1;
+                    exponent += 1;
+                    break;
+                }
+                index -= 1;
+                if mantissa[index] == 9 {
+                    mantissa[index] = 0;
+                } else {
+                    mantissa[index] += 1;
+                    break;
+                }
+            }
+        }
+
+        if !(-MAX_EXPONENT..=MAX_EXPONENT).contains(&exponent) {
+            return Err(Diagnostic::error(format!(
+                "{} is outside the range of TI floating-point numbers",
+                text
+            ))
+            .with_note("non-zero values must have a magnitude from 1E-99 to 9.9999999999999E99")
+            .into());
+        }
+        Ok(TiFloat {
+            negative,
+            exponent,
+            digits: mantissa,
+        })
+    }
+
+    pub fn zero() -> Self {
+        TiFloat {
+            negative: false,
+            exponent: 0,
+            digits: [0; MANTISSA_DIGITS],
+        }
+    }
+
+    /// The 9 bytes of the number with the given object type.
+    pub fn encode(&self, object_type: u8) -> [u8; 9] {
+        let mut bytes = [0; 9];
+        bytes[0] = object_type | if self.negative { 0x80 } else { 0 };
+        bytes[1] = (0x80 + self.exponent) as u8;
+        for (byte, pair) in bytes[2..].iter_mut().zip(self.digits.chunks(2)) {
+            *byte = pair[0] << 4 | pair[1];
+        }
+        bytes
+    }
+}
+
+/// Encode a real literal, or a complex one such as `3-4i` or `2.5i`.
+pub fn encode_float_literal(text: &str) -> Result<Vec<u8>> {
+    let text: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    let Some(complex) = text.strip_suffix('i') else {
+        return Ok(TiFloat::parse(&text)?.encode(REAL_OBJ).to_vec());
+    };
+
+    // The imaginary part starts at the last sign that is not part of an exponent
+    let split = complex
+        .char_indices()
+        .rev()
+        .find(|&(index, c)| {
+            matches!(c, '+' | '-') && index > 0 && !complex[..index].ends_with(['e', 'E'])
+        })
+        .map(|(index, _)| index);
+    let (real, imaginary) = match split {
+        Some(index) => (&complex[..index], &complex[index..]),
+        None => ("0", complex),
+    };
+    let imaginary = match imaginary {
+        "" | "+" => "1",
+        "-" => "-1",
+        other => other,
+    };
+
+    let mut bytes = TiFloat::parse(real)?.encode(CPLX_OBJ).to_vec();
+    bytes.extend(TiFloat::parse(imaginary)?.encode(CPLX_OBJ));
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_reals() {
+        assert_eq!(
+            encode_float_literal("3.14159").unwrap(),
+            vec![0x00, 0x80, 0x31, 0x41, 0x59, 0x00, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(
+            encode_float_literal("-2.5e-3").unwrap(),
+            vec![0x80, 0x7D, 0x25, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(
+            encode_float_literal("0.000").unwrap(),
+            vec![0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(
+            encode_float_literal("1E99").unwrap()[..3],
+            [0x00, 0xE3, 0x10]
+        );
+    }
+
+    #[test]
+    fn test_rounding_to_fourteen_digits() {
+        let pi = TiFloat::parse("3.14159265358979323846").unwrap();
+        assert_eq!(pi.digits, [3, 1, 4, 1, 5, 9, 2, 6, 5, 3, 5, 8, 9, 8]);
+        let carried = TiFloat::parse("99999999999999.5").unwrap();
+        assert_eq!(carried.exponent, 14);
+        assert_eq!(carried.digits[..2], [1, 0]);
+    }
+
+    #[test]
+    fn test_encode_complex() {
+        let bytes = encode_float_literal("3-4i").unwrap();
+        assert_eq!(bytes.len(), 18);
+        assert_eq!(bytes[..3], [0x0C, 0x80, 0x30]);
+        assert_eq!(bytes[9..12], [0x8C, 0x80, 0x40]);
+
+        let bytes = encode_float_literal("1e-5i").unwrap();
+        assert_eq!(bytes[..2], [0x0C, 0x80]);
+        assert_eq!(bytes[9..12], [0x0C, 0x7B, 0x10]);
+    }
+
+    #[test]
+    fn test_out_of_range_and_invalid() {
+        assert!(encode_float_literal("1e100").is_err());
+        assert!(encode_float_literal("-9.99999999999999999e99").is_err());
+        assert!(encode_float_literal("1e-100").is_err());
+        assert!(encode_float_literal("1.2.3").is_err());
+        assert!(encode_float_literal("PI").is_err());
+    }
+}
@@ -1,7 +1,14 @@
+use crate::assembler::target::Target;
+
 pub struct TI8XPGenerator;
 
 impl TI8XPGenerator {
     pub fn create_8xp(program_name: &str, code: &[u8]) -> Vec<u8> {
+        Self::create_8xp_for(Target::TI83Plus, program_name, code)
+    }
+
+    /// Build a .8xp file with the program variable type of `target`.
+    pub fn create_8xp_for(target: Target, program_name: &str, code: &[u8]) -> Vec<u8> {
         // TI-83 Plus file header
         let header = vec![
             0x2a, 0x2a, 0x54, 0x49, 0x38, 0x33, 0x46, 0x2a, // **TI83F*
@@ -28,7 +35,7 @@ impl TI8XPGenerator {
         var_header[3] = 0x00;
         var_header[4] = (data_length & 0xff) as u8;
         var_header[5] = ((data_length >> 8) & 0xff) as u8;
-        var_header[6] = 0x05; // Program type
+        var_header[6] = target.program_type();
 
         // Program name (8 bytes, padded)
         let name = program_name.to_uppercase();
@@ -59,8 +66,8 @@ impl TI8XPGenerator {
 
         // Calculate checksum
         let mut checksum: u16 = 0;
-        for i in 2..var_header.len() {
-            checksum = checksum.wrapping_add(var_header[i] as u16);
+        for byte in &var_header[2..] {
+            checksum = checksum.wrapping_add(*byte as u16);
         }
         checksum = checksum.wrapping_add(length_bytes[0] as u16);
         checksum = checksum.wrapping_add(length_bytes[1] as u16);
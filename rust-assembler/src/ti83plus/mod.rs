@@ -1,5 +1,8 @@
+pub mod charset;
+pub mod float;
 pub mod generator;
 pub mod rom_calls;
 pub mod sys_vars;
 
+pub use charset::Charset;
 pub use generator::TI8XPGenerator;
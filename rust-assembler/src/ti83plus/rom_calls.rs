@@ -122,3 +122,14 @@ pub static ROM_CALLS: phf::Map<&'static str, u16> = phf_map! {
     "_Maybe_CloseEdit" => 0x401b,
     "_IsEditEmpty" => 0x4032,
 };
+
+/// Restart vectors of OS routines, called with `rst` as in `rst rBR_CALL`
+pub static RESTARTS: phf::Map<&'static str, u8> = phf_map! {
+    "rOP1TOOP2" => 0x08,
+    "rFINDSYM" => 0x10,
+    "rPUSHREALO1" => 0x18,
+    "rMOV9TOOP1" => 0x20,
+    // bcall, followed by the address of the ROM call: `rst rBR_CALL \ .dw _PutS`
+    "rBR_CALL" => 0x28,
+    "rFPADD" => 0x30,
+};
@@ -0,0 +1,2 @@
+pub mod rom_calls;
+pub mod sys_vars;
@@ -0,0 +1,29 @@
+use phf::phf_map;
+
+/// TI-84 Plus CE OS routines, called directly with `call`.
+pub static ROM_CALLS: phf::Map<&'static str, u32> = phf_map! {
+    // Display routines
+    "_ClrLCDFull" => 0x020808,
+    "_ClrLCD" => 0x02080C,
+    "_ClrScrnFull" => 0x020814,
+    "_ClrScrn" => 0x020818,
+    "_HomeUp" => 0x020828,
+    "_NewLine" => 0x0207F0,
+    "_PutC" => 0x0207B8,
+    "_PutS" => 0x0207C0,
+    "_DispHL" => 0x021EE0,
+    "_RunIndicOn" => 0x020844,
+    "_RunIndicOff" => 0x020848,
+    "_DrawStatusBar" => 0x021A3C,
+
+    // Keyboard routines
+    "_GetCSC" => 0x02014C,
+    "_GetKey" => 0x020D8C,
+
+    // Variable routines
+    "_Mov9ToOP1" => 0x020320,
+    "_ChkFindSym" => 0x02050C,
+    "_FindSym" => 0x020510,
+    "_DelVar" => 0x021434,
+    "_Arc_Unarc" => 0x021448,
+};
@@ -0,0 +1,20 @@
+use phf::phf_map;
+
+pub static SYS_VARS: phf::Map<&'static str, u32> = phf_map! {
+    "flags" => 0xD00080,
+    "curRow" => 0xD00595,
+    "curCol" => 0xD00596,
+    "OP1" => 0xD005F8,
+    "OP2" => 0xD00603,
+    "OP3" => 0xD0060E,
+    "OP4" => 0xD00619,
+    "OP5" => 0xD00624,
+    "OP6" => 0xD0062F,
+    "penCol" => 0xD008D2,
+    "penRow" => 0xD008D5,
+    "pixelShadow" => 0xD031F6,
+    "plotSScreen" => 0xD09466,
+    "saveSScreen" => 0xD0EA1F,
+    "userMem" => 0xD1A881,
+    "vRam" => 0xD40000,
+};
@@ -1,3 +0,0 @@
-pub mod immediate;
-
-pub use immediate::parse_immediate;
@@ -1,14 +1,39 @@
-use z80asm::{TI8XPGenerator, Z80Assembler};
+use z80asm::instructions::instruction_cycles;
+use z80asm::{
+    AssemblyResult, Cycles, Dialect, Severity, SymbolKind, TI8XPGenerator, Target, Z80Assembler,
+};
+
+/// Assemble each source of a table on its own, with its expected result.
+fn assemble_cases<'a, T>(
+    assembler: &'a Z80Assembler,
+    cases: &'a [(&'a str, T)],
+) -> impl Iterator<Item = (&'a str, AssemblyResult, &'a T)> + 'a {
+    cases.iter().map(|(source, expected)| {
+        let result = assembler
+            .assemble(source)
+            .unwrap_or_else(|e| panic!("Failed to assemble {}: {}", source, e));
+        (*source, result, expected)
+    })
+}
+
+/// Check that each source of a table assembles to its bytes without warnings.
+fn assert_encodings(assembler: &Z80Assembler, cases: &[(&str, &[u8])]) {
+    for (source, result, expected) in assemble_cases(assembler, cases) {
+        assert_eq!(result.bytes, *expected, "{}", source);
+        assert!(result.warnings.is_empty(), "{}", source);
+    }
+}
 
 #[test]
 fn test_hello_world_assembly() {
     let source = include_str!("fixtures/hello.asm");
-    let mut assembler = Z80Assembler::new();
+    let assembler = Z80Assembler::new();
 
     // Assemble the code
     let code = assembler
         .assemble(source)
-        .expect("Failed to assemble hello.asm");
+        .expect("Failed to assemble hello.asm")
+        .bytes;
 
     // Generate .8xp file
     let output = TI8XPGenerator::create_8xp("HELLO", &code);
@@ -27,60 +52,70 @@ fn test_hello_world_assembly() {
 #[test]
 fn test_math_demo_assembly() {
     let source = include_str!("fixtures/math.asm");
-    let mut assembler = Z80Assembler::new();
+    let assembler = Z80Assembler::new();
 
     // Assemble the code
     let code = assembler
         .assemble(source)
-        .expect("Failed to assemble math.asm");
+        .expect("Failed to assemble math.asm")
+        .bytes;
 
     // Generate .8xp file
     let output = TI8XPGenerator::create_8xp("MATH", &code);
 
     // Compare with known good output (note: name will be different, so just check assembly)
-    assert!(code.len() > 0, "No code generated");
+    assert!(!code.is_empty(), "No code generated");
     assert!(output.len() > 100, "Output file too small");
 }
 
 #[test]
 fn test_basic_instructions() {
-    let mut assembler = Z80Assembler::new();
+    let assembler = Z80Assembler::new();
 
     // Test NOP instruction
-    let code = assembler.assemble("nop").expect("Failed to assemble NOP");
+    let code = assembler
+        .assemble("nop")
+        .expect("Failed to assemble NOP")
+        .bytes;
     assert_eq!(code, vec![0x00]);
 
     // Test RET instruction
-    let code = assembler.assemble("ret").expect("Failed to assemble RET");
+    let code = assembler
+        .assemble("ret")
+        .expect("Failed to assemble RET")
+        .bytes;
     assert_eq!(code, vec![0xc9]);
 
     // Test LD A,B
     let code = assembler
         .assemble("ld a,b")
-        .expect("Failed to assemble LD A,B");
+        .expect("Failed to assemble LD A,B")
+        .bytes;
     assert_eq!(code, vec![0x78]);
 }
 
 #[test]
 fn test_immediate_loads() {
-    let mut assembler = Z80Assembler::new();
+    let assembler = Z80Assembler::new();
 
     // Test LD A,42
     let code = assembler
         .assemble("ld a,42")
-        .expect("Failed to assemble LD A,42");
+        .expect("Failed to assemble LD A,42")
+        .bytes;
     assert_eq!(code, vec![0x3e, 42]);
 
     // Test LD HL,$1234
     let code = assembler
         .assemble("ld hl,$1234")
-        .expect("Failed to assemble LD HL,$1234");
+        .expect("Failed to assemble LD HL,$1234")
+        .bytes;
     assert_eq!(code, vec![0x21, 0x34, 0x12]);
 }
 
 #[test]
 fn test_labels_and_jumps() {
-    let mut assembler = Z80Assembler::new();
+    let assembler = Z80Assembler::new();
 
     let source = r#"
         .org $9D93
@@ -93,7 +128,8 @@ fn test_labels_and_jumps() {
 
     let code = assembler
         .assemble(source)
-        .expect("Failed to assemble with labels");
+        .expect("Failed to assemble with labels")
+        .bytes;
     // JP end (where end = $9D93 + 3 + 2 = $9D98)
     assert_eq!(&code[0..3], &[0xc3, 0x98, 0x9d]);
     assert_eq!(&code[3..5], &[0x00, 0x00]); // Two NOPs
@@ -102,149 +138,1787 @@ fn test_labels_and_jumps() {
 
 #[test]
 fn test_data_directives() {
-    let mut assembler = Z80Assembler::new();
+    let assembler = Z80Assembler::new();
 
     // Test .db with string
     let code = assembler
         .assemble(r#".db "Hello",0"#)
-        .expect("Failed to assemble .db");
+        .expect("Failed to assemble .db")
+        .bytes;
     assert_eq!(code, b"Hello\0");
 
     // Test .db with bytes
     let code = assembler
         .assemble(".db $FF, $00, 42")
-        .expect("Failed to assemble .db bytes");
+        .expect("Failed to assemble .db bytes")
+        .bytes;
     assert_eq!(code, vec![0xFF, 0x00, 42]);
 
     // Test .dw
     let code = assembler
         .assemble(".dw $1234")
-        .expect("Failed to assemble .dw");
+        .expect("Failed to assemble .dw")
+        .bytes;
     assert_eq!(code, vec![0x34, 0x12]); // Little-endian
 }
 
 #[test]
 fn test_ix_iy_instructions() {
-    let mut assembler = Z80Assembler::new();
+    let assembler = Z80Assembler::new();
 
     // Test LD IX,nn
     let code = assembler
         .assemble("ld ix,$1234")
-        .expect("Failed to assemble LD IX");
+        .expect("Failed to assemble LD IX")
+        .bytes;
     assert_eq!(code, vec![0xdd, 0x21, 0x34, 0x12]);
 
     // Test LD IY,nn
     let code = assembler
         .assemble("ld iy,$5678")
-        .expect("Failed to assemble LD IY");
+        .expect("Failed to assemble LD IY")
+        .bytes;
     assert_eq!(code, vec![0xfd, 0x21, 0x78, 0x56]);
 
     // Test PUSH IX
     let code = assembler
         .assemble("push ix")
-        .expect("Failed to assemble PUSH IX");
+        .expect("Failed to assemble PUSH IX")
+        .bytes;
     assert_eq!(code, vec![0xdd, 0xe5]);
 
     // Test POP IY
     let code = assembler
         .assemble("pop iy")
-        .expect("Failed to assemble POP IY");
+        .expect("Failed to assemble POP IY")
+        .bytes;
     assert_eq!(code, vec![0xfd, 0xe1]);
 }
 
 #[test]
 fn test_bit_manipulation() {
-    let mut assembler = Z80Assembler::new();
+    let assembler = Z80Assembler::new();
 
     // Test BIT
     let code = assembler
         .assemble("bit 3,a")
-        .expect("Failed to assemble BIT");
+        .expect("Failed to assemble BIT")
+        .bytes;
     assert_eq!(code, vec![0xcb, 0x5f]); // CB prefix, then 01 011 111 (bit 3, reg a)
 
     // Test SET
     let code = assembler
         .assemble("set 7,b")
-        .expect("Failed to assemble SET");
+        .expect("Failed to assemble SET")
+        .bytes;
     assert_eq!(code, vec![0xcb, 0xf8]); // CB prefix, then 11 111 000 (set 7, reg b)
 
     // Test RES
     let code = assembler
         .assemble("res 0,c")
-        .expect("Failed to assemble RES");
+        .expect("Failed to assemble RES")
+        .bytes;
     assert_eq!(code, vec![0xcb, 0x81]); // CB prefix, then 10 000 001 (res 0, reg c)
 
     // Test rotate/shift
-    let code = assembler.assemble("rlc a").expect("Failed to assemble RLC");
+    let code = assembler
+        .assemble("rlc a")
+        .expect("Failed to assemble RLC")
+        .bytes;
     assert_eq!(code, vec![0xcb, 0x07]);
 
-    let code = assembler.assemble("sla b").expect("Failed to assemble SLA");
+    let code = assembler
+        .assemble("sla b")
+        .expect("Failed to assemble SLA")
+        .bytes;
     assert_eq!(code, vec![0xcb, 0x20]);
 }
 
 #[test]
 fn test_io_instructions() {
-    let mut assembler = Z80Assembler::new();
+    let assembler = Z80Assembler::new();
 
     // Test IN A,(n)
     let code = assembler
         .assemble("in a,($10)")
-        .expect("Failed to assemble IN");
+        .expect("Failed to assemble IN")
+        .bytes;
     assert_eq!(code, vec![0xdb, 0x10]);
 
     // Test OUT (n),A
     let code = assembler
         .assemble("out ($11),a")
-        .expect("Failed to assemble OUT");
+        .expect("Failed to assemble OUT")
+        .bytes;
     assert_eq!(code, vec![0xd3, 0x11]);
 
     // Test IN r,(C)
     let code = assembler
         .assemble("in b,(c)")
-        .expect("Failed to assemble IN B,(C)");
+        .expect("Failed to assemble IN B,(C)")
+        .bytes;
     assert_eq!(code, vec![0xed, 0x40]);
 
     // Test block I/O
-    let code = assembler.assemble("otir").expect("Failed to assemble OTIR");
+    let code = assembler
+        .assemble("otir")
+        .expect("Failed to assemble OTIR")
+        .bytes;
     assert_eq!(code, vec![0xed, 0xb3]);
 }
 
 #[test]
 fn test_block_transfer() {
-    let mut assembler = Z80Assembler::new();
+    let assembler = Z80Assembler::new();
 
     // Test LDIR
-    let code = assembler.assemble("ldir").expect("Failed to assemble LDIR");
+    let code = assembler
+        .assemble("ldir")
+        .expect("Failed to assemble LDIR")
+        .bytes;
     assert_eq!(code, vec![0xed, 0xb0]);
 
     // Test CPIR
-    let code = assembler.assemble("cpir").expect("Failed to assemble CPIR");
+    let code = assembler
+        .assemble("cpir")
+        .expect("Failed to assemble CPIR")
+        .bytes;
     assert_eq!(code, vec![0xed, 0xb1]);
 
     // Test LDD
-    let code = assembler.assemble("ldd").expect("Failed to assemble LDD");
+    let code = assembler
+        .assemble("ldd")
+        .expect("Failed to assemble LDD")
+        .bytes;
     assert_eq!(code, vec![0xed, 0xa8]);
 }
 
 #[test]
 fn test_expanded_rom_calls() {
-    let mut assembler = Z80Assembler::new();
+    let assembler = Z80Assembler::new();
 
     // Test math ROM call
     let code = assembler
         .assemble("bcall(_FPAdd)")
-        .expect("Failed to assemble _FPAdd");
+        .expect("Failed to assemble _FPAdd")
+        .bytes;
     assert_eq!(code, vec![0xef, 0x72, 0x40]); // RST 28h, then address
 
     // Test graphics ROM call
     let code = assembler
         .assemble("bcall(_ILine)")
-        .expect("Failed to assemble _ILine");
+        .expect("Failed to assemble _ILine")
+        .bytes;
     assert_eq!(code, vec![0xef, 0x98, 0x47]);
 
     // Test variable management
     let code = assembler
         .assemble("bcall(_ChkFindSym)")
-        .expect("Failed to assemble _ChkFindSym");
+        .expect("Failed to assemble _ChkFindSym")
+        .bytes;
     assert_eq!(code, vec![0xef, 0xf1, 0x42]);
+
+    // ROM calls are recorded however they are called
+    let result = assembler
+        .assemble("    rst rBR_CALL\n    .dw _PutS\n    ld hl,_ClrLCDFull\n")
+        .expect("Failed to assemble rst rBR_CALL");
+    assert_eq!(result.bytes, vec![0xef, 0x0a, 0x45, 0x21, 0x40, 0x45]);
+    let calls: Vec<_> = result.rom_calls.keys().map(String::as_str).collect();
+    assert_eq!(calls, vec!["_ClrLCDFull", "_PutS"]);
+}
+
+#[test]
+fn test_label_addresses_after_multibyte_instructions() {
+    let assembler = Z80Assembler::new();
+
+    let source = r#"
+        .org $9D93
+        ld a,(ix+0)
+        push ix
+        ld (hl),a
+        bit 7,a
+        in a,($11)
+        ldir
+        im 1
+        ld hl,target
+    target:
+        ret
+    "#;
+
+    let code = assembler
+        .assemble(source)
+        .expect("Failed to assemble multi-byte instructions")
+        .bytes;
+    // 3 + 2 + 1 + 2 + 2 + 2 + 2 + 3 bytes before the label
+    let target = 0x9D93 + 17;
+    assert_eq!(code.len(), 18);
+    assert_eq!(&code[14..17], &[0x21, target as u8, (target >> 8) as u8]);
+    assert_eq!(code[17], 0xc9);
+}
+
+#[test]
+fn test_forward_relative_jump() {
+    let assembler = Z80Assembler::new();
+
+    let source = r#"
+        .org $9D93
+        jr z,done
+        set 6,(hl)
+        ld a,(ix+5)
+    done:
+        ret
+    "#;
+
+    let code = assembler
+        .assemble(source)
+        .expect("Failed to assemble forward JR")
+        .bytes;
+    assert_eq!(&code[0..2], &[0x28, 0x05]);
+    assert_eq!(code[7], 0xc9);
+}
+
+#[test]
+fn test_operands_ignore_case_and_whitespace() {
+    let assembler = Z80Assembler::new();
+
+    let code = assembler
+        .assemble("ld a, b\nLD A,B\nld a,( hl )\nLD A,(IX + 5)\nEX AF,AF'")
+        .expect("Failed to assemble spaced and uppercase operands")
+        .bytes;
+    assert_eq!(code, vec![0x78, 0x78, 0x7e, 0xdd, 0x7e, 0x05, 0x08]);
+
+    let code = assembler
+        .assemble("JP NZ, $1234\nret C\nout ( $10 ), a")
+        .expect("Failed to assemble spaced conditions and ports")
+        .bytes;
+    assert_eq!(code, vec![0xc2, 0x34, 0x12, 0xd8, 0xd3, 0x10]);
+}
+
+#[test]
+fn test_expressions_in_operands() {
+    let assembler = Z80Assembler::new();
+
+    let source = r#"
+        .org $9D93
+    start:
+        ld hl,(end-start)/2
+        ld a,1<<3|1
+        ld de,curRow
+        ld hl,(table)
+        jr $+2
+        ld a,high(table)
+        add a,low(table+1)
+    table:
+        .db table-start, end-table
+        .dw penCol, table+1
+    end:
+    "#;
+
+    let code = assembler
+        .assemble(source)
+        .expect("Failed to assemble expressions")
+        .bytes;
+    let table: u16 = 0x9D93 + 17;
+    assert_eq!(&code[0..3], &[0x21, 11, 0x00]);
+    assert_eq!(&code[3..5], &[0x3e, 0x09]);
+    assert_eq!(&code[5..8], &[0x11, 0x4b, 0x84]);
+    assert_eq!(&code[8..11], &[0x2a, table as u8, (table >> 8) as u8]);
+    assert_eq!(&code[11..13], &[0x18, 0x00]);
+    assert_eq!(&code[13..15], &[0x3e, (table >> 8) as u8]);
+    assert_eq!(&code[15..17], &[0xc6, (table + 1) as u8]);
+    assert_eq!(&code[17..19], &[17, 6]);
+    assert_eq!(
+        &code[19..23],
+        &[0xd7, 0x86, (table + 1) as u8, ((table + 1) >> 8) as u8]
+    );
+}
+
+#[test]
+fn test_reports_every_error_with_location() {
+    let assembler = Z80Assembler::new().with_file_name("broken.asm");
+
+    let source =
+        "start:\n    foo a\n    ld a,1\n    jp missing ; typo\n    jr start\n    bcall(_puts)\n";
+
+    let error = assembler.assemble(source).unwrap_err();
+    let lines: Vec<usize> = error
+        .diagnostics
+        .iter()
+        .map(|d| d.span.as_ref().unwrap().line)
+        .collect();
+    assert_eq!(lines, vec![2, 4, 6]);
+    assert_eq!(error.error_count(), 3);
+
+    let undefined = &error.diagnostics[1];
+    assert_eq!(undefined.message, "Undefined symbol: missing");
+    assert_eq!(undefined.span.as_ref().unwrap().columns, 4..14);
+    assert!(undefined.help.is_some());
+
+    let rendered = error.to_string();
+    assert!(rendered.contains(" --> broken.asm:4:5\n"));
+    assert!(rendered.contains("4 |     jp missing ; typo\n"));
+    assert!(rendered.ends_with("could not assemble due to 3 errors"));
+}
+
+#[test]
+fn test_relative_jump_out_of_range_has_help() {
+    let assembler = Z80Assembler::new();
+
+    let source = format!("    jr far\n{}far:\n", "    nop\n".repeat(200));
+
+    let error = assembler.assemble(&source).unwrap_err();
+    assert_eq!(error.diagnostics.len(), 1);
+    assert!(error.diagnostics[0]
+        .message
+        .starts_with("JR target out of range"));
+    assert_eq!(
+        error.diagnostics[0].help.as_deref(),
+        Some("use jp, which can reach any address")
+    );
+}
+
+#[test]
+fn test_operand_out_of_range() {
+    let assembler = Z80Assembler::new();
+
+    // Signed and unsigned forms both fit, including negative equates
+    let result = assembler
+        .assemble(
+            "step .equ -5\n    ld a,-128\n    cp 255\n    ld a,step\n    ld hl,-1\n.dw 65535\n",
+        )
+        .expect("Failed to assemble values at the edges of their range");
+    assert_eq!(
+        result.bytes,
+        vec![0x3e, 0x80, 0xfe, 0xff, 0x3e, 0xfb, 0x21, 0xff, 0xff, 0xff, 0xff]
+    );
+
+    for (source, message) in [
+        ("    ld a,300", "8-bit value out of range: 300"),
+        ("    .db 256", "8-bit value out of range: 256"),
+        ("    .fill 2,300", "8-bit value out of range: 300"),
+        ("    out (300),a", "8-bit value out of range: 300"),
+        ("    add a,-129", "8-bit value out of range: -129"),
+        ("    ld hl,70000", "16-bit value out of range: 70000"),
+        ("    .dw 65536", "16-bit value out of range: 65536"),
+    ] {
+        let error = assembler.assemble(source).unwrap_err();
+        assert_eq!(error.diagnostics.len(), 1, "{}", source);
+        assert_eq!(error.diagnostics[0].message, message, "{}", source);
+    }
+
+    let error = assembler.assemble("    ld a,300").unwrap_err();
+    assert_eq!(
+        error.diagnostics[0].help.as_deref(),
+        Some("use a value from -128 to 255")
+    );
+}
+
+#[test]
+fn test_sessions_do_not_share_symbols() {
+    let assembler = Z80Assembler::new();
+
+    assembler
+        .assemble(".equ value,1\nstart:\n    ld a,value")
+        .expect("Failed to assemble first source");
+
+    let error = assembler.assemble("    jp start").unwrap_err();
+    assert_eq!(error.diagnostics[0].message, "Undefined symbol: start");
+}
+
+#[test]
+fn test_assembly_result_contents() {
+    let assembler = Z80Assembler::new()
+        .with_origin(0x8000)
+        .with_symbol("COUNT", 3);
+
+    let source =
+        "start:\n    ld b,COUNT\n\n    bcall(_PutS)\nloop: djnz loop\n    .org $9000\n    ret";
+    let result = assembler.assemble(source).expect("Failed to assemble");
+
+    assert_eq!(
+        result.bytes,
+        vec![0x06, 0x03, 0xef, 0x0a, 0x45, 0x10, 0xfe, 0xc9]
+    );
+    assert_eq!(result.symbols.value("start"), Some(0x8000));
+    assert_eq!(result.symbols.value("loop"), Some(0x8005));
+    assert_eq!(result.symbols.value("COUNT"), Some(3));
+    assert_eq!(result.rom_calls.get("_PutS"), Some(&0x450a));
+    assert!(result.warnings.is_empty());
+
+    let lines: Vec<(usize, u32, usize)> = result
+        .lines
+        .iter()
+        .map(|record| (record.span.line, record.address, record.size()))
+        .collect();
+    assert_eq!(
+        lines,
+        vec![
+            (1, 0x8000, 0),
+            (2, 0x8000, 2),
+            (4, 0x8002, 3),
+            (5, 0x8005, 2),
+            (6, 0x9000, 0),
+            (7, 0x9000, 1),
+        ]
+    );
+}
+
+#[test]
+fn test_duplicate_label_is_an_error() {
+    let assembler = Z80Assembler::new().with_file_name("dup.asm");
+
+    let error = assembler
+        .assemble("loop:\n    nop\nloop:\n    jr loop")
+        .unwrap_err();
+    assert_eq!(error.error_count(), 1);
+    assert_eq!(error.diagnostics[0].message, "Duplicate definition of loop");
+    assert_eq!(error.diagnostics[0].span.as_ref().unwrap().line, 3);
+    assert_eq!(
+        error.diagnostics[0].notes,
+        vec!["first defined at dup.asm:1"]
+    );
+
+    let error = assembler.assemble(".equ size,1\n.equ size,2").unwrap_err();
+    assert_eq!(error.diagnostics[0].message, "Duplicate definition of size");
+
+    // Every expansion of a body defines its symbols again
+    let source = ".macro m(v)\nK .equ v\n.endm\n    m(1)\n    m(2)\n    .db K\n";
+    let error = assembler.assemble(source).unwrap_err();
+    assert_eq!(error.error_count(), 1);
+    assert_eq!(error.diagnostics[0].message, "Duplicate definition of K");
+    assert_eq!(error.diagnostics[0].related[0].span.line, 5);
+
+    let source = ".for i, 0, 1\nX .equ i+5\n.endfor\n    .db X\n";
+    let error = assembler.assemble(source).unwrap_err();
+    assert_eq!(error.error_count(), 1);
+    assert_eq!(error.diagnostics[0].message, "Duplicate definition of X");
+}
+
+#[test]
+fn test_symbol_kinds_and_references() {
+    let assembler = Z80Assembler::new();
+
+    let source = r#"
+        .set count,1
+        .equ width,8
+    start:
+        ld de,curRow
+        ld a,count+width
+        .set count,count+1
+        .dw penCol, _PutS, count
+        jr start
+    "#;
+
+    let result = assembler.assemble(source).expect("Failed to assemble");
+    assert_eq!(
+        result.bytes,
+        vec![0x11, 0x4b, 0x84, 0x3e, 9, 0xd7, 0x86, 0x0a, 0x45, 2, 0, 0x18, 0xf3]
+    );
+
+    let kinds: Vec<(&str, SymbolKind)> = result
+        .symbols
+        .iter()
+        .map(|(name, symbol)| (name, symbol.kind))
+        .collect();
+    assert_eq!(
+        kinds,
+        vec![
+            ("_PutS", SymbolKind::RomCall),
+            ("count", SymbolKind::Set),
+            ("curRow", SymbolKind::SysVar),
+            ("penCol", SymbolKind::SysVar),
+            ("start", SymbolKind::Label),
+            ("width", SymbolKind::Equate),
+        ]
+    );
+
+    let start = result.symbols.get("start").unwrap();
+    assert_eq!(start.defined_at.as_ref().unwrap().line, 4);
+    assert_eq!(start.references.len(), 1);
+    assert_eq!(start.references[0].line, 9);
+
+    let count = result.symbols.get("count").unwrap();
+    let uses: Vec<usize> = count.references.iter().map(|site| site.line).collect();
+    assert_eq!(uses, vec![6, 7, 8]);
+}
+
+#[test]
+fn test_local_labels() {
+    let assembler = Z80Assembler::new().with_origin(0x8000);
+
+    let source = r#"
+    clear:
+        ld b,4
+    .loop:
+        djnz .loop
+        ret
+    draw:
+        ld b,2
+    _loop:
+        djnz _loop
+        jp clear.loop
+    "#;
+
+    let result = assembler.assemble(source).expect("Failed to assemble");
+    assert_eq!(
+        result.bytes,
+        vec![0x06, 0x04, 0x10, 0xfe, 0xc9, 0x06, 0x02, 0x10, 0xfe, 0xc3, 0x02, 0x80]
+    );
+    assert_eq!(result.symbols.value("clear.loop"), Some(0x8002));
+    assert_eq!(result.symbols.value("draw.loop"), Some(0x8007));
+}
+
+#[test]
+fn test_undefined_local_label_names_scope() {
+    let assembler = Z80Assembler::new();
+
+    let error = assembler
+        .assemble("main:\n    jr .done\nother:\n.done:\n    ret")
+        .unwrap_err();
+    assert_eq!(
+        error.diagnostics[0].message,
+        "Undefined local label .done in scope main"
+    );
+
+    let error = assembler.assemble(".start:\n    ret").unwrap_err();
+    assert_eq!(
+        error.diagnostics[0].message,
+        "Local label .start has no enclosing global label"
+    );
+}
+
+#[test]
+fn test_anonymous_labels() {
+    let assembler = Z80Assembler::new().with_origin(0x8000);
+
+    let source = r#"
+        ld b,3
+    @@: djnz -
+        jr z,+
+        jr ++
+    -:  nop
+    +:  jr --
+    +:  ret
+    "#;
+
+    let result = assembler.assemble(source).expect("Failed to assemble");
+    assert_eq!(
+        result.bytes,
+        vec![0x06, 0x03, 0x10, 0xfe, 0x28, 0x03, 0x18, 0x03, 0x00, 0x18, 0xf7, 0xc9]
+    );
+
+    // `-` only sees `-:` and `@@:` labels, `+` only `+:` and `@@:` labels
+    let source = "    jr +\n-:  nop\n+:  nop\n    jr -\n@@: jr +\n-:  jr -\n+:  ret\n";
+    let result = assembler.assemble(source).expect("Failed to assemble");
+    assert_eq!(
+        result.bytes,
+        vec![0x18, 0x01, 0x00, 0x00, 0x18, 0xfc, 0x18, 0x02, 0x18, 0xfe, 0xc9]
+    );
+
+    let error = assembler.assemble("    jr +").unwrap_err();
+    assert_eq!(error.diagnostics[0].message, "No anonymous label for +");
+}
+
+#[test]
+fn test_macros_with_parameters_and_local_labels() {
+    let assembler = Z80Assembler::new().with_origin(0x8000);
+
+    let source = r#"
+    .macro preserve_ix(body_call, count=2)
+        push ix
+        ld b,count
+    .again:
+        call body_call
+        djnz .again
+        pop ix
+    .endm
+
+    main:
+        preserve_ix(draw)
+        preserve_ix draw, 5
+    .done:
+        jr .done
+    draw:
+        ret
+    "#;
+
+    let result = assembler
+        .assemble(source)
+        .expect("Failed to assemble macros");
+    let draw: u16 = 0x8000 + 2 * 11 + 2;
+    let expansion = |count: u8| {
+        vec![
+            0xdd,
+            0xe5,
+            0x06,
+            count,
+            0xcd,
+            draw as u8,
+            (draw >> 8) as u8,
+            0x10,
+            0xfb,
+            0xdd,
+            0xe1,
+        ]
+    };
+    let mut expected = expansion(2);
+    expected.extend(expansion(5));
+    expected.extend([0x18, 0xfe, 0xc9]);
+    assert_eq!(result.bytes, expected);
+    assert_eq!(result.symbols.value("main.done"), Some(0x8016));
+}
+
+#[test]
+fn test_macro_errors_point_to_body_and_invocation() {
+    let assembler = Z80Assembler::new().with_file_name("macro.asm");
+
+    let source = ".macro show(text)
+    ld hl,text
+    bcall(_PutS)
+.endm
+    show(missing)
+";
+
+    let error = assembler.assemble(source).unwrap_err();
+    assert_eq!(error.error_count(), 1);
+    let diagnostic = &error.diagnostics[0];
+    assert_eq!(diagnostic.message, "Undefined symbol: missing");
+    assert_eq!(diagnostic.span.as_ref().unwrap().line, 2);
+    assert_eq!(diagnostic.related.len(), 1);
+    assert_eq!(diagnostic.related[0].span.line, 5);
+
+    let rendered = error.to_string();
+    assert!(rendered.contains(" --> macro.asm:2:5\n"));
+    assert!(rendered.contains("note: in expansion of macro show\n --> macro.asm:5:5\n"));
+
+    let error = assembler
+        .assemble(".macro two(a, b)\n    ld a,b\n.endm\n    two(1)\n")
+        .unwrap_err();
+    assert_eq!(
+        error.diagnostics[0].message,
+        "Missing argument b for macro two"
+    );
+}
+
+/// A fresh directory for tests that need files on disk, removed when dropped.
+struct TempDir(std::path::PathBuf);
+
+impl std::ops::Deref for TempDir {
+    type Target = std::path::Path;
+
+    fn deref(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn temp_dir(name: &str) -> TempDir {
+    let dir = std::env::temp_dir().join(format!("z80asm-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    TempDir(dir)
+}
+
+#[test]
+fn test_include_files_and_search_paths() {
+    let dir = temp_dir("include");
+    std::fs::create_dir_all(dir.join("src/lib")).unwrap();
+    std::fs::create_dir_all(dir.join("inc")).unwrap();
+    std::fs::write(dir.join("src/lib/util.asm"), "util:\n    ret\n").unwrap();
+    std::fs::write(dir.join("inc/defs.inc"), ".equ LIVES,3\n").unwrap();
+
+    let main = dir.join("src/main.asm");
+    let source =
+        "#include \"defs.inc\"\n    ld a,LIVES\n    call util\n.include \"lib/util.asm\"\n";
+    let assembler = Z80Assembler::new()
+        .with_origin(0x8000)
+        .with_file_name(main.display().to_string())
+        .with_include_path(dir.join("inc"));
+
+    let result = assembler
+        .assemble(source)
+        .expect("Failed to assemble includes");
+    assert_eq!(result.bytes, vec![0x3e, 0x03, 0xcd, 0x05, 0x80, 0xc9]);
+
+    let util = result.symbols.get("util").unwrap();
+    let site = util.defined_at.as_ref().unwrap();
+    assert!(site.file.ends_with("util.asm"));
+    assert_eq!(site.line, 1);
+}
+
+#[test]
+fn test_include_errors() {
+    let dir = temp_dir("include-errors");
+    std::fs::write(dir.join("a.inc"), "    nop\n.include \"b.inc\"\n").unwrap();
+    std::fs::write(dir.join("b.inc"), "    foo\n.include \"a.inc\"\n").unwrap();
+
+    let assembler = Z80Assembler::new().with_file_name(dir.join("main.asm").display().to_string());
+
+    let error = assembler
+        .assemble(".include \"a.inc\"\n.include \"missing.inc\"\n")
+        .unwrap_err();
+    let messages: Vec<(&str, &str, usize)> = error
+        .diagnostics
+        .iter()
+        .map(|d| {
+            let span = d.span.as_ref().unwrap();
+            let file = std::path::Path::new(&span.file).file_name().unwrap();
+            (d.message.as_str(), file.to_str().unwrap(), span.line)
+        })
+        .collect();
+    assert_eq!(messages.len(), 3);
+    assert_eq!(messages[0], ("Unknown instruction: foo", "b.inc", 1));
+    let prefix = format!("{}/", dir.display());
+    assert_eq!(
+        messages[1].0.replace(&prefix, ""),
+        "Include cycle: a.inc -> b.inc -> a.inc"
+    );
+    assert_eq!((messages[1].1, messages[1].2), ("b.inc", 2));
+    assert_eq!(
+        messages[2],
+        ("Cannot find file \"missing.inc\"", "main.asm", 2)
+    );
+}
+
+#[test]
+fn test_conditional_assembly() {
+    let source = r#"
+    #define SPEED 3
+    .equ mode,2
+    #ifdef DEBUG
+        di
+    #else
+        ld a,SPEED
+    #endif
+    .if mode == 1
+        nop
+    .elseif mode == 2 && SPEED > 2
+        halt
+    .else
+        ei
+    .endif
+    #ifndef DEBUG
+        ret
+    #endif
+    "#;
+
+    let result = Z80Assembler::new()
+        .assemble(source)
+        .expect("Failed to assemble conditionals");
+    assert_eq!(result.bytes, vec![0x3e, 0x03, 0x76, 0xc9]);
+
+    let result = Z80Assembler::new()
+        .with_define("DEBUG", "1")
+        .assemble(source)
+        .expect("Failed to assemble conditionals with DEBUG");
+    assert_eq!(result.bytes, vec![0xf3, 0x76]);
+}
+
+#[test]
+fn test_conditional_errors() {
+    let source = "#if later\n    nop\n#endif\n.endif\n.ifdef FAST\n    nop\n";
+    let error = Z80Assembler::new().assemble(source).unwrap_err();
+    let messages: Vec<_> = error
+        .diagnostics
+        .iter()
+        .map(|d| (d.message.as_str(), d.span.as_ref().unwrap().line))
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            ("Undefined symbol in condition: later", 1),
+            (".endif without a matching #if", 4),
+            ("Missing #endif for this .ifdef", 5),
+        ]
+    );
+}
+
+#[test]
+fn test_incbin_with_offset_and_length() {
+    let dir = temp_dir("incbin");
+    std::fs::create_dir_all(dir.join("data")).unwrap();
+    std::fs::write(dir.join("data/tiles.bin"), [1, 2, 3, 4, 5, 6]).unwrap();
+
+    let source = r#"
+        jr after
+    tiles:
+        .incbin "tiles.bin"
+    second:
+        .incbin "tiles.bin", TILE_SIZE, TILE_SIZE
+    rest:
+        .incbin "tiles.bin", 4
+    after:
+        ret
+    .equ TILE_SIZE,2
+    "#;
+    let assembler = Z80Assembler::new()
+        .with_origin(0x8000)
+        .with_file_name(dir.join("main.asm").display().to_string())
+        .with_include_path(dir.join("data"));
+
+    let result = assembler
+        .assemble(source)
+        .expect("Failed to assemble .incbin");
+    assert_eq!(
+        result.bytes,
+        vec![0x18, 0x0a, 1, 2, 3, 4, 5, 6, 3, 4, 5, 6, 0xc9]
+    );
+    assert_eq!(result.symbols.value("second"), Some(0x8008));
+    assert_eq!(result.symbols.value("after"), Some(0x800c));
+
+    let error = assembler
+        .assemble(".incbin \"tiles.bin\", 7\n.incbin \"tiles.bin\", 4, 3\n.incbin \"none.bin\"\n")
+        .unwrap_err();
+    let messages: Vec<_> = error
+        .diagnostics
+        .iter()
+        .map(|d| d.message.as_str())
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            ".incbin offset 7 is past the end of \"tiles.bin\"",
+            ".incbin range 4..7 is past the end of \"tiles.bin\"",
+            "Cannot find file \"none.bin\"",
+        ]
+    );
+    assert_eq!(
+        error.diagnostics[1].help.as_deref(),
+        Some("at most 2 bytes can be read from offset 4")
+    );
+}
+
+#[test]
+fn test_repetition_directives() {
+    let source = r#"
+        .rept 2
+        nop
+        .endr
+    squares:
+        .for i, 0, 3
+    square_{i}: .db i*i
+        .endfor
+        .set n, 3
+        .while n > 0
+    .again:
+        djnz .again
+        .set n, n-1
+        .endw
+        .for x, 4, 0, -2
+        .rept x/2
+        .db x
+        .endr
+        .endfor
+        ld hl,square_2
+    "#;
+
+    let assembler = Z80Assembler::new().with_origin(0x8000);
+    let result = assembler
+        .assemble(source)
+        .expect("Failed to assemble repetitions");
+    assert_eq!(
+        result.bytes,
+        vec![0x00, 0x00, 0, 1, 4, 9, 0x10, 0xfe, 0x10, 0xfe, 0x10, 0xfe, 4, 4, 2, 0x21, 0x04, 0x80]
+    );
+    assert_eq!(result.symbols.value("square_3"), Some(0x8005));
+}
+
+#[test]
+fn test_repetition_errors() {
+    let source = ".rept count\n    nop\n.endr\n.while 1\n.endw\n.endfor\n.for i, 0, 1\n    nop\n";
+    let error = Z80Assembler::new().assemble(source).unwrap_err();
+    let messages: Vec<_> = error
+        .diagnostics
+        .iter()
+        .map(|d| (d.message.as_str(), d.span.as_ref().unwrap().line))
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            ("Undefined symbol in repeat count: count", 1),
+            ("Repetition exceeds 65536 iterations", 4),
+            (".endfor without a matching loop", 6),
+            ("Missing .endfor for this .for", 7),
+        ]
+    );
+}
+
+#[test]
+fn test_storage_and_alignment_directives() {
+    let source = r#"
+    .equ BUFFER_SIZE,3
+        .db 1
+    buffer:
+        .ds BUFFER_SIZE
+        .block 2, $ff
+        .fill 2, 'x'
+        .align 8
+    aligned:
+        .align 4, $aa
+        .dl $123456
+        .dd $89abcdef
+        .asciz "Hi", 13
+        .asciiz ""
+    "#;
+
+    let assembler = Z80Assembler::new().with_origin(0x8000);
+    let result = assembler
+        .assemble(source)
+        .expect("Failed to assemble storage directives");
+    assert_eq!(
+        result.bytes,
+        vec![
+            1, 0, 0, 0, 0xff, 0xff, b'x', b'x', 0x56, 0x34, 0x12, 0xef, 0xcd, 0xab, 0x89, b'H',
+            b'i', 13, 0, 0
+        ]
+    );
+    assert_eq!(result.symbols.value("buffer"), Some(0x8001));
+    assert_eq!(result.symbols.value("aligned"), Some(0x8008));
+
+    // Alignment padding follows the address, which a forward jump can change
+    let result = assembler
+        .assemble("    jr later\n    .align 4, $ee\nlater:\n    ret\n")
+        .expect("Failed to assemble .align");
+    assert_eq!(result.bytes, vec![0x18, 0x02, 0xee, 0xee, 0xc9]);
+
+    let error = assembler
+        .assemble(".ds later\nlater:\n.align 0\n")
+        .unwrap_err();
+    let messages: Vec<_> = error
+        .diagnostics
+        .iter()
+        .map(|d| d.message.as_str())
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            "Undefined symbol: later",
+            ".align boundary must be between 1 and 65536, found 0",
+        ]
+    );
+}
+
+#[test]
+fn test_assertions_and_messages() {
+    let source = r#"
+    start:
+        ld a,1
+    table:
+        .db 1,2,3
+    table_end:
+        .echo "table is ", table_end - table, " bytes"
+        .warning "slow path enabled"
+        .assert table_end - start <= 8, "code over budget"
+        .assert (table & $FF00) == (table_end & $FF00)
+    "#;
+
+    let assembler = Z80Assembler::new().with_origin(0x80f0);
+    let result = assembler
+        .assemble(source)
+        .expect("Failed to assemble assertions");
+    let messages: Vec<_> = result
+        .warnings
+        .iter()
+        .map(|d| {
+            (
+                d.severity,
+                d.message.as_str(),
+                d.span.as_ref().unwrap().line,
+            )
+        })
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            (Severity::Note, "table is 3 bytes", 7),
+            (Severity::Warning, "slow path enabled", 8),
+        ]
+    );
+
+    // Moving the table across a page boundary fails the second assertion
+    let error = Z80Assembler::new()
+        .with_origin(0x80fd)
+        .assemble(&format!(
+            "{}\n    .error \"version \", 2, \" is not supported\"\n",
+            source
+        ))
+        .unwrap_err();
+    let errors: Vec<_> = error
+        .diagnostics
+        .iter()
+        .filter(|d| d.is_error())
+        .map(|d| d.message.as_str())
+        .collect();
+    assert_eq!(
+        errors,
+        vec!["Assertion failed", "version 2 is not supported"]
+    );
+    let assertion = error
+        .diagnostics
+        .iter()
+        .find(|d| d.message == "Assertion failed")
+        .unwrap();
+    assert!(assertion
+        .notes
+        .contains(&"table_end = 33026 ($8102)".to_string()));
+}
+
+#[test]
+fn test_string_and_character_literals() {
+    let source = r#"
+    msg: .db "Score: 10;", 0 ; the ; and : are part of the string
+        .db "say \"hi\"\\\0\x7F"
+        ld a,'\''
+        ld hl,'AB'
+        cp ';'
+        ex af,af' ; comment
+    "#;
+
+    let assembler = Z80Assembler::new();
+    let result = assembler
+        .assemble(source)
+        .expect("Failed to assemble string literals");
+    let mut expected = b"Score: 10;\0".to_vec();
+    expected.extend(b"say \"hi\"\\\0\x7F");
+    expected.extend([0x3e, b'\'', 0x21, b'B', b'A', 0xfe, b';', 0x08]);
+    assert_eq!(result.bytes, expected);
+    assert!(result.symbols.get("msg").is_some());
+
+    let error = assembler
+        .assemble(".db \"\\q\"\n ld a,'ABC'\n")
+        .unwrap_err();
+    let messages: Vec<_> = error
+        .diagnostics
+        .iter()
+        .map(|d| d.message.as_str())
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            "Unknown escape sequence \\q",
+            "Character constant 'ABC' is longer than 2 characters",
+        ]
+    );
+}
+
+#[test]
+fn test_ti_charset() {
+    let source = r#"
+        .charset ti
+        .asciz "θ→[x]²≤é"
+        .charset ascii
+        .db "[", '['
+    "#;
+
+    let assembler = Z80Assembler::new();
+    let result = assembler
+        .assemble(source)
+        .expect("Failed to assemble TI charset");
+    assert_eq!(
+        result.bytes,
+        vec![0x5B, 0x1C, 0xC1, b'x', b']', 0x12, 0x17, 0x96, 0, b'[', b'[']
+    );
+
+    // Character constants use the charset too
+    let source = "    .charset ti\n    .db 'θ', \"θ\"\n    ld a,'θ'\n    cp '['\n    ld hl,'θ['\n";
+    let result = assembler
+        .assemble(source)
+        .expect("Failed to assemble TI character constants");
+    assert_eq!(
+        result.bytes,
+        vec![0x5B, 0x5B, 0x3e, 0x5B, 0xfe, 0xC1, 0x21, 0xC1, 0x5B]
+    );
+
+    // Escaped bytes are emitted as written under any charset
+    let source = "    .charset ti\n    .db \"\\xD6\\xC1\\xFF[\", '\\xD6'\n    ld a,'\\xFF'\n";
+    let result = assembler
+        .assemble(source)
+        .expect("Failed to assemble TI escaped bytes");
+    assert_eq!(result.bytes, vec![0xD6, 0xC1, 0xFF, 0xC1, 0xD6, 0x3e, 0xFF]);
+
+    let error = assembler
+        .assemble(".db \"θ\"\nld a,'θ'\n.charset ti\n.db \"日\"\ncp '日'\n.charset utf8\n")
+        .unwrap_err();
+    let messages: Vec<_> = error
+        .diagnostics
+        .iter()
+        .map(|d| d.message.as_str())
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            "Character 'θ' has no single-byte code",
+            "Character 'θ' has no single-byte code",
+            "Character '日' (U+65E5) is not in the TI-83 Plus font",
+            "Character '日' (U+65E5) is not in the TI-83 Plus font",
+            "Unknown charset utf8, expected ti or ascii",
+        ]
+    );
+}
+
+#[test]
+fn test_ti_float_directive() {
+    let source = r#"
+        ld hl,pi
+        nop
+    pi: .float 3.14159265358979
+    small: .dfp -2.5e-3, 3-4i
+    "#;
+
+    let assembler = Z80Assembler::new().with_origin(0x8000);
+    let result = assembler
+        .assemble(source)
+        .expect("Failed to assemble floats");
+    assert_eq!(result.symbols.value("small"), Some(0x800d));
+    assert_eq!(
+        result.bytes[4..13],
+        [0x00, 0x80, 0x31, 0x41, 0x59, 0x26, 0x53, 0x58, 0x98]
+    );
+    assert_eq!(
+        result.bytes[13..22],
+        [0x80, 0x7d, 0x25, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]
+    );
+    assert_eq!(result.bytes.len(), 4 + 9 + 9 + 18);
+    assert_eq!(result.bytes[22..24], [0x0c, 0x80]);
+    assert_eq!(result.bytes[31..33], [0x8c, 0x80]);
+
+    let error = assembler.assemble(".float 1e100\n").unwrap_err();
+    assert_eq!(
+        error.diagnostics[0].message,
+        "1e100 is outside the range of TI floating-point numbers"
+    );
+}
+
+#[test]
+fn test_struct_and_enum_layouts() {
+    let source = r#"
+    .struct Point
+        x .byte
+        y .byte
+    .endstruct
+
+    .struct Entity
+        pos Point
+        hp: .word
+        name .ds 8
+        state .byte
+    .endstruct
+
+    .enum
+        IDLE, WALKING
+        DEAD = 10
+    .endenum
+
+    .enum Dir
+        UP
+        DOWN
+    .endenum
+
+        ld a,(ix+Entity.pos+Point.y)
+        ld (ix+Entity.state),a
+        ld (iy-Entity.size),b
+        .db DEAD, Dir.DOWN
+    entities:
+        .ds Entity.size * 2
+    "#;
+
+    let assembler = Z80Assembler::new().with_origin(0x8000);
+    let result = assembler
+        .assemble(source)
+        .expect("Failed to assemble layouts");
+    assert_eq!(result.symbols.value("Entity.hp"), Some(2));
+    assert_eq!(result.symbols.value("Entity.size"), Some(13));
+    assert_eq!(result.symbols.value("WALKING"), Some(1));
+    assert_eq!(
+        result.symbols.get("Entity.state").unwrap().kind,
+        SymbolKind::Equate
+    );
+    assert_eq!(
+        result.bytes[..11],
+        [0xdd, 0x7e, 0x01, 0xdd, 0x77, 0x0c, 0xfd, 0x70, 0xf3, 10, 1]
+    );
+    assert_eq!(result.bytes.len(), 11 + 26);
+
+    let error = assembler
+        .assemble(".struct Big\n    data .ds 200\n    last .byte\n.endstruct\n    ld a,(ix+Big.last)\n.enum\n    A\n")
+        .unwrap_err();
+    let messages: Vec<_> = error
+        .diagnostics
+        .iter()
+        .map(|d| (d.message.as_str(), d.span.as_ref().unwrap().line))
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            ("Index displacement out of range: 200", 5),
+            ("Missing .endenum for this .enum", 6),
+        ]
+    );
+}
+
+#[test]
+fn test_equate_forms_and_forward_references() {
+    let source = r#"
+        ld a,LIVES
+        ld hl,TABLE_END
+    LIVES .equ START_LIVES + 1
+    START_LIVES = 2
+    TABLE_END equ table + LENGTH
+    LENGTH: .equ 3
+    #define SPEED 7
+    count := 1
+    count := count + 1
+    .if LENGTH == 3 && count == 2
+        .db SPEED, count
+    .endif
+    count .set 10
+        .db count
+    table:
+        .db 1, 2, 3
+    "#;
+
+    let assembler = Z80Assembler::new().with_origin(0x8000);
+    let result = assembler
+        .assemble(source)
+        .expect("Failed to assemble equates");
+    assert_eq!(
+        result.bytes,
+        vec![0x3e, 0x03, 0x21, 0x0b, 0x80, 7, 2, 10, 1, 2, 3]
+    );
+    assert_eq!(
+        result.symbols.get("LIVES").unwrap().kind,
+        SymbolKind::Equate
+    );
+    assert_eq!(result.symbols.get("count").unwrap().kind, SymbolKind::Set);
+
+    let error = assembler
+        .assemble("LOOP .equ LOOP + 1\nSIZE = 1\nSIZE := 2\n")
+        .unwrap_err();
+    let messages: Vec<_> = error
+        .diagnostics
+        .iter()
+        .map(|d| (d.message.as_str(), d.span.as_ref().unwrap().line))
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            ("Undefined symbol: LOOP", 1),
+            ("Duplicate definition of SIZE", 3),
+        ]
+    );
+
+    // Sizes and addresses may use constants defined further down
+    let source = r#"
+        .ds FWD
+        .org LATER
+    here:
+        .db FWD
+    FWD .equ LEN * 2
+    LEN .equ 2
+    LATER .equ $9000 + LEN
+    "#;
+    let result = assembler
+        .assemble(source)
+        .expect("Failed to assemble forward sizes");
+    assert_eq!(result.bytes, vec![0, 0, 0, 0, 4]);
+    assert_eq!(result.symbols.value("here"), Some(0x9002));
+
+    // Labels further down are not known while code is placed
+    let error = assembler
+        .assemble(
+            "    .ds SIZE
+SIZE .equ end - start
+start: nop
+end:
+",
+        )
+        .unwrap_err();
+    assert_eq!(error.diagnostics[0].message, "Undefined symbol: SIZE");
+    assert_eq!(
+        error.diagnostics[0].notes,
+        vec![
+            "this value sets the size or address of code, so it cannot depend on labels defined further down"
+                .to_string()
+        ]
+    );
+}
+
+#[test]
+fn test_counters_in_macro_and_loop_bodies() {
+    let source = r#"
+    n := 3
+    .while n > 0
+        .db n
+    n := n - 1
+    .endw
+    .macro bump
+    total := total + 2
+    .endm
+    total := 0
+        bump
+        bump
+        .db total
+    "#;
+
+    let assembler = Z80Assembler::new();
+    let result = assembler
+        .assemble(source)
+        .expect("Failed to assemble counters");
+    assert_eq!(result.bytes, vec![3, 2, 1, 4]);
+    assert_eq!(result.symbols.value("n"), Some(0));
+
+    // A column-one counter in SPASM sources is not a label either
+    let source = "n .set 0
+ .rept 3
+n .set n+1
+ .endr
+ .db n
+";
+    let result = Z80Assembler::new()
+        .with_dialect(Dialect::Spasm)
+        .assemble(source)
+        .expect("Failed to assemble a SPASM counter");
+    assert_eq!(result.bytes, vec![3]);
+}
+
+#[test]
+fn test_spasm_dialect() {
+    let source = r#"
+#define bcall(xxxx) rst 28h \ .dw xxxx
+.addinstr ldhli a,(hl+) 7E23 2
+.addinstr ldw hl,* 21 3
+start
+    bcall(_ClrLCDFull) \ b_call(_HomeUp)
+    rst rBR_CALL \ .dw _PutS ; comment \ not a separator
+    ld a,0FFh \ ld b,101b
+loop djnz loop
+    ldhli a,(hl+)
+    ldw hl,start
+    bjump(_HomeUp)
+    .db "\\"
+"#;
+
+    let assembler = Z80Assembler::new()
+        .with_origin(0x9d95)
+        .with_dialect(Dialect::Spasm);
+    let result = assembler
+        .assemble(source)
+        .expect("Failed to assemble SPASM source");
+    assert_eq!(
+        result.bytes,
+        vec![
+            0xef, 0x40, 0x45, 0xef, 0x58, 0x45, 0xef, 0x0a, 0x45, 0x3e, 0xff, 0x06, 0x05, 0x10,
+            0xfe, 0x7e, 0x23, 0x21, 0x95, 0x9d, 0xcd, 0x50, 0x00, 0x58, 0x45, b'\\',
+        ]
+    );
+    assert_eq!(result.symbols.value("loop"), Some(0x9da2));
+    assert_eq!(
+        result.symbols.get("rBR_CALL").unwrap().kind,
+        SymbolKind::Restart
+    );
+
+    // The native dialect keeps reading these lines as before
+    let error = Z80Assembler::new().assemble(source).unwrap_err();
+    assert_eq!(
+        error.diagnostics[0].message,
+        "#define with parameters is not supported"
+    );
+}
+
+#[test]
+fn test_ed_prefix_and_remaining_instructions() {
+    let assembler = Z80Assembler::new();
+    let cases: &[(&str, &[u8])] = &[
+        ("adc hl,bc", &[0xed, 0x4a]),
+        ("adc hl,de", &[0xed, 0x5a]),
+        ("adc hl,hl", &[0xed, 0x6a]),
+        ("adc hl,sp", &[0xed, 0x7a]),
+        ("sbc hl,bc", &[0xed, 0x42]),
+        ("sbc hl,de", &[0xed, 0x52]),
+        ("sbc hl,hl", &[0xed, 0x62]),
+        ("sbc hl,sp", &[0xed, 0x72]),
+        ("ld ($1234),bc", &[0xed, 0x43, 0x34, 0x12]),
+        ("ld ($1234),de", &[0xed, 0x53, 0x34, 0x12]),
+        ("ld ($1234),sp", &[0xed, 0x73, 0x34, 0x12]),
+        ("ld bc,($1234)", &[0xed, 0x4b, 0x34, 0x12]),
+        ("ld de,($1234)", &[0xed, 0x5b, 0x34, 0x12]),
+        ("ld sp,($1234)", &[0xed, 0x7b, 0x34, 0x12]),
+        ("ld i,a", &[0xed, 0x47]),
+        ("ld a,i", &[0xed, 0x57]),
+        ("ld r,a", &[0xed, 0x4f]),
+        ("ld a,r", &[0xed, 0x5f]),
+        ("rld", &[0xed, 0x6f]),
+        ("rrd", &[0xed, 0x67]),
+        ("neg", &[0xed, 0x44]),
+        ("retn", &[0xed, 0x45]),
+        ("reti", &[0xed, 0x4d]),
+        ("im 0", &[0xed, 0x46]),
+        ("im 1", &[0xed, 0x56]),
+        ("im 2", &[0xed, 0x5e]),
+        ("in b,(c)", &[0xed, 0x40]),
+        ("in c,(c)", &[0xed, 0x48]),
+        ("in d,(c)", &[0xed, 0x50]),
+        ("in e,(c)", &[0xed, 0x58]),
+        ("in h,(c)", &[0xed, 0x60]),
+        ("in l,(c)", &[0xed, 0x68]),
+        ("in a,(c)", &[0xed, 0x78]),
+        ("out (c),b", &[0xed, 0x41]),
+        ("out (c),c", &[0xed, 0x49]),
+        ("out (c),d", &[0xed, 0x51]),
+        ("out (c),e", &[0xed, 0x59]),
+        ("out (c),h", &[0xed, 0x61]),
+        ("out (c),l", &[0xed, 0x69]),
+        ("out (c),a", &[0xed, 0x79]),
+        ("ldi", &[0xed, 0xa0]),
+        ("cpi", &[0xed, 0xa1]),
+        ("ini", &[0xed, 0xa2]),
+        ("outi", &[0xed, 0xa3]),
+        ("ldd", &[0xed, 0xa8]),
+        ("cpd", &[0xed, 0xa9]),
+        ("ind", &[0xed, 0xaa]),
+        ("outd", &[0xed, 0xab]),
+        ("ldir", &[0xed, 0xb0]),
+        ("cpir", &[0xed, 0xb1]),
+        ("inir", &[0xed, 0xb2]),
+        ("otir", &[0xed, 0xb3]),
+        ("lddr", &[0xed, 0xb8]),
+        ("cpdr", &[0xed, 0xb9]),
+        ("indr", &[0xed, 0xba]),
+        ("otdr", &[0xed, 0xbb]),
+        ("rst 0", &[0xc7]),
+        ("rst $08", &[0xcf]),
+        ("rst 10h", &[0xd7]),
+        ("rst 18h", &[0xdf]),
+        ("rst 32", &[0xe7]),
+        ("rst rBR_CALL", &[0xef]),
+        ("rst 30h", &[0xf7]),
+        ("rst 38h", &[0xff]),
+        ("jp (hl)", &[0xe9]),
+        ("ld sp,hl", &[0xf9]),
+    ];
+    assert_encodings(&assembler, cases);
+
+    let error = assembler.assemble("rst 33").unwrap_err();
+    assert_eq!(error.diagnostics[0].message, "Invalid rst target: $21");
+    let error = assembler.assemble("rst 3").unwrap_err();
+    assert_eq!(error.diagnostics[0].message, "Invalid rst target: $03");
+}
+
+#[test]
+fn test_indexed_instructions() {
+    let assembler = Z80Assembler::new();
+    let cases: &[(&str, &[u8])] = &[
+        ("ld (ix+3),$42", &[0xdd, 0x36, 0x03, 0x42]),
+        ("ld (iy-1),-2", &[0xfd, 0x36, 0xff, 0xfe]),
+        ("add a,(ix+2)", &[0xdd, 0x86, 0x02]),
+        ("adc a,(iy+2)", &[0xfd, 0x8e, 0x02]),
+        ("sub (ix+2)", &[0xdd, 0x96, 0x02]),
+        ("sbc a,(iy)", &[0xfd, 0x9e, 0x00]),
+        ("and (ix-128)", &[0xdd, 0xa6, 0x80]),
+        ("xor (iy+127)", &[0xfd, 0xae, 0x7f]),
+        ("or (ix+1)", &[0xdd, 0xb6, 0x01]),
+        ("cp (iy+1)", &[0xfd, 0xbe, 0x01]),
+        ("inc (ix+4)", &[0xdd, 0x34, 0x04]),
+        ("dec (iy+4)", &[0xfd, 0x35, 0x04]),
+        ("rlc (ix+1)", &[0xdd, 0xcb, 0x01, 0x06]),
+        ("rrc (iy+1)", &[0xfd, 0xcb, 0x01, 0x0e]),
+        ("rl (ix+1)", &[0xdd, 0xcb, 0x01, 0x16]),
+        ("rr (iy+1)", &[0xfd, 0xcb, 0x01, 0x1e]),
+        ("sla (ix+1)", &[0xdd, 0xcb, 0x01, 0x26]),
+        ("sra (iy+1)", &[0xfd, 0xcb, 0x01, 0x2e]),
+        ("srl (ix+1)", &[0xdd, 0xcb, 0x01, 0x3e]),
+        ("bit 0,(ix+2)", &[0xdd, 0xcb, 0x02, 0x46]),
+        ("res 7,(iy-2)", &[0xfd, 0xcb, 0xfe, 0xbe]),
+        ("set 3,(iy+5)", &[0xfd, 0xcb, 0x05, 0xde]),
+    ];
+    assert_encodings(&assembler, cases);
+
+    // TI-OS flags are bit numbers and offsets from IY, often defined after use
+    let source = "    set textInverse,(iy+textFlags)\ntextFlags .equ 5\ntextInverse .equ 3\n";
+    let code = assembler
+        .assemble(source)
+        .expect("Failed to assemble TI-OS flag")
+        .bytes;
+    assert_eq!(code, vec![0xfd, 0xcb, 0x05, 0xde]);
+
+    // Negative equates and enum members are stored wrapped to 16 bits
+    let source =
+        "d .equ -5\n.enum\nM = -2\n.endenum\n    ld (ix+d),a\n    ld a,(ix-d)\n    inc (iy+M)\n";
+    let code = assembler
+        .assemble(source)
+        .expect("Failed to assemble negative displacements")
+        .bytes;
+    assert_eq!(
+        code,
+        vec![0xdd, 0x77, 0xfb, 0xdd, 0x7e, 0x05, 0xfd, 0x34, 0xfe]
+    );
+
+    let error = assembler
+        .assemble("inc (ix+128)\nbit 2,(iy-129)\nld (ix+200),0\nset 8,(iy)\nld a,(ix+65535)\n")
+        .unwrap_err();
+    let messages: Vec<_> = error
+        .diagnostics
+        .iter()
+        .map(|d| d.message.as_str())
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            "Index displacement out of range: 128",
+            "Index displacement out of range: -129",
+            "Index displacement out of range: 200",
+            "Bit number must be 0-7",
+            "Index displacement out of range: 65535",
+        ]
+    );
+}
+
+#[test]
+fn test_undocumented_instructions() {
+    let assembler = Z80Assembler::new().with_undocumented(true);
+    let cases: &[(&str, &[u8])] = &[
+        ("ld a,ixh", &[0xdd, 0x7c]),
+        ("ld ixl,b", &[0xdd, 0x68]),
+        ("ld iyh,iyl", &[0xfd, 0x65]),
+        ("ld iyl,$12", &[0xfd, 0x2e, 0x12]),
+        ("add a,ixl", &[0xdd, 0x85]),
+        ("sub iyh", &[0xfd, 0x94]),
+        ("cp ixh", &[0xdd, 0xbc]),
+        ("inc ixh", &[0xdd, 0x24]),
+        ("dec iyl", &[0xfd, 0x2d]),
+        ("sll a", &[0xcb, 0x37]),
+        ("sll (hl)", &[0xcb, 0x36]),
+        ("sll (ix+2)", &[0xdd, 0xcb, 0x02, 0x36]),
+        ("rlc (ix+1),b", &[0xdd, 0xcb, 0x01, 0x00]),
+        ("srl (iy-1),l", &[0xfd, 0xcb, 0xff, 0x3d]),
+        ("res 7,(ix+5),a", &[0xdd, 0xcb, 0x05, 0xbf]),
+        ("set 0,(iy+2),c", &[0xfd, 0xcb, 0x02, 0xc1]),
+        ("in f,(c)", &[0xed, 0x70]),
+        ("in (c)", &[0xed, 0x70]),
+        ("out (c),0", &[0xed, 0x71]),
+    ];
+    assert_encodings(&assembler, cases);
+
+    // Without the option they still assemble, with a warning on each line
+    let result = Z80Assembler::new()
+        .assemble("    sll b\n    ld ixh,a\n")
+        .expect("Failed to assemble undocumented instructions");
+    let warnings: Vec<_> = result
+        .warnings
+        .iter()
+        .map(|d| (d.severity, d.message.as_str()))
+        .collect();
+    assert_eq!(
+        warnings,
+        vec![
+            (Severity::Warning, "Undocumented instruction: sll b"),
+            (Severity::Warning, "Undocumented instruction: ld ixh,a"),
+        ]
+    );
+
+    // .undoc allows them from that line on
+    let source = "    sll b\n    ld a,1\n.undoc\n    sll c\n";
+    let result = Z80Assembler::new()
+        .assemble(source)
+        .expect("Failed to assemble .undoc");
+    assert_eq!(result.bytes, vec![0xcb, 0x30, 0x3e, 0x01, 0xcb, 0x31]);
+    assert_eq!(result.warnings.len(), 1);
+    assert_eq!(result.warnings[0].span.as_ref().unwrap().line, 1);
+
+    let error = assembler.assemble("ld h,ixl\nld ixh,iyh\n").unwrap_err();
+    let messages: Vec<_> = error
+        .diagnostics
+        .iter()
+        .map(|d| d.message.as_str())
+        .collect();
+    assert_eq!(
+        messages,
+        vec!["Cannot load ixl into h", "Cannot load iyh into ixh"]
+    );
+}
+
+#[test]
+fn test_ti84_plus_ce_target() {
+    let source = r#"
+start:
+    ld hl,message
+    call _PutS
+    bcall(_NewLine)
+    lea de,ix+3
+    mlt bc
+    ld.sis bc,$1234
+    .assume adl=0
+    ld hl,$1234
+    jp start
+    .assume adl=1
+    jr start
+message:
+    .db "Hi",0
+"#;
+    let assembler = Z80Assembler::new().with_target(Target::TI84PlusCE);
+    let result = assembler
+        .assemble(source)
+        .expect("Failed to assemble for the TI-84 Plus CE");
+    assert_eq!(
+        result.bytes,
+        vec![
+            0x21, 0x9c, 0xa8, 0xd1, // ld hl,message
+            0xcd, 0xc0, 0x07, 0x02, // call _PutS
+            0xcd, 0xf0, 0x07, 0x02, // bcall(_NewLine)
+            0xed, 0x12, 0x03, // lea de,ix+3
+            0xed, 0x4c, // mlt bc
+            0x40, 0x01, 0x34, 0x12, // ld.sis bc,$1234
+            0x21, 0x34, 0x12, // ld hl,$1234 in Z80 mode
+            0xc3, 0x7f, 0xa8, // jp start in Z80 mode
+            0x18, 0xe3, // jr start
+            b'H', b'i', 0x00,
+        ]
+    );
+    assert_eq!(result.symbols.value("message"), Some(0xd1a89c));
+    assert_eq!(result.rom_calls.get("_NewLine"), Some(&0x0207f0));
+    assert_eq!(result.rom_calls.get("_PutS"), Some(&0x0207c0));
+
+    // With the header in front, as the command line adds it, code starts at userMem
+    let program = Z80Assembler::new()
+        .with_target(Target::TI84PlusCE)
+        .with_origin(Target::TI84PlusCE.program_start())
+        .assemble("start:\n    jp start\n")
+        .expect("Failed to assemble after the CE header");
+    assert_eq!(program.symbols.value("start"), Some(0xd1a881));
+    assert_eq!(
+        program.symbols.value("start"),
+        program.symbols.value("userMem")
+    );
+    assert_eq!(program.bytes, vec![0xc3, 0x81, 0xa8, 0xd1]);
+
+    let output = TI8XPGenerator::create_8xp_for(Target::TI84PlusCE, "HI", &result.bytes);
+    assert_eq!(output[59], 0x06, "CE programs are protected");
+
+    let cases: &[(&str, &[u8])] = &[
+        ("lea bc,ix+1", &[0xed, 0x02, 0x01]),
+        ("lea de,ix-1", &[0xed, 0x12, 0xff]),
+        ("lea hl,ix+2", &[0xed, 0x22, 0x02]),
+        ("lea bc,iy+3", &[0xed, 0x03, 0x03]),
+        ("lea de,iy+4", &[0xed, 0x13, 0x04]),
+        ("lea hl,iy", &[0xed, 0x23, 0x00]),
+        ("lea ix,ix+5", &[0xed, 0x32, 0x05]),
+        ("lea iy,iy-2", &[0xed, 0x33, 0xfe]),
+        ("lea ix,iy+6", &[0xed, 0x54, 0x06]),
+        ("lea iy,ix+7", &[0xed, 0x55, 0x07]),
+        ("pea ix+8", &[0xed, 0x65, 0x08]),
+        ("pea iy-8", &[0xed, 0x66, 0xf8]),
+    ];
+    assert_encodings(&assembler, cases);
+
+    // eZ80 features are errors on the TI-83 Plus
+    let error = Z80Assembler::new()
+        .assemble("lea hl,ix+5\nld.lil hl,0\n.assume adl=1\nmlt de\n")
+        .unwrap_err();
+    let messages: Vec<_> = error
+        .diagnostics
+        .iter()
+        .map(|d| d.message.as_str())
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            "lea is an eZ80 instruction",
+            "The .lil suffix is an eZ80 feature",
+            ".assume adl is an eZ80 feature",
+            "mlt is an eZ80 instruction",
+        ]
+    );
+}
+
+#[test]
+fn test_cycle_counts() {
+    let cases: &[(&str, Cycles)] = &[
+        ("nop", Cycles::fixed(4)),
+        ("ld a,(hl)", Cycles::fixed(7)),
+        ("ld hl,($8000)", Cycles::fixed(16)),
+        ("ex (sp),hl", Cycles::fixed(19)),
+        ("jp $9d95", Cycles::fixed(10)),
+        ("jp z,$9d95", Cycles::fixed(10)),
+        ("jr nz,$", Cycles::branch(12, 7)),
+        ("djnz $", Cycles::branch(13, 8)),
+        ("call c,$9d95", Cycles::branch(17, 10)),
+        ("ret nc", Cycles::branch(11, 5)),
+        ("rst 38h", Cycles::fixed(11)),
+        ("bit 0,(hl)", Cycles::fixed(12)),
+        ("set 0,(hl)", Cycles::fixed(15)),
+        ("sbc hl,de", Cycles::fixed(15)),
+        ("ldi", Cycles::fixed(16)),
+        ("ldir", Cycles::branch(21, 16)),
+        ("otir", Cycles::branch(21, 16)),
+        ("ld ix,$1234", Cycles::fixed(14)),
+        ("ld (ix+3),a", Cycles::fixed(19)),
+        ("ld (iy+3),$20", Cycles::fixed(19)),
+        ("inc (ix+1)", Cycles::fixed(23)),
+        ("bit 1,(iy+2)", Cycles::fixed(20)),
+        ("res 1,(iy+2)", Cycles::fixed(23)),
+        ("jp (ix)", Cycles::fixed(8)),
+    ];
+    let assembler = Z80Assembler::new();
+    for (source, result, expected) in assemble_cases(&assembler, cases) {
+        assert_eq!(result.lines[0].cycles, Some(*expected), "{}", source);
+        assert_eq!(
+            instruction_cycles(&result.bytes),
+            Some(*expected),
+            "{}",
+            source
+        );
+    }
+    assert_eq!(Cycles::branch(12, 7).to_string(), "12/7");
+    assert_eq!(Cycles::fixed(4).to_string(), "4");
+
+    // Directives and ROM calls have no count
+    let result = assembler
+        .assemble("label:\n    .db 1,2\n    bcall(_PutS)\n")
+        .expect("Failed to assemble data");
+    assert!(result.lines.iter().all(|line| line.cycles.is_none()));
+
+    let source = "\
+.cycles begin
+    ld b,10
+loop:
+    ld a,(ix+3)
+    djnz loop
+.cycles end
+";
+    let result = assembler
+        .assemble(source)
+        .expect("Failed to assemble a .cycles region");
+    assert_eq!(result.warnings.len(), 1);
+    assert_eq!(result.warnings[0].severity, Severity::Note);
+    assert_eq!(
+        result.warnings[0].message,
+        "39/34 T-states since the .cycles begin on line 1"
+    );
+
+    let listing = result.listing();
+    let rows: Vec<&str> = listing.lines().collect();
+    assert_eq!(rows[1], "9D93  06 0A            7      ld b,10");
+    assert_eq!(rows[4], "9D98  10 FB         13/8      djnz loop");
+
+    let error = assembler
+        .assemble(".cycles end\n.cycles start\n.cycles begin\n")
+        .unwrap_err();
+    let messages: Vec<_> = error
+        .diagnostics
+        .iter()
+        .map(|d| d.message.as_str())
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            ".cycles end without .cycles begin",
+            "Unknown .cycles action start, expected begin or end",
+            ".cycles begin without .cycles end",
+        ]
+    );
 }